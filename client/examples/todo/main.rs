@@ -4,15 +4,13 @@ use std::{io, thread};
 use crossterm::event::{read, KeyCode};
 use crossterm::{execute, terminal};
 use log::{debug, error, LevelFilter};
-use merkle_trie_clock::models::{RowParam, ValueType};
 
 use crate::global_syncer::TodoSyncer;
-use crate::models::{TodoParam, TODO_TABLE};
+use crate::ops::{add_todo, list_todos, remove_todo, update_todo, GROUP_ID};
 
 mod global_syncer;
 mod models;
-
-const GROUP_ID: &str = "todo-app";
+mod ops;
 
 fn main() {
     // 初始化日志系统
@@ -28,12 +26,18 @@ fn main() {
                 s.debug();
             }
 
-            match s.sync(GROUP_ID, vec![], None) {
-                Ok(messages) => {
-                    debug!("Applied messages: {:#?}", messages);
+            // Flushing also uploads the messages queued while offline or by
+            // a failed sync, a plain sync would leave them for the next edit
+            match s.flush(GROUP_ID) {
+                Ok(()) => {
+                    debug!("Synced, merkle root hash: {}", s.merkle_root_hash());
                 }
                 Err(e) => {
-                    error!("Failed to sync message: {}", e);
+                    error!(
+                        "Failed to sync message, {} queued: {}",
+                        s.outbound_messages().len(),
+                        e
+                    );
                 }
             };
         }
@@ -70,95 +74,62 @@ fn main() {
 }
 
 fn show_tasks() {
-    let storage = TodoSyncer::global().lock().unwrap();
-    let todos = storage.storage().items();
-    todos
-        .iter()
-        .filter(|(_, v)| v.tombstone == 0)
-        .for_each(|kv| {
-            println!("Todo: {:?}", kv.1);
-        })
+    let s = TodoSyncer::global().lock().unwrap();
+    for todo in list_todos(&s) {
+        println!("Todo: {:?}", todo);
+    }
+    println!(
+        "\nLast event at: {}, merkle root hash: {}",
+        s.current_timestamp(),
+        s.merkle_root_hash()
+    );
 }
 
 fn add_task() {
     println!("Enter the todo item: [content, type]");
-    let mut new_item = String::new();
-    io::stdin()
-        .read_line(&mut new_item)
-        .expect("Failed to read line");
-
-    let mut parts = new_item.split_whitespace();
-    let (content, todo_type) = (parts.next(), parts.next());
-    {
-        let mut s = TodoSyncer::global().lock().unwrap();
-        let res = s.insert(
-            GROUP_ID,
-            TODO_TABLE,
-            vec![
-                RowParam {
-                    id: None,
-                    column: TodoParam::Content.to_string(),
-                    value_type: ValueType::String,
-                    value: content.unwrap().to_string(),
-                },
-                RowParam {
-                    id: None,
-                    column: TodoParam::TodoType.to_string(),
-                    value_type: ValueType::String,
-                    value: todo_type.unwrap().to_string(),
-                },
-            ],
-        );
-        println!("\nInsert result: {:?}", res);
-    }
+    let line = read_line();
+    let mut parts = line.split_whitespace();
+    let (Some(content), Some(todo_type)) = (parts.next(), parts.next()) else {
+        println!("\nInvalid todo: {}", line.trim());
+        return;
+    };
+
+    let mut s = TodoSyncer::global().lock().unwrap();
+    let res = add_todo(&mut s, content, todo_type);
+    println!("\nInsert result: {:?}", res);
 }
 
 fn update_task() {
     println!("Enter the todo item: [id, content, type]");
-    let mut new_item = String::new();
-    io::stdin()
-        .read_line(&mut new_item)
-        .expect("Failed to read line");
-
-    let mut parts = new_item.split_whitespace();
-    let (id, content, todo_type) = (parts.next(), parts.next(), parts.next());
-    {
-        let id = id.unwrap().to_string();
-        let mut s = TodoSyncer::global().lock().unwrap();
-        let res = s.update(
-            GROUP_ID,
-            TODO_TABLE,
-            vec![
-                RowParam {
-                    id: Some(id.clone()),
-                    column: TodoParam::Content.to_string(),
-                    value_type: ValueType::String,
-                    value: content.unwrap().to_string(),
-                },
-                RowParam {
-                    id: Some(id),
-                    column: TodoParam::TodoType.to_string(),
-                    value_type: ValueType::String,
-                    value: todo_type.unwrap().to_string(),
-                },
-            ],
-        );
-        println!("\nInsert result: {:?}", res);
-    }
+    let line = read_line();
+    let mut parts = line.split_whitespace();
+    let (Some(id), Some(content), Some(todo_type)) = (parts.next(), parts.next(), parts.next())
+    else {
+        println!("\nInvalid todo: {}", line.trim());
+        return;
+    };
+
+    let mut s = TodoSyncer::global().lock().unwrap();
+    let res = update_todo(&mut s, id, content, todo_type);
+    println!("\nUpdate result: {:?}", res);
 }
 
 fn remove_task() {
-    println!("Enter the index of the todo item to delete:");
-    let mut index_input = String::new();
+    println!("Enter the id of the todo item to delete:");
+    let line = read_line();
+    let id = line.trim();
+
+    let mut s = TodoSyncer::global().lock().unwrap();
+    let res = remove_todo(&mut s, id);
+    println!("\nDelete result: {:?}", res);
+}
+
+fn read_line() -> String {
+    let mut line = String::new();
     io::stdin()
-        .read_line(&mut index_input)
+        .read_line(&mut line)
         .expect("Failed to read line");
-    let index_input = index_input.trim();
-    {
-        let mut s = TodoSyncer::global().lock().unwrap();
-        s.delete(GROUP_ID, TODO_TABLE, index_input).unwrap();
-    }
-    println!("\nDelete task: {}", index_input);
+    line
 }
 
 fn read_key() -> KeyCode {
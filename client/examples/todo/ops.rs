@@ -0,0 +1,103 @@
+use client::error::SyncerError;
+use client::syncer::Syncer;
+use merkle_trie_clock::models::{RowParam, ValueType};
+
+use crate::models::{Todo, TodoParam, TODO_TABLE};
+
+pub const GROUP_ID: &str = "todo-app";
+
+/// Add a todo, returning its id
+pub fn add_todo(
+    s: &mut Syncer<Todo>,
+    content: &str,
+    todo_type: &str,
+) -> Result<String, SyncerError> {
+    s.insert(GROUP_ID, TODO_TABLE, todo_params(None, content, todo_type))
+}
+
+/// Replace the content and type of a todo
+pub fn update_todo(
+    s: &mut Syncer<Todo>,
+    id: &str,
+    content: &str,
+    todo_type: &str,
+) -> Result<(), SyncerError> {
+    s.update(
+        GROUP_ID,
+        TODO_TABLE,
+        todo_params(Some(id), content, todo_type),
+    )
+}
+
+pub fn remove_todo(s: &mut Syncer<Todo>, id: &str) -> Result<(), SyncerError> {
+    s.delete(GROUP_ID, TODO_TABLE, id)
+}
+
+/// The todos not deleted, ordered by id
+pub fn list_todos(s: &Syncer<Todo>) -> Vec<&Todo> {
+    let mut todos: Vec<&Todo> = s
+        .storage()
+        .items()
+        .values()
+        .filter(|todo| todo.tombstone == 0)
+        .collect();
+    todos.sort_by(|a, b| a.id.cmp(&b.id));
+    todos
+}
+
+fn todo_params(id: Option<&str>, content: &str, todo_type: &str) -> Vec<RowParam> {
+    vec![
+        RowParam {
+            id: id.map(str::to_string),
+            column: TodoParam::Content.to_string(),
+            value_type: ValueType::String,
+            value: content.to_string(),
+        },
+        RowParam {
+            id: id.map(str::to_string),
+            column: TodoParam::TodoType.to_string(),
+            value_type: ValueType::String,
+            value: todo_type.to_string(),
+        },
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use client::syncer::Syncer;
+
+    use crate::models::Todo;
+    use crate::ops::{add_todo, list_todos, remove_todo, update_todo};
+
+    fn offline_syncer() -> Syncer<Todo> {
+        Syncer::builder().sync_enabled(false).build()
+    }
+
+    #[test]
+    fn add_and_list_test() {
+        let mut s = offline_syncer();
+        assert!(list_todos(&s).is_empty());
+
+        let id = add_todo(&mut s, "write tests", "work").unwrap();
+        update_todo(&mut s, &id, "write more tests", "work").unwrap();
+
+        let todos = list_todos(&s);
+        assert_eq!(todos.len(), 1);
+        assert_eq!(todos[0].id, id);
+        assert_eq!(todos[0].content, "write more tests");
+        assert_eq!(todos[0].todo_type, "work");
+    }
+
+    #[test]
+    fn delete_and_list_test() {
+        let mut s = offline_syncer();
+        let first = add_todo(&mut s, "first", "home").unwrap();
+        let second = add_todo(&mut s, "second", "home").unwrap();
+
+        remove_todo(&mut s, &first).unwrap();
+
+        let todos = list_todos(&s);
+        assert_eq!(todos.len(), 1);
+        assert_eq!(todos[0].id, second);
+    }
+}
@@ -0,0 +1,43 @@
+use thiserror::Error;
+
+use merkle_trie_clock::error::{BaseMismatch, DiffError, NonConvergence, TimestampError};
+
+/// Why an operation of a [`crate::syncer::Syncer`] failed
+#[derive(Debug, Error)]
+pub enum SyncerError {
+    /// The server couldn't be reached, or refused the request
+    #[error("Transport error: {0}")]
+    Transport(#[source] anyhow::Error),
+
+    /// The clock couldn't produce or receive a timestamp, e.g. the counter
+    /// overflowed or a remote clock drifted too far ahead
+    #[error(transparent)]
+    Timestamp(#[from] TimestampError),
+
+    /// The store rejected messages, e.g. invalid ones
+    #[error("Storage error: {0}")]
+    Storage(#[source] anyhow::Error),
+
+    /// The server's merkle trie has another base than ours
+    #[error(transparent)]
+    BaseMismatch(#[from] BaseMismatch),
+
+    /// The server's merkle trie can't be compared with ours
+    #[error(transparent)]
+    Diff(#[from] DiffError),
+
+    /// The tries still differed after syncing the same range twice
+    #[error(transparent)]
+    NonConvergence(#[from] NonConvergence),
+}
+
+impl SyncerError {
+    /// Whether the failure is in how client and server talk to each other
+    /// rather than in reaching the server or in the local state
+    pub fn is_protocol(&self) -> bool {
+        matches!(
+            self,
+            SyncerError::BaseMismatch(_) | SyncerError::Diff(_) | SyncerError::NonConvergence(_)
+        )
+    }
+}
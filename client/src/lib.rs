@@ -1,3 +1,10 @@
+pub mod error;
 pub mod mem_storage;
 pub mod storage;
 pub mod syncer;
+pub mod transport;
+#[cfg(feature = "wasm")]
+pub mod wasm_transport;
+
+#[cfg(test)]
+mod test_utils;
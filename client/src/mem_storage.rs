@@ -1,25 +1,60 @@
 use std::collections::{HashMap, HashSet};
 use std::fmt::Debug;
 
+use anyhow::bail;
 use log::debug;
 use serde::de::DeserializeOwned;
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 
 use merkle_trie_clock::clock::MerkleClock;
-use merkle_trie_clock::models::Message;
+use merkle_trie_clock::merkle::DEFAULT_BASE;
+use merkle_trie_clock::models::{Message, TOMBSTONE_COLUMN};
 use merkle_trie_clock::timestamp::Timestamp;
 
-use crate::storage::{MessageHandler, Store};
+use crate::storage::{MessageHandler, Resolution, Store};
 
-pub const MERKLE_BASE_CONST: usize = 3;
+pub const MERKLE_BASE_CONST: usize = DEFAULT_BASE;
+
+/// A callback notified of every applied message
+pub type Subscriber = Box<dyn Fn(&Message) + Send>;
 
 pub struct MemStorage<
     Item: MessageHandler + DeserializeOwned + Serialize + Debug,
     const MERKLE_BASE: usize,
 > {
-    table_name: String,
+    table_names: Vec<String>,
+    items: HashMap<String, Item>,
+    applied_messages: HashSet<String>,
+    /// The message whose value each (row, column) currently holds
+    field_messages: HashMap<(String, String), Message>,
+    dedupe: bool,
+    /// Every applied message, only kept when `retain_messages` is set
+    retained: Vec<Message>,
+    retain_messages: bool,
+    subscribers: Vec<Subscriber>,
+    /// The rows by their current value, for each indexed column
+    indexes: HashMap<String, HashMap<String, HashSet<String>>>,
+    /// The rows removed by [`Store::hard_delete`], whose messages are only
+    /// recorded from then on
+    hard_deleted: HashSet<String>,
+}
+
+/// The serialized form of a [`MemStorage`], see [`Store::snapshot`]
+#[derive(Serialize)]
+struct SnapshotRef<'a, Item> {
+    items: &'a HashMap<String, Item>,
+    applied_messages: &'a HashSet<String>,
+    field_messages: Vec<&'a Message>,
+    hard_deleted: &'a HashSet<String>,
+}
+
+#[derive(Deserialize)]
+struct Snapshot<Item> {
     items: HashMap<String, Item>,
     applied_messages: HashSet<String>,
+    field_messages: Vec<Message>,
+    #[serde(default)]
+    hard_deleted: HashSet<String>,
 }
 
 impl<Item: MessageHandler + DeserializeOwned + Serialize + Debug, const MERKLE_BASE: usize>
@@ -29,29 +64,47 @@ impl<Item: MessageHandler + DeserializeOwned + Serialize + Debug, const MERKLE_B
         &mut self,
         clock: &mut MerkleClock<MERKLE_BASE>,
         messages: &mut Vec<Message>,
-    ) -> anyhow::Result<()> {
+    ) -> anyhow::Result<Vec<String>> {
+        // Reject the whole batch before touching local state
+        for message in messages.iter() {
+            message.validate()?;
+        }
+
         // Sort the whole messages
-        messages.sort_by(|a, b| {
-            let timestamp_a = &a.timestamp;
-            let timestamp_b = &b.timestamp;
-            timestamp_a.cmp(timestamp_b)
-        });
+        messages.sort_by(Message::cmp_by_timestamp);
+
+        // When deduplicating, only the latest message per field (i.e., dataset +
+        // row + column) of the batch is applied, the older ones are just recorded.
+        let superseded = if self.dedupe {
+            superseded_messages(messages)
+        } else {
+            HashSet::new()
+        };
 
         // Look at each incoming message. If it's new to us (i.e., we don't have it in
         // our local store), or is newer than the message we have for the same field
         // (i.e., dataset + row + column), then apply it to our local data store and
         // insert it into our local collection of messages and merkle tree (which is
         // basically a specialized index of those messages).
-        for message in messages {
-            if message.dataset.as_str().eq(self.table_name.as_str()) {
-                (*self).apply_item_table(clock, message)?;
+        let mut applied = vec![];
+        for (idx, message) in messages.iter().enumerate() {
+            if self.table_names.contains(&message.dataset) {
+                if self.applied_messages.contains(&message.timestamp) {
+                    continue;
+                }
+                if superseded.contains(&idx) {
+                    self.record_message(clock, message)?;
+                } else {
+                    self.apply_item_table(clock, message)?;
+                }
+                applied.push(message.timestamp.clone());
             } else {
                 log::warn!("Unknown dataset, message: {:?}", message);
                 continue;
             }
         }
 
-        Ok(())
+        Ok(applied)
     }
 
     fn items(&self) -> &HashMap<String, Item> {
@@ -61,6 +114,72 @@ impl<Item: MessageHandler + DeserializeOwned + Serialize + Debug, const MERKLE_B
     fn applied_messages(&self) -> &HashSet<String> {
         &self.applied_messages
     }
+
+    fn field_value(&self, row: &str, column: &str) -> Option<&str> {
+        self.field_messages
+            .get(&(row.to_string(), column.to_string()))
+            .map(|message| message.value.as_str())
+    }
+
+    fn hard_delete(
+        &mut self,
+        _clock: &mut MerkleClock<MERKLE_BASE>,
+        row: &str,
+    ) -> anyhow::Result<()> {
+        if !self.items.contains_key(row) {
+            return Ok(());
+        }
+        if self.field_value(row, TOMBSTONE_COLUMN) != Some("1") {
+            bail!("Row {} isn't deleted", row);
+        }
+
+        // The messages stay applied and in the trie, which has to keep
+        // matching the server's. Those synced again, or later ones for the
+        // row, are only recorded.
+        let fields: Vec<(String, String)> = self
+            .field_messages
+            .keys()
+            .filter(|(field_row, _)| field_row == row)
+            .cloned()
+            .collect();
+        for field in fields {
+            if let Some(message) = self.field_messages.remove(&field) {
+                self.reindex(row, &field.1, Some(&message.value), None);
+            }
+        }
+        self.retained.retain(|m| m.row != row);
+        self.items.remove(row);
+        self.hard_deleted.insert(row.to_string());
+
+        Ok(())
+    }
+
+    fn snapshot(&self) -> anyhow::Result<Vec<u8>> {
+        Ok(serde_json::to_vec(&SnapshotRef {
+            items: &self.items,
+            applied_messages: &self.applied_messages,
+            field_messages: self.field_messages.values().collect(),
+            hard_deleted: &self.hard_deleted,
+        })?)
+    }
+
+    fn restore(&mut self, bytes: &[u8]) -> anyhow::Result<()> {
+        let snapshot: Snapshot<Item> = serde_json::from_slice(bytes)?;
+        self.items = snapshot.items;
+        self.applied_messages = snapshot.applied_messages;
+        self.field_messages = snapshot
+            .field_messages
+            .into_iter()
+            .map(|message| ((message.row.clone(), message.column.clone()), message))
+            .collect();
+        self.hard_deleted = snapshot.hard_deleted;
+        let columns: Vec<String> = self.indexes.keys().cloned().collect();
+        for column in columns {
+            self.add_index(&column);
+        }
+
+        Ok(())
+    }
 }
 
 impl<Item: MessageHandler + DeserializeOwned + Serialize + Debug, const MERKLE_BASE: usize> Default
@@ -76,9 +195,157 @@ impl<Item: MessageHandler + DeserializeOwned + Serialize + Debug, const MERKLE_B
 {
     pub fn new() -> Self {
         Self {
-            table_name: Item::table_name(),
+            table_names: Item::table_names(),
             items: HashMap::new(),
             applied_messages: HashSet::new(),
+            field_messages: HashMap::new(),
+            dedupe: false,
+            retained: Vec::new(),
+            retain_messages: false,
+            subscribers: Vec::new(),
+            indexes: HashMap::new(),
+            hard_deleted: HashSet::new(),
+        }
+    }
+
+    /// A storage holding `messages`, applied as one batch through
+    /// [`Store::apply_messages`] which records them in `clock`.
+    pub fn from_messages(
+        messages: impl IntoIterator<Item = Message>,
+        clock: &mut MerkleClock<MERKLE_BASE>,
+    ) -> anyhow::Result<Self> {
+        let mut storage = Self::new();
+        storage.apply_messages(clock, &mut messages.into_iter().collect())?;
+
+        Ok(storage)
+    }
+
+    /// Only apply the latest message per field of each batch. Superseded
+    /// messages are still recorded as applied and inserted into the merkle
+    /// trie, which has to cover every message to converge with the server.
+    pub fn set_dedupe(&mut self, dedupe: bool) {
+        self.dedupe = dedupe;
+    }
+
+    pub fn dedupe(&self) -> bool {
+        self.dedupe
+    }
+
+    /// Keep a copy of every applied message, so the history can be
+    /// re-emitted with [`MemStorage::export_messages`]. Off by default to
+    /// save the memory.
+    pub fn set_retain_messages(&mut self, retain: bool) {
+        self.retain_messages = retain;
+    }
+
+    pub fn retain_messages(&self) -> bool {
+        self.retain_messages
+    }
+
+    /// The messages applied while retaining was on, oldest first
+    pub fn export_messages(&self) -> Vec<Message> {
+        let mut messages = self.retained.clone();
+        messages.sort_by(Message::cmp_by_timestamp);
+        messages
+    }
+
+    /// Combine another storage into this one, e.g. two replicas after an
+    /// offline split: its messages are replayed through
+    /// [`Store::apply_messages`], so conflicting fields resolve as usual
+    /// (last writer wins by default), and its applied messages are added to
+    /// ours and to the merkle trie of `clock`.
+    ///
+    /// Unless `other` retains its messages, only the ones holding the current
+    /// field values can be replayed, the others are just recorded as applied:
+    /// they lost to a newer message of `other` for the same field anyway.
+    pub fn merge_store(
+        &mut self,
+        other: &Self,
+        clock: &mut MerkleClock<MERKLE_BASE>,
+    ) -> anyhow::Result<()> {
+        let mut messages = other.export_messages();
+        let exported: HashSet<&str> = messages.iter().map(|m| m.timestamp.as_str()).collect();
+        let current: Vec<Message> = other
+            .field_messages
+            .values()
+            .filter(|message| !exported.contains(message.timestamp.as_str()))
+            .cloned()
+            .collect();
+        messages.extend(current);
+        self.apply_messages(clock, &mut messages)?;
+
+        for timestamp in &other.applied_messages {
+            if !self.applied_messages.contains(timestamp) {
+                clock.merkle_mut().insert(&Timestamp::parse(timestamp)?);
+                self.applied_messages.insert(timestamp.clone());
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Register a callback invoked with every message that changes an item
+    pub fn subscribe(&mut self, cb: Subscriber) {
+        self.subscribers.push(cb);
+    }
+
+    /// The timestamp of the message whose value a field currently holds
+    pub fn field_timestamp(&self, row: &str, column: &str) -> Option<&str> {
+        self.field_messages
+            .get(&(row.to_string(), column.to_string()))
+            .map(|message| message.timestamp.as_str())
+    }
+
+    /// Index the items by the value of `column`, to look them up with
+    /// [`MemStorage::by_index`] instead of scanning all of them. The index
+    /// is built from the current values and kept up to date on apply.
+    pub fn add_index(&mut self, column: &str) {
+        let mut index: HashMap<String, HashSet<String>> = HashMap::new();
+        for ((row, field_column), message) in &self.field_messages {
+            if field_column == column {
+                index
+                    .entry(message.value.clone())
+                    .or_default()
+                    .insert(row.clone());
+            }
+        }
+        self.indexes.insert(column.to_string(), index);
+    }
+
+    /// The items whose `column` currently holds `value`, ordered by row.
+    /// Deleted items are included, as deleting only sets their tombstone.
+    /// Empty unless `column` was indexed with [`MemStorage::add_index`].
+    pub fn by_index(&self, column: &str, value: &str) -> Vec<&Item> {
+        let Some(rows) = self.indexes.get(column).and_then(|index| index.get(value)) else {
+            return vec![];
+        };
+        let mut rows: Vec<&String> = rows.iter().collect();
+        rows.sort();
+
+        rows.into_iter()
+            .filter_map(|row| self.items.get(row))
+            .collect()
+    }
+
+    /// Move a row from its `old` to its `new` value in the index of `column`,
+    /// if there is one
+    fn reindex(&mut self, row: &str, column: &str, old: Option<&str>, new: Option<&str>) {
+        let Some(index) = self.indexes.get_mut(column) else {
+            return;
+        };
+        if let Some(old) = old {
+            if let Some(rows) = index.get_mut(old) {
+                rows.remove(row);
+                if rows.is_empty() {
+                    index.remove(old);
+                }
+            }
+        }
+        if let Some(new) = new {
+            index
+                .entry(new.to_string())
+                .or_default()
+                .insert(row.to_string());
         }
     }
 
@@ -99,26 +366,868 @@ impl<Item: MessageHandler + DeserializeOwned + Serialize + Debug, const MERKLE_B
         // a corresponding local message for the same dataset/row/column OR we did,
         // but it has a different timestamp than ours), we need to add it to our
         // array of local messages and update the merkle tree.
-        if !self.applied_messages.contains(&incoming_message.timestamp) {
-            match self.items.get_mut(&incoming_message.row) {
+        if self.applied_messages.contains(&incoming_message.timestamp) {
+            return Ok(());
+        }
+        if self.hard_deleted.contains(&incoming_message.row) {
+            return self.record_message(clock, incoming_message);
+        }
+
+        // Another message already set this field: the item decides which value
+        // wins (last writer wins by default). A losing message is only
+        // recorded, it mustn't overwrite the current value.
+        let field = (
+            incoming_message.row.clone(),
+            incoming_message.column.clone(),
+        );
+        let winner = match (
+            self.field_messages.get(&field),
+            self.items.get(&incoming_message.row),
+        ) {
+            (Some(current), Some(item)) => match item.resolve(current, incoming_message) {
+                Resolution::KeepCurrent => None,
+                Resolution::TakeIncoming => Some(incoming_message.clone()),
+                Resolution::Merge(value) => Some(Message {
+                    value,
+                    ..incoming_message.clone()
+                }),
+            },
+            _ => Some(incoming_message.clone()),
+        };
+
+        if let Some(winner) = winner {
+            match self.items.get_mut(&winner.row) {
                 // We don't have the data yet, insert;
                 None => {
-                    let mut new_item = Item::from_message(incoming_message);
-                    new_item.handle_message(incoming_message)?;
-                    self.items.insert(incoming_message.row.clone(), new_item);
+                    let mut new_item = Item::from_message(&winner);
+                    new_item.handle_message(&winner)?;
+                    self.items.insert(winner.row.clone(), new_item);
                 }
                 // We have the data
                 Some(item) => {
-                    item.handle_message(incoming_message)?;
+                    item.handle_message(&winner)?;
                 }
             }
-            clock
-                .merkle_mut()
-                .insert(&Timestamp::parse(&incoming_message.timestamp)?);
-            self.applied_messages
-                .insert(incoming_message.timestamp.clone());
-        };
+            for cb in &self.subscribers {
+                cb(&winner);
+            }
+            let old = self
+                .field_messages
+                .get(&field)
+                .map(|message| message.value.clone());
+            self.reindex(&field.0, &field.1, old.as_deref(), Some(&winner.value));
+            self.field_messages.insert(field, winner);
+        }
+        self.record_message(clock, incoming_message)
+    }
+
+    /// Add a message to our collection of applied messages and merkle trie
+    /// without touching the items.
+    fn record_message(
+        &mut self,
+        clock: &mut MerkleClock<MERKLE_BASE>,
+        message: &Message,
+    ) -> anyhow::Result<()> {
+        if self.applied_messages.contains(&message.timestamp) {
+            return Ok(());
+        }
+        clock
+            .merkle_mut()
+            .insert(&Timestamp::parse(&message.timestamp)?);
+        self.applied_messages.insert(message.timestamp.clone());
+        if self.retain_messages {
+            self.retained.push(message.clone());
+        }
 
         Ok(())
     }
 }
+
+/// Indices of the messages of a sorted batch that are followed by a newer
+/// message for the same field.
+fn superseded_messages(messages: &[Message]) -> HashSet<usize> {
+    let mut latest = HashSet::new();
+    let mut superseded = HashSet::new();
+    for (idx, message) in messages.iter().enumerate().rev() {
+        if !latest.insert((&message.dataset, &message.row, &message.column)) {
+            superseded.insert(idx);
+        }
+    }
+    superseded
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use serde::{Deserialize, Serialize};
+
+    use merkle_trie_clock::clock::MerkleClock;
+    use merkle_trie_clock::merkle::MerkleTrie;
+    use merkle_trie_clock::models::{Message, ValueType};
+    use merkle_trie_clock::timestamp::Timestamp;
+
+    use crate::mem_storage::MemStorage;
+    use crate::storage::{MessageHandler, Resolution, Store};
+    use crate::test_utils::{Note, NOTE_TABLE};
+
+    const TS: &str = "2024-04-12T05:13:20.831+00:00-0000-5ef35ca3375b14c8";
+
+    #[test]
+    fn apply_invalid_messages_test() {
+        let mut storage = MemStorage::<Note, 3>::new();
+        let mut clock = MerkleClock::new(
+            Timestamp::new(0, 0, "client".to_string()),
+            MerkleTrie::<3>::new(),
+        );
+
+        let mut messages = vec![
+            Message::change(TS, NOTE_TABLE, "1", "content", ValueType::String, "hi"),
+            Message::change("", NOTE_TABLE, "2", "content", ValueType::String, "hi"),
+        ];
+        assert!(storage.apply_messages(&mut clock, &mut messages).is_err());
+
+        // Nothing of the rejected batch is applied
+        assert!(storage.items().is_empty());
+        assert!(storage.applied_messages().is_empty());
+        assert!(clock.merkle().stored_keys().is_empty());
+    }
+
+    /// A note counting how often a message was handled
+    #[derive(Debug, Default, Serialize, Deserialize)]
+    struct CountedNote {
+        note: Note,
+        handled: usize,
+    }
+
+    impl MessageHandler for CountedNote {
+        fn from_message(message: &Message) -> Self {
+            CountedNote {
+                note: Note::from_message(message),
+                handled: 0,
+            }
+        }
+
+        fn handle_message(&mut self, message: &Message) -> anyhow::Result<()> {
+            self.handled += 1;
+            self.note.handle_message(message)
+        }
+
+        fn table_name() -> String {
+            Note::table_name()
+        }
+    }
+
+    #[test]
+    fn dedupe_test() {
+        let mut storage = MemStorage::<CountedNote, 3>::new();
+        storage.set_dedupe(true);
+        let mut clock = MerkleClock::new(
+            Timestamp::new(0, 0, "client".to_string()),
+            MerkleTrie::<3>::new(),
+        );
+
+        let timestamps: Vec<String> = (1..=3)
+            .map(|i| Timestamp::new(1712898800000 + i, 0, "remote".to_string()).to_string())
+            .collect();
+        // Out of order, the latest update must win
+        let mut messages = vec![
+            Message::change(
+                &timestamps[2],
+                NOTE_TABLE,
+                "1",
+                "content",
+                ValueType::String,
+                "c",
+            ),
+            Message::change(
+                &timestamps[0],
+                NOTE_TABLE,
+                "1",
+                "content",
+                ValueType::String,
+                "a",
+            ),
+            Message::change(
+                &timestamps[1],
+                NOTE_TABLE,
+                "1",
+                "content",
+                ValueType::String,
+                "b",
+            ),
+        ];
+        storage.apply_messages(&mut clock, &mut messages).unwrap();
+
+        let item = &storage.items()["1"];
+        assert_eq!(item.note.content, "c");
+        assert_eq!(item.handled, 1);
+        for timestamp in &timestamps {
+            assert!(storage.applied_messages().contains(timestamp));
+        }
+        assert_eq!(clock.merkle().stored_keys().len(), 3);
+    }
+
+    #[test]
+    fn last_writer_wins_test() {
+        let mut storage = MemStorage::<Note, 3>::new();
+        let mut clock = MerkleClock::new(
+            Timestamp::new(0, 0, "client".to_string()),
+            MerkleTrie::<3>::new(),
+        );
+
+        let older = Timestamp::new(1712898800001, 0, "remote".to_string()).to_string();
+        let newer = Timestamp::new(1712898800002, 0, "remote".to_string()).to_string();
+
+        // The newer message arrives in an earlier batch than the older one
+        let mut messages = vec![Message::change(
+            &newer,
+            NOTE_TABLE,
+            "1",
+            "content",
+            ValueType::String,
+            "newer",
+        )];
+        storage.apply_messages(&mut clock, &mut messages).unwrap();
+        let mut messages = vec![Message::change(
+            &older,
+            NOTE_TABLE,
+            "1",
+            "content",
+            ValueType::String,
+            "older",
+        )];
+        storage.apply_messages(&mut clock, &mut messages).unwrap();
+
+        assert_eq!(storage.items()["1"].content, "newer");
+        assert_eq!(
+            storage.field_timestamp("1", "content"),
+            Some(newer.as_str())
+        );
+        assert!(storage.applied_messages().contains(&older));
+        assert_eq!(clock.merkle().stored_keys().len(), 2);
+
+        // Timestamps are compared parsed, not as strings: with a wider
+        // counter the newer one sorts first as a string
+        let at = |counter: usize, width: usize| {
+            Timestamp::new(1712898800003, counter, "remote".to_string())
+                .with_counter_width(width)
+                .to_string()
+        };
+        let (older, newer) = (at(0x5, 4), at(0x10, 6));
+        assert!(older > newer);
+        for (timestamp, content) in [(&newer, "newer"), (&older, "older")] {
+            let mut messages = vec![Message::change(
+                timestamp,
+                NOTE_TABLE,
+                "2",
+                "content",
+                ValueType::String,
+                content,
+            )];
+            storage.apply_messages(&mut clock, &mut messages).unwrap();
+        }
+        assert_eq!(storage.items()["2"].content, "newer");
+    }
+
+    #[test]
+    fn apply_returns_new_messages_test() {
+        let mut storage = MemStorage::<Note, 3>::new();
+        let mut clock = MerkleClock::new(
+            Timestamp::new(0, 0, "client".to_string()),
+            MerkleTrie::<3>::new(),
+        );
+        let first = Timestamp::new(1712898800001, 0, "remote".to_string()).to_string();
+        let second = Timestamp::new(1712898800002, 0, "remote".to_string()).to_string();
+        let message =
+            |t: &str| Message::change(t, NOTE_TABLE, "1", "content", ValueType::String, "x");
+
+        let applied = storage
+            .apply_messages(&mut clock, &mut vec![message(&first)])
+            .unwrap();
+        assert_eq!(applied, vec![first.clone()]);
+
+        let applied = storage
+            .apply_messages(&mut clock, &mut vec![message(&first), message(&second)])
+            .unwrap();
+        assert_eq!(applied, vec![second]);
+    }
+
+    #[test]
+    fn export_messages_test() {
+        let mut storage = MemStorage::<Note, 3>::new();
+        storage.set_retain_messages(true);
+        let mut clock = MerkleClock::<3>::local();
+        let t = |millis| Timestamp::new(millis, 0, "remote".to_string()).to_string();
+        let mut messages = vec![
+            Message::change(
+                t(1712898800002),
+                NOTE_TABLE,
+                "1",
+                "content",
+                ValueType::String,
+                "b",
+            ),
+            Message::change(
+                t(1712898800001),
+                NOTE_TABLE,
+                "1",
+                "content",
+                ValueType::String,
+                "a",
+            ),
+            Message::change(
+                t(1712898800003),
+                NOTE_TABLE,
+                "2",
+                "content",
+                ValueType::String,
+                "c",
+            ),
+            Message::tombstone(t(1712898800004), NOTE_TABLE, "2"),
+        ];
+        storage.apply_messages(&mut clock, &mut messages).unwrap();
+
+        let exported = storage.export_messages();
+        assert_eq!(exported.len(), 4);
+        assert!(exported.is_sorted_by(|a, b| a.timestamp < b.timestamp));
+
+        let mut replayed = MemStorage::<Note, 3>::new();
+        let mut replayed_clock = MerkleClock::<3>::local();
+        replayed
+            .apply_messages(&mut replayed_clock, &mut exported.clone())
+            .unwrap();
+        assert_eq!(replayed.items(), storage.items());
+        assert_eq!(
+            replayed_clock.merkle().root_hash(),
+            clock.merkle().root_hash()
+        );
+
+        // Nothing is kept unless asked for
+        let mut plain = MemStorage::<Note, 3>::new();
+        plain
+            .apply_messages(&mut replayed_clock, &mut exported.clone())
+            .unwrap();
+        assert!(plain.export_messages().is_empty());
+    }
+
+    #[test]
+    fn from_messages_test() {
+        let t = |millis| Timestamp::new(millis, 0, "remote".to_string()).to_string();
+        let mut clock = MerkleClock::<3>::local();
+        let storage = MemStorage::<Note, 3>::from_messages(
+            [
+                Message::change(
+                    t(1712898800002),
+                    NOTE_TABLE,
+                    "1",
+                    "content",
+                    ValueType::String,
+                    "b",
+                ),
+                Message::change(
+                    t(1712898800001),
+                    NOTE_TABLE,
+                    "1",
+                    "content",
+                    ValueType::String,
+                    "a",
+                ),
+                Message::change(
+                    t(1712898800003),
+                    NOTE_TABLE,
+                    "2",
+                    "title",
+                    ValueType::String,
+                    "c",
+                ),
+            ],
+            &mut clock,
+        )
+        .unwrap();
+
+        assert_eq!(storage.items().len(), 2);
+        assert_eq!(storage.items()["1"].content, "b");
+        assert_eq!(storage.items()["2"].title, "c");
+        assert_eq!(storage.applied_messages().len(), 3);
+        assert_eq!(clock.merkle().stored_keys().len(), 3);
+
+        let invalid = [Message::change(
+            "",
+            NOTE_TABLE,
+            "1",
+            "content",
+            ValueType::String,
+            "a",
+        )];
+        assert!(MemStorage::<Note, 3>::from_messages(invalid, &mut clock).is_err());
+    }
+
+    #[test]
+    fn verify_against_clock_test() {
+        let t = |millis| Timestamp::new(millis, 0, "remote".to_string());
+        let message = |millis| {
+            Message::change(
+                t(millis).to_string(),
+                NOTE_TABLE,
+                "1",
+                "content",
+                ValueType::String,
+                "a",
+            )
+        };
+        let mut clock = MerkleClock::<3>::local();
+        let storage = MemStorage::<Note, 3>::from_messages(
+            [message(1712898800001), message(1712898800002)],
+            &mut clock,
+        )
+        .unwrap();
+        storage.verify_against_clock(&clock).unwrap();
+
+        // An entry the store doesn't know about
+        let mut extra = clock.clone();
+        extra.merkle_mut().insert(&t(1712898800003));
+        assert!(storage.verify_against_clock(&extra).is_err());
+
+        // An applied message missing in the trie
+        let mut missing = MerkleClock::<3>::local();
+        missing.merkle_mut().insert(&t(1712898800001));
+        missing.merkle_mut().insert(&t(1712898800004));
+        let err = storage.verify_against_clock(&missing).unwrap_err();
+        assert!(err.to_string().contains("missing"));
+    }
+
+    #[test]
+    fn merge_store_test() {
+        let t = |millis, node: &str| Timestamp::new(millis, 0, node.to_string()).to_string();
+        let note = |timestamp: String, row: &str, value: &str| {
+            Message::change(
+                timestamp,
+                NOTE_TABLE,
+                row,
+                "content",
+                ValueType::String,
+                value,
+            )
+        };
+        let shared = note(t(1712898800000, "client0000000001"), "1", "shared");
+
+        let mut left = MemStorage::<Note, 3>::new();
+        let mut left_clock = MerkleClock::<3>::local();
+        left.apply_messages(
+            &mut left_clock,
+            &mut vec![
+                shared.clone(),
+                note(t(1712898800002, "client0000000001"), "1", "left"),
+                note(t(1712898800003, "client0000000001"), "2", "only left"),
+            ],
+        )
+        .unwrap();
+
+        let mut right = MemStorage::<Note, 3>::new();
+        let mut right_clock = MerkleClock::<3>::local();
+        right
+            .apply_messages(
+                &mut right_clock,
+                &mut vec![
+                    shared,
+                    // Superseded below, not retained
+                    note(t(1712898800001, "client0000000002"), "1", "old right"),
+                    note(t(1712898800004, "client0000000002"), "1", "right"),
+                    note(t(1712898800005, "client0000000002"), "3", "only right"),
+                ],
+            )
+            .unwrap();
+
+        left.merge_store(&right, &mut left_clock).unwrap();
+
+        // The latest write wins the overlapping row, disjoint rows are kept
+        assert_eq!(left.items()["1"].content, "right");
+        assert_eq!(left.items()["2"].content, "only left");
+        assert_eq!(left.items()["3"].content, "only right");
+        assert_eq!(left.applied_messages().len(), 6);
+        assert!(left
+            .applied_messages()
+            .is_superset(right.applied_messages()));
+        assert_eq!(left_clock.merkle().stored_keys().len(), 6);
+
+        // Merging the other way round converges to the same state
+        right.merge_store(&left, &mut right_clock).unwrap();
+        assert_eq!(right.items(), left.items());
+        assert_eq!(
+            right_clock.merkle().root_hash(),
+            left_clock.merkle().root_hash()
+        );
+    }
+
+    #[test]
+    fn subscribe_test() {
+        let mut storage = MemStorage::<Note, 3>::new();
+        let mut clock = MerkleClock::new(
+            Timestamp::new(0, 0, "client".to_string()),
+            MerkleTrie::<3>::new(),
+        );
+
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let sink = received.clone();
+        storage.subscribe(Box::new(move |m: &Message| {
+            sink.lock().unwrap().push(m.timestamp.clone())
+        }));
+
+        let first = Timestamp::new(1712898800001, 0, "remote".to_string()).to_string();
+        let second = Timestamp::new(1712898800002, 0, "remote".to_string()).to_string();
+        let mut messages = vec![
+            Message::change(&first, NOTE_TABLE, "1", "content", ValueType::String, "a"),
+            Message::change(&second, NOTE_TABLE, "2", "content", ValueType::String, "b"),
+        ];
+        storage.apply_messages(&mut clock, &mut messages).unwrap();
+        // Already applied messages don't fire again
+        storage.apply_messages(&mut clock, &mut messages).unwrap();
+
+        assert_eq!(*received.lock().unwrap(), vec![first, second]);
+    }
+
+    #[test]
+    fn applied_since_test() {
+        let mut storage = MemStorage::<Note, 3>::new();
+        let mut clock = MerkleClock::new(
+            Timestamp::new(0, 0, "client".to_string()),
+            MerkleTrie::<3>::new(),
+        );
+
+        let timestamps: Vec<String> = (1..=4)
+            .map(|i| Timestamp::new(1712898800000 + i, 0, "remote".to_string()).to_string())
+            .collect();
+        let mut messages: Vec<Message> = timestamps
+            .iter()
+            .rev()
+            .enumerate()
+            .map(|(row, t)| {
+                Message::change(
+                    t,
+                    NOTE_TABLE,
+                    row.to_string(),
+                    "content",
+                    ValueType::String,
+                    "x",
+                )
+            })
+            .collect();
+        storage.apply_messages(&mut clock, &mut messages).unwrap();
+
+        assert_eq!(
+            storage.applied_since(&timestamps[1]),
+            vec![timestamps[2].as_str(), timestamps[3].as_str()]
+        );
+        assert_eq!(storage.applied_since(&timestamps[3]), Vec::<&str>::new());
+        assert_eq!(storage.applied_since("").len(), 4);
+    }
+
+    #[test]
+    fn snapshot_restore_test() {
+        let mut storage = MemStorage::<Note, 3>::new();
+        let mut clock = MerkleClock::new(
+            Timestamp::new(0, 0, "server".to_string()),
+            MerkleTrie::<3>::new(),
+        );
+
+        let older = Timestamp::new(1712898800001, 0, "remote".to_string()).to_string();
+        let newer = Timestamp::new(1712898800002, 0, "remote".to_string()).to_string();
+        let mut messages = vec![
+            Message::change(&newer, NOTE_TABLE, "1", "content", ValueType::String, "a"),
+            Message::change(&older, NOTE_TABLE, "2", "content", ValueType::String, "b"),
+        ];
+        storage.apply_messages(&mut clock, &mut messages).unwrap();
+        let bytes = storage.snapshot().unwrap();
+
+        let mut restored = MemStorage::<Note, 3>::new();
+        let mut restored_clock = MerkleClock::new(
+            Timestamp::new(0, 0, "client".to_string()),
+            MerkleTrie::<3>::new(),
+        );
+        restored.restore(&bytes).unwrap();
+        restored_clock.restore(&clock.snapshot().unwrap()).unwrap();
+
+        assert_eq!(restored.items()["1"].content, "a");
+        assert_eq!(restored.items()["2"].content, "b");
+        assert_eq!(restored.applied_messages(), storage.applied_messages());
+        assert_eq!(
+            restored.field_timestamp("1", "content"),
+            Some(newer.as_str())
+        );
+        assert_eq!(
+            restored_clock.merkle().root_hash(),
+            clock.merkle().root_hash()
+        );
+
+        // The restored field state still decides conflicts of the tail
+        let mut tail = vec![Message::change(
+            &older,
+            NOTE_TABLE,
+            "1",
+            "content",
+            ValueType::String,
+            "stale",
+        )];
+        restored
+            .apply_messages(&mut restored_clock, &mut tail)
+            .unwrap();
+        assert_eq!(restored.items()["1"].content, "a");
+
+        assert!(restored.restore(b"{}").is_err());
+    }
+
+    /// A counter whose highest value wins, regardless of the write order
+    #[derive(Debug, Default, Serialize, Deserialize)]
+    struct MaxCounter {
+        value: i64,
+    }
+
+    impl MessageHandler for MaxCounter {
+        fn from_message(_message: &Message) -> Self {
+            MaxCounter::default()
+        }
+
+        fn handle_message(&mut self, message: &Message) -> anyhow::Result<()> {
+            self.value = message.value.parse()?;
+            Ok(())
+        }
+
+        fn table_name() -> String {
+            String::from("counters")
+        }
+
+        fn resolve(&self, current: &Message, incoming: &Message) -> Resolution {
+            let current: i64 = current.value.parse().unwrap_or_default();
+            let incoming: i64 = incoming.value.parse().unwrap_or_default();
+            if incoming > current {
+                Resolution::TakeIncoming
+            } else {
+                Resolution::KeepCurrent
+            }
+        }
+    }
+
+    #[test]
+    fn hard_delete_test() {
+        let mut storage = MemStorage::<Note, 3>::new();
+        storage.set_retain_messages(true);
+        let mut clock = MerkleClock::new(
+            Timestamp::new(0, 0, "client".to_string()),
+            MerkleTrie::<3>::new(),
+        );
+        let t = |i: i64| Timestamp::new(1712898800000 + i, 0, "remote".to_string()).to_string();
+        let mut messages = vec![
+            Message::change(t(0), NOTE_TABLE, "1", "content", ValueType::String, "a"),
+            Message::change(t(1), NOTE_TABLE, "1", "content", ValueType::String, "b"),
+            Message::change(t(2), NOTE_TABLE, "2", "content", ValueType::String, "c"),
+        ];
+        storage.apply_messages(&mut clock, &mut messages).unwrap();
+
+        // Only deleted rows go away for good
+        assert!(storage.hard_delete(&mut clock, "1").is_err());
+        assert_eq!(storage.items().len(), 2);
+
+        let mut tombstone = vec![Message::tombstone(t(3), NOTE_TABLE, "1")];
+        storage.apply_messages(&mut clock, &mut tombstone).unwrap();
+        let hash = clock.merkle().root_hash();
+        storage.hard_delete(&mut clock, "1").unwrap();
+
+        assert!(!storage.items().contains_key("1"));
+        assert!(storage.items().contains_key("2"));
+        assert_eq!(storage.field_value("1", "content"), None);
+        // The trie and the applied messages still match the server's
+        assert_eq!(clock.merkle().root_hash(), hash);
+        storage.verify_against_clock(&clock).unwrap();
+        assert_eq!(
+            storage
+                .export_messages()
+                .iter()
+                .map(|m| &m.timestamp)
+                .collect::<Vec<_>>(),
+            vec![&t(2)]
+        );
+
+        // Later messages for the row are recorded without bringing it back,
+        // also after a restore
+        let mut restored = MemStorage::<Note, 3>::new();
+        restored.restore(&storage.snapshot().unwrap()).unwrap();
+        for storage in [&mut storage, &mut restored] {
+            let mut late = vec![Message::change(
+                t(4),
+                NOTE_TABLE,
+                "1",
+                "content",
+                ValueType::String,
+                "late",
+            )];
+            storage
+                .apply_messages(&mut clock.clone(), &mut late)
+                .unwrap();
+            assert!(!storage.items().contains_key("1"));
+            assert!(storage.applied_messages().contains(&t(4)));
+        }
+
+        // An unknown row is nothing to delete
+        storage.hard_delete(&mut clock, "1").unwrap();
+    }
+
+    #[test]
+    fn by_index_test() {
+        let mut storage = MemStorage::<Note, 3>::new();
+        storage.add_index("title");
+        let mut clock = MerkleClock::<3>::local();
+        let t = |millis| Timestamp::new(millis, 0, "remote".to_string()).to_string();
+        let title = |millis, row, value| {
+            Message::change(
+                t(millis),
+                NOTE_TABLE,
+                row,
+                "title",
+                ValueType::String,
+                value,
+            )
+        };
+        let mut messages = vec![
+            title(1712898800001, "1", "work"),
+            title(1712898800002, "2", "home"),
+            title(1712898800003, "3", "work"),
+            Message::change(
+                t(1712898800004),
+                NOTE_TABLE,
+                "3",
+                "content",
+                ValueType::String,
+                "work",
+            ),
+        ];
+        storage.apply_messages(&mut clock, &mut messages).unwrap();
+
+        let ids = |storage: &MemStorage<Note, 3>, value| {
+            storage
+                .by_index("title", value)
+                .into_iter()
+                .map(|note| note.id.clone())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(ids(&storage, "work"), vec!["1", "3"]);
+        assert_eq!(ids(&storage, "home"), vec!["2"]);
+        assert!(ids(&storage, "other").is_empty());
+        // Only indexed columns can be queried
+        assert!(storage.by_index("content", "work").is_empty());
+
+        // A changed value moves the row, an older one is ignored
+        let mut messages = vec![
+            title(1712898800005, "3", "home"),
+            title(1712898800000, "1", "home"),
+        ];
+        storage.apply_messages(&mut clock, &mut messages).unwrap();
+        assert_eq!(ids(&storage, "work"), vec!["1"]);
+        assert_eq!(ids(&storage, "home"), vec!["2", "3"]);
+
+        // An index added later covers the current values
+        storage.add_index("content");
+        assert_eq!(storage.by_index("content", "work").len(), 1);
+
+        // And is rebuilt on restore
+        let mut restored = MemStorage::<Note, 3>::new();
+        restored.add_index("title");
+        restored.restore(&storage.snapshot().unwrap()).unwrap();
+        assert_eq!(ids(&restored, "home"), vec!["2", "3"]);
+    }
+
+    #[test]
+    fn resolve_test() {
+        let mut storage = MemStorage::<MaxCounter, 3>::new();
+        let mut clock = MerkleClock::new(
+            Timestamp::new(0, 0, "client".to_string()),
+            MerkleTrie::<3>::new(),
+        );
+
+        let values = ["3", "7", "5"];
+        let mut messages: Vec<Message> = values
+            .iter()
+            .enumerate()
+            .map(|(i, value)| {
+                let t = Timestamp::new(1712898800000 + i as i64, 0, "remote".to_string());
+                Message::change(
+                    t.to_string(),
+                    "counters",
+                    "1",
+                    "count",
+                    ValueType::Number,
+                    *value,
+                )
+            })
+            .collect();
+        storage.apply_messages(&mut clock, &mut messages).unwrap();
+
+        // The latest write is 5, but the resolver keeps the maximum
+        assert_eq!(storage.items()["1"].value, 7);
+        assert_eq!(
+            storage.field_timestamp("1", "count"),
+            Some(messages[1].timestamp.as_str())
+        );
+        assert_eq!(storage.applied_messages().len(), 3);
+    }
+
+    /// A todo that is either active or archived, depending on its dataset
+    #[derive(Debug, Default, Serialize, Deserialize)]
+    struct Todo {
+        content: String,
+        archived: bool,
+    }
+
+    impl MessageHandler for Todo {
+        fn from_message(_message: &Message) -> Self {
+            Todo::default()
+        }
+
+        fn handle_message(&mut self, message: &Message) -> anyhow::Result<()> {
+            self.content.clone_from(&message.value);
+            self.archived = message.dataset == "archived_todos";
+            Ok(())
+        }
+
+        fn table_name() -> String {
+            String::from("todos")
+        }
+
+        fn table_names() -> Vec<String> {
+            vec![Self::table_name(), String::from("archived_todos")]
+        }
+    }
+
+    #[test]
+    fn table_names_test() {
+        let mut storage = MemStorage::<Todo, 3>::new();
+        let mut clock = MerkleClock::new(
+            Timestamp::new(0, 0, "client".to_string()),
+            MerkleTrie::<3>::new(),
+        );
+
+        let mut messages: Vec<Message> = [("todos", "1"), ("archived_todos", "2"), ("notes", "3")]
+            .iter()
+            .enumerate()
+            .map(|(i, (dataset, row))| {
+                let t = Timestamp::new(1712898800000 + i as i64, 0, "remote".to_string());
+                Message::change(
+                    t.to_string(),
+                    *dataset,
+                    *row,
+                    "content",
+                    ValueType::String,
+                    "x",
+                )
+            })
+            .collect();
+        let applied = storage.apply_messages(&mut clock, &mut messages).unwrap();
+
+        // Both datasets of the item are routed to it, the unknown one isn't
+        assert_eq!(applied.len(), 2);
+        assert!(!storage.items()["1"].archived);
+        assert!(storage.items()["2"].archived);
+        assert!(!storage.items().contains_key("3"));
+        assert_eq!(Note::table_names(), vec![NOTE_TABLE]);
+    }
+}
@@ -1,28 +1,128 @@
 use std::collections::{HashMap, HashSet};
 use std::fmt::Debug;
 
+use anyhow::bail;
 use serde::de::DeserializeOwned;
 use serde::Serialize;
 
 use merkle_trie_clock::clock::MerkleClock;
 use merkle_trie_clock::models::Message;
+use merkle_trie_clock::timestamp::Timestamp;
 
-pub trait Store<Item: DeserializeOwned + Serialize + Debug, const MERKLE_BASE: usize> {
+/// Where the syncer keeps its items. `Send`, so that a syncer can move to
+/// another thread, e.g. behind a `Mutex`.
+pub trait Store<Item: DeserializeOwned + Serialize + Debug, const MERKLE_BASE: usize>:
+    Send
+{
+    /// Apply a batch of messages and return the timestamps of the ones not
+    /// seen before, already applied messages are skipped.
     fn apply_messages(
         &mut self,
         clock: &mut MerkleClock<MERKLE_BASE>,
         messages: &mut Vec<Message>,
-    ) -> anyhow::Result<()>;
+    ) -> anyhow::Result<Vec<String>>;
 
     fn items(&self) -> &HashMap<String, Item>;
 
     fn applied_messages(&self) -> &HashSet<String>;
+
+    /// The value a field currently holds, if the store keeps track of it
+    fn field_value(&self, _row: &str, _column: &str) -> Option<&str> {
+        None
+    }
+
+    /// Serialize the items and applied messages, so another client can be
+    /// seeded with [`Store::restore`] and then sync only the tail.
+    fn snapshot(&self) -> anyhow::Result<Vec<u8>>;
+
+    /// Replace the whole local state with a [`Store::snapshot`]
+    fn restore(&mut self, bytes: &[u8]) -> anyhow::Result<()>;
+
+    /// Remove a deleted row for good: its item and field values. Its
+    /// messages stay applied and in the merkle trie of `clock`, which still
+    /// matches the server's, and messages for the row applied later on are
+    /// only recorded, so a sync doesn't bring it back. Fails for a row
+    /// without a tombstone.
+    fn hard_delete(
+        &mut self,
+        _clock: &mut MerkleClock<MERKLE_BASE>,
+        _row: &str,
+    ) -> anyhow::Result<()> {
+        bail!("Hard delete isn't supported by this store")
+    }
+
+    /// Timestamps of the applied messages newer than `since`, oldest first.
+    fn applied_since(&self, since: &str) -> Vec<&str> {
+        let mut timestamps: Vec<&str> = self
+            .applied_messages()
+            .iter()
+            .map(String::as_str)
+            .filter(|timestamp| *timestamp > since)
+            .collect();
+        timestamps.sort_unstable();
+        timestamps
+    }
+
+    /// Check that the merkle trie of `clock` holds exactly the applied
+    /// messages: each of them is in the trie, and the trie has no more
+    /// entries than there are applied messages.
+    fn verify_against_clock(&self, clock: &MerkleClock<MERKLE_BASE>) -> anyhow::Result<()> {
+        for timestamp in self.applied_messages() {
+            if !clock.merkle().contains(&Timestamp::parse(timestamp)?) {
+                bail!(
+                    "Applied message {} is missing in the merkle trie",
+                    timestamp
+                );
+            }
+        }
+
+        let applied = self.applied_messages().len() as u64;
+        if clock.merkle().length() != applied {
+            bail!(
+                "The merkle trie has {} entries for {} applied messages",
+                clock.merkle().length(),
+                applied
+            );
+        }
+
+        Ok(())
+    }
 }
 
-pub trait MessageHandler: Sized {
+/// An item kept by a [`Store`], built from and updated by messages
+pub trait MessageHandler: Sized + Send {
     fn from_message(message: &Message) -> Self;
 
     fn handle_message(&mut self, message: &Message) -> anyhow::Result<()>;
 
     fn table_name() -> String;
+
+    /// All the datasets routed to this item, e.g. `todos` and
+    /// `archived_todos`. The item tells them apart by `message.dataset`,
+    /// rows are shared between them. Defaults to [`MessageHandler::table_name`].
+    fn table_names() -> Vec<String> {
+        vec![Self::table_name()]
+    }
+
+    /// Decide which value a field keeps when an incoming message touches a
+    /// field already set by `current`. Defaults to last writer wins, by the
+    /// parsed timestamps like [`Message::cmp_by_timestamp`].
+    fn resolve(&self, current: &Message, incoming: &Message) -> Resolution {
+        if incoming.cmp_by_timestamp(current).is_gt() {
+            Resolution::TakeIncoming
+        } else {
+            Resolution::KeepCurrent
+        }
+    }
+}
+
+/// The outcome of two messages writing the same field
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Resolution {
+    /// The field keeps its value, the incoming message is only recorded
+    KeepCurrent,
+    /// The incoming message is applied
+    TakeIncoming,
+    /// The incoming message is applied with this value instead of its own
+    Merge(String),
 }
@@ -1,35 +1,35 @@
+use std::collections::{HashMap, HashSet};
 use std::env;
 use std::fmt::Debug;
 
-use anyhow::bail;
 use log::debug;
 use serde::de::DeserializeOwned;
-use serde::{Deserialize, Serialize};
+use serde::Serialize;
 
 use merkle_trie_clock::clock::MerkleClock;
+use merkle_trie_clock::error::{BaseMismatch, NonConvergence};
 use merkle_trie_clock::merkle::MerkleTrie;
-use merkle_trie_clock::models::{Message, RowParam, ValueType};
+use merkle_trie_clock::models::{Message, RowParam};
+use merkle_trie_clock::sync::{HandshakeRequest, SyncRequest};
 use merkle_trie_clock::timestamp::Timestamp;
 
+use crate::error::SyncerError;
 use crate::mem_storage::{MemStorage, MERKLE_BASE_CONST};
 use crate::storage::{MessageHandler, Store};
+use crate::transport::{DefaultTransport, Transport, DEFAULT_ENDPOINT};
 
-const DEFAULT_NODE_NAME: &str = "CLIENT";
-
-const ENDPOINT: &str = "http://localhost:8006";
-
-#[derive(Debug, Serialize, Deserialize)]
-struct SyncRequest<const MERKLE_BASE: usize> {
-    group_id: String,
-    client_id: String,
-    messages: Vec<Message>,
-    merkle: MerkleTrie<MERKLE_BASE>,
-}
-
-#[derive(Debug, Serialize, Deserialize)]
-struct SyncResponse<const MERKLE_BASE: usize> {
-    messages: Vec<Message>,
-    merkle: MerkleTrie<MERKLE_BASE>,
+/// What a [`Syncer::sync`] exchanged with the server
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SyncStats {
+    /// Messages uploaded
+    pub sent: usize,
+    /// Messages received from the server
+    pub received: usize,
+    /// The time since which the tries still differed after the first
+    /// round, `None` if one round was enough
+    pub diff_time: Option<i64>,
+    /// Sync requests sent, zero if the handshake found nothing to sync
+    pub iterations: usize,
 }
 
 pub struct Syncer<
@@ -40,20 +40,17 @@ pub struct Syncer<
     merkle_clock: MerkleClock<MERKLE_BASE>,
     sync_enabled: bool,
     storage: Box<dyn Store<Item, MERKLE_BASE>>,
-}
-
-unsafe impl<
-        Item: 'static + MessageHandler + DeserializeOwned + Serialize + Debug,
-        const MERKLE_BASE: usize,
-    > Sync for Syncer<Item, MERKLE_BASE>
-{
-}
-
-unsafe impl<
-        Item: 'static + MessageHandler + DeserializeOwned + Serialize + Debug,
-        const MERKLE_BASE: usize,
-    > Send for Syncer<Item, MERKLE_BASE>
-{
+    transport: Box<dyn Transport<MERKLE_BASE>>,
+    /// Locally applied messages not yet acknowledged by a successful sync
+    outbound: Vec<Message>,
+    /// Timestamps of messages the server is known to have, either because
+    /// it sent them to us or because it accepted them in a sync
+    acknowledged: HashSet<String>,
+    /// Sent along with every sync request, e.g. for authentication
+    headers: Vec<(String, String)>,
+    /// The buckets whose messages couldn't be applied, with their local
+    /// and remote hashes then, see [`Syncer::unresolved_buckets`]
+    unresolved: HashMap<i64, (Option<u64>, Option<u64>)>,
 }
 
 impl<Item: MessageHandler + DeserializeOwned + Serialize + Debug, const MERKLE_BASE: usize> Default
@@ -68,16 +65,37 @@ impl<Item: MessageHandler + DeserializeOwned + Serialize + Debug, const MERKLE_B
     Syncer<Item, MERKLE_BASE>
 {
     pub fn new() -> Self {
-        let node_name = env::var("CLIENT").unwrap_or(DEFAULT_NODE_NAME.to_string());
-        let t = Timestamp::new(0, 0, node_name.clone());
-        let c = MerkleClock::new(t, MerkleTrie::<MERKLE_BASE>::new());
+        Self::builder().build()
+    }
 
-        Syncer {
-            node_name,
-            merkle_clock: c,
-            sync_enabled: true,
-            storage: Box::new(MemStorage::new()),
-        }
+    pub fn with_transport(transport: Box<dyn Transport<MERKLE_BASE>>) -> Self {
+        Self::builder().transport(transport).build()
+    }
+
+    /// Keep the items in `storage` instead of a [`MemStorage`]
+    pub fn with_storage(storage: Box<dyn Store<Item, MERKLE_BASE>>) -> Self {
+        Self::builder().storage(storage).build()
+    }
+
+    pub fn builder() -> SyncerBuilder<Item, MERKLE_BASE> {
+        SyncerBuilder::default()
+    }
+
+    /// Authenticate every sync request with a bearer token
+    pub fn with_auth(mut self, token: &str) -> Self {
+        self.set_header("Authorization", &format!("Bearer {}", token));
+        self
+    }
+
+    /// Send a header with every sync request, replacing a previous value
+    pub fn set_header(&mut self, name: &str, value: &str) {
+        self.headers
+            .retain(|(existing, _)| !existing.eq_ignore_ascii_case(name));
+        self.headers.push((name.to_string(), value.to_string()));
+    }
+
+    pub fn headers(&self) -> &[(String, String)] {
+        &self.headers
     }
 
     pub fn insert(
@@ -85,36 +103,52 @@ impl<Item: MessageHandler + DeserializeOwned + Serialize + Debug, const MERKLE_B
         group_id: &str,
         table: &str,
         row_params: Vec<RowParam>,
-    ) -> anyhow::Result<String> {
-        // This is roughly comparable to assigning a primary key value to the row if
-        // it were in a RDBMS.
-        let id = uuid::Uuid::new_v4().to_string();
+    ) -> Result<String, SyncerError> {
+        let mut ids = self.insert_many(group_id, table, vec![row_params])?;
+        Ok(ids.remove(0))
+    }
 
-        // Because we're going to generate a "change" message for every field in the
-        // object that is being "inserted" (i.e., there)
+    /// Insert several rows, each with its own id, and send all their messages
+    /// in a single sync. Returns the ids in the order of `rows`.
+    pub fn insert_many(
+        &mut self,
+        group_id: &str,
+        table: &str,
+        rows: Vec<Vec<RowParam>>,
+    ) -> Result<Vec<String>, SyncerError> {
+        let mut ids = Vec::with_capacity(rows.len());
         let mut messages = vec![];
-        for x in row_params {
-            // Here we update the timestamp, but not update the merkle tree
-            // Update merkle tree will be operated when sync called, and
-            // data exactly executed!
-            let next_time = self.merkle_clock.timer_mut().send()?;
+        for row_params in rows {
+            // This is roughly comparable to assigning a primary key value to the row if
+            // it were in a RDBMS.
+            let id = uuid::Uuid::new_v4().to_string();
+
+            // Because we're going to generate a "change" message for every field in the
+            // object that is being "inserted" (i.e., there)
+            for x in row_params {
+                // Here we update the timestamp, but not update the merkle tree
+                // Update merkle tree will be operated when sync called, and
+                // data exactly executed!
+                let next_time = self.merkle_clock.new_event_timestamp()?;
 
-            messages.push(Message {
                 // Note that every message we create/send gets its own, globally-unique
                 // timestamp. In effect, there is a 1-1 relationship between the timestamp
                 // and this specific message.
-                timestamp: next_time.to_string(),
-                dataset: table.to_string(),
-                row: x.id.unwrap_or(id.clone()),
-                column: x.column,
-                value_type: x.value_type,
-                value: x.value,
-            })
+                messages.push(Message::change(
+                    next_time,
+                    table,
+                    x.id.unwrap_or(id.clone()),
+                    x.column,
+                    x.value_type,
+                    x.value,
+                ))
+            }
+            ids.push(id);
         }
 
         self.send_messages(group_id, messages)?;
 
-        Ok(id)
+        Ok(ids)
     }
 
     pub fn update(
@@ -122,22 +156,22 @@ impl<Item: MessageHandler + DeserializeOwned + Serialize + Debug, const MERKLE_B
         group_id: &str,
         table: &str,
         row_params: Vec<RowParam>,
-    ) -> anyhow::Result<()> {
+    ) -> Result<(), SyncerError> {
         let mut messages = vec![];
         for x in row_params {
             if let Some(id) = x.id {
-                let next_time = self.merkle_clock.timer_mut().send()?;
-                messages.push(Message {
-                    // Note that every message we create/send gets its own, globally-unique
-                    // timestamp. In effect, there is a 1-1 relationship between the timestamp
-                    // and this specific message.
-                    timestamp: next_time.to_string(),
-                    dataset: table.to_string(),
-                    row: id,
-                    column: x.column,
-                    value_type: x.value_type,
-                    value: x.value,
-                })
+                let next_time = self.merkle_clock.new_event_timestamp()?;
+                // Note that every message we create/send gets its own, globally-unique
+                // timestamp. In effect, there is a 1-1 relationship between the timestamp
+                // and this specific message.
+                messages.push(Message::change(
+                    next_time,
+                    table,
+                    id,
+                    x.column,
+                    x.value_type,
+                    x.value,
+                ))
             }
         }
         self.send_messages(group_id, messages)?;
@@ -145,119 +179,364 @@ impl<Item: MessageHandler + DeserializeOwned + Serialize + Debug, const MERKLE_B
         Ok(())
     }
 
-    pub fn delete(&mut self, group_id: &str, table: &str, id: &str) -> anyhow::Result<()> {
-        let next_time = self.merkle_clock.timer_mut().send()?;
-        self.send_messages(
-            group_id,
-            vec![Message {
-                timestamp: next_time.to_string(),
-                dataset: table.to_string(),
-                row: id.to_string(),
-                column: "tombstone".to_string(),
-                value_type: ValueType::Number,
-                value: "1".to_string(),
-            }],
-        )?;
+    /// Like [`Syncer::update`], but skips the params whose value equals the
+    /// one the store currently holds, so unchanged fields get no new timestamp.
+    pub fn update_changed(
+        &mut self,
+        group_id: &str,
+        table: &str,
+        row_params: Vec<RowParam>,
+    ) -> Result<(), SyncerError> {
+        let changed = row_params
+            .into_iter()
+            .filter(|x| {
+                x.id.as_deref().is_none_or(|id| {
+                    self.storage.field_value(id, &x.column) != Some(x.value.as_str())
+                })
+            })
+            .collect();
+        self.update(group_id, table, changed)
+    }
+
+    pub fn delete(&mut self, group_id: &str, table: &str, id: &str) -> Result<(), SyncerError> {
+        let next_time = self.merkle_clock.new_event_timestamp()?;
+        self.send_messages(group_id, vec![Message::tombstone(next_time, table, id)])?;
         Ok(())
     }
 
+    /// Remove a row deleted with [`Syncer::delete`] for good, see
+    /// [`Store::hard_delete`]. Nothing is sent, the row stays deleted on the
+    /// other peers, and gone here whatever is synced for it later.
+    pub fn hard_delete(&mut self, row: &str) -> Result<(), SyncerError> {
+        self.storage
+            .hard_delete(&mut self.merkle_clock, row)
+            .map_err(SyncerError::Storage)
+    }
+
     pub fn sync(
         &mut self,
         group_id: &str,
         initial_messages: Vec<Message>,
         since: Option<i64>,
-    ) -> anyhow::Result<Option<Vec<Message>>> {
+    ) -> Result<SyncStats, SyncerError> {
+        self.sync_round(group_id, initial_messages, since, 1)
+    }
+
+    /// A round of [`Syncer::sync`], `iteration` counting the rounds so far
+    fn sync_round(
+        &mut self,
+        group_id: &str,
+        initial_messages: Vec<Message>,
+        since: Option<i64>,
+        iteration: usize,
+    ) -> Result<SyncStats, SyncerError> {
+        let mut stats = SyncStats::default();
         if !self.sync_enabled {
-            return Ok(None);
+            return Ok(stats);
         }
 
         let mut messages = initial_messages;
 
         if let Some(since) = since {
-            let since = Timestamp::new(since, 0, "".to_string()).to_string();
+            let since = Timestamp::from_millis(since).to_string();
             messages.retain(|msg| msg.timestamp >= since);
         }
+        // Don't upload again what the server already has
+        messages.retain(|msg| !self.is_synced(&msg.timestamp));
+        let sent: Vec<String> = messages.iter().map(|m| m.timestamp.clone()).collect();
 
-        let client = reqwest::blocking::Client::new();
-        let endpoint = format!("{}/sync", ENDPOINT);
-
-        let diff_time = {
-            let body = serde_json::to_string(&SyncRequest {
-                group_id: group_id.to_string(),
-                client_id: self.node_name.clone(),
-                messages,
-                merkle: self.merkle_clock.merkle().clone(),
-            })?;
-
-            let res = client
-                .post(endpoint)
-                .header("Content-Type", "application/json")
-                .body(body)
-                .send()?
-                .json::<SyncResponse<MERKLE_BASE>>()?;
+        // With nothing to upload, first check whether the root hashes match,
+        // which saves transferring the whole trie when already in sync
+        if messages.is_empty() && since.is_none() {
+            let res = self
+                .transport
+                .handshake(
+                    &HandshakeRequest {
+                        group_id: group_id.to_string(),
+                        client_id: self.node_name.clone(),
+                        root_hash: self.merkle_clock.merkle().root_hash(),
+                        base: MERKLE_BASE,
+                    },
+                    &self.headers,
+                )
+                .map_err(SyncerError::Transport)?;
+            if res.base != MERKLE_BASE {
+                return Err(BaseMismatch {
+                    local: MERKLE_BASE,
+                    remote: res.base,
+                }
+                .into());
+            }
+            if res.in_sync {
+                return Ok(stats);
+            }
+        }
+
+        let (diff_time, remote, unapplied) = {
+            let res = self
+                .transport
+                .sync(
+                    &SyncRequest {
+                        group_id: group_id.to_string(),
+                        client_id: self.node_name.clone(),
+                        messages,
+                        merkle: self.merkle_clock.merkle().clone(),
+                        base: MERKLE_BASE,
+                    },
+                    &self.headers,
+                )
+                .map_err(SyncerError::Transport)?;
             debug!("Got synced response: {:#?}", res);
+            res.check_base()?;
+            stats.iterations = 1;
+            stats.sent = sent.len();
+            stats.received = res.messages.len();
+            self.acknowledged.extend(sent);
 
+            // Received messages the store skipped, e.g. of an unknown
+            // dataset: the tries can't converge at their time
+            let mut unapplied = 0;
             if !res.messages.is_empty() {
                 // handle received messages
                 debug!("{:#?}", res.messages);
-                self.receive_messages(res.messages)?;
+                unapplied = self.receive_messages(res.messages)?;
             }
 
-            self.merkle_clock.merkle_mut().diff(&res.merkle)
+            (
+                self.diff_past_unresolved(&res.merkle)?,
+                res.merkle,
+                unapplied,
+            )
         };
+        stats.diff_time = diff_time;
 
         if let Some(diff_time) = diff_time {
             if diff_time > 0 {
-                if let Some(since) = since {
-                    if since == diff_time {
-                        bail!(
-                            "A bug happened while syncing and the client \
-                        was unable to get in sync with the server. \
-                        This is an internal error that shouldn't happen"
-                        );
+                if let Some(since) = since.filter(|since| *since == diff_time) {
+                    if unapplied == 0 {
+                        return Err(NonConvergence {
+                            since,
+                            diff_time,
+                            local_hash: self.merkle_clock.merkle().root_hash(),
+                            remote_hash: remote.root_hash(),
+                            iterations: iteration,
+                        }
+                        .into());
                     }
+                    log::warn!(
+                        "{} messages since {} can't be applied, not syncing their bucket again",
+                        unapplied,
+                        diff_time
+                    );
+                    let merkle = self.merkle_clock.merkle();
+                    let hashes = (merkle.bucket_hash(diff_time), remote.bucket_hash(diff_time));
+                    self.unresolved.insert(diff_time, hashes);
+                    stats.diff_time = self.diff_past_unresolved(&remote)?;
+                }
+                if let Some(diff_time) = stats.diff_time {
+                    let next = self.sync_round(group_id, vec![], Some(diff_time), iteration + 1)?;
+                    stats.sent += next.sent;
+                    stats.received += next.received;
+                    stats.iterations += next.iterations;
                 }
-                self.sync(group_id, vec![], Some(diff_time))
-            } else {
-                Ok(None)
             }
-        } else {
-            Ok(None)
         }
+
+        Ok(stats)
+    }
+
+    /// Ask the server how far we are out of sync without uploading or
+    /// applying anything: returns the time since which the local and the
+    /// server merkle differ, if they do.
+    pub fn diff_with_server(&self, group_id: &str) -> Result<Option<i64>, SyncerError> {
+        let res = self
+            .transport
+            .sync(
+                &SyncRequest {
+                    group_id: group_id.to_string(),
+                    client_id: self.node_name.clone(),
+                    messages: vec![],
+                    merkle: self.merkle_clock.merkle().clone(),
+                    base: MERKLE_BASE,
+                },
+                &self.headers,
+            )
+            .map_err(SyncerError::Transport)?;
+        res.check_base()?;
+
+        Ok(self.merkle_clock.merkle().diff(&res.merkle)?)
     }
 
     pub fn send_messages(
         &mut self,
         group_id: &str,
         mut messages: Vec<Message>,
-    ) -> anyhow::Result<()> {
+    ) -> Result<(), SyncerError> {
         self.storage
-            .apply_messages(&mut self.merkle_clock, &mut messages)?;
-        self.sync(group_id, messages, None)?;
+            .apply_messages(&mut self.merkle_clock, &mut messages)
+            .map_err(SyncerError::Storage)?;
+        self.outbound.extend(messages);
+        self.flush(group_id)
+    }
+
+    /// Send every queued outbound message to the server. The queue is only
+    /// drained once the sync succeeds, so a failed or disabled sync keeps
+    /// the messages around for the next flush.
+    pub fn flush(&mut self, group_id: &str) -> Result<(), SyncerError> {
+        if !self.sync_enabled {
+            return Ok(());
+        }
+
+        let messages = std::mem::take(&mut self.outbound);
+        if let Err(e) = self.sync(group_id, messages.clone(), None) {
+            self.outbound = messages;
+            return Err(e);
+        }
+
         Ok(())
     }
 
-    fn receive_messages(&mut self, mut messages: Vec<Message>) -> anyhow::Result<()> {
-        for msg in &messages {
-            match Timestamp::parse(&msg.timestamp) {
-                Ok(timestamp) => {
-                    self.merkle_clock.timer_mut().recv(&timestamp)?;
-                }
-                _ => {
-                    log::warn!("Parse timestamp failed: {:?}", msg);
+    pub fn outbound_messages(&self) -> &[Message] {
+        &self.outbound
+    }
+
+    pub fn applied_messages(&self) -> &HashSet<String> {
+        self.storage.applied_messages()
+    }
+
+    /// Whether a message is applied locally and known to the server, so it
+    /// doesn't need to be uploaded again.
+    pub fn is_synced(&self, timestamp: &str) -> bool {
+        self.acknowledged.contains(timestamp) && self.applied_messages().contains(timestamp)
+    }
+
+    /// Apply messages from the server, returning how many of them the store
+    /// skipped. Invalid messages, e.g. stored by a server not checking them,
+    /// are skipped as well instead of failing the whole batch.
+    fn receive_messages(&mut self, messages: Vec<Message>) -> Result<usize, SyncerError> {
+        let (mut messages, invalid): (Vec<Message>, Vec<Message>) =
+            messages.into_iter().partition(|msg| match msg.validate() {
+                Ok(()) => true,
+                Err(e) => {
+                    log::warn!("Skipping invalid message {:?}: {}", msg, e);
+                    false
                 }
+            });
+
+        for msg in &messages {
+            if let Ok(timestamp) = Timestamp::parse(&msg.timestamp) {
+                self.merkle_clock.timer_mut().recv(&timestamp)?;
             }
         }
 
         self.storage
-            .apply_messages(&mut self.merkle_clock, &mut messages)?;
-        Ok(())
+            .apply_messages(&mut self.merkle_clock, &mut messages)
+            .map_err(SyncerError::Storage)?;
+        let unapplied = messages
+            .iter()
+            .filter(|m| !self.applied_messages().contains(&m.timestamp))
+            .count();
+        self.acknowledged
+            .extend(messages.into_iter().map(|m| m.timestamp));
+        Ok(unapplied + invalid.len())
+    }
+
+    /// The time since which the local and the remote trie differ, skipping
+    /// the unresolved buckets neither side changed since. A changed one is
+    /// dropped from them and synced again, like one that no longer differs.
+    fn diff_past_unresolved(
+        &mut self,
+        remote: &MerkleTrie<MERKLE_BASE>,
+    ) -> Result<Option<i64>, SyncerError> {
+        let merkle = self.merkle_clock.merkle();
+        self.unresolved.retain(|time, hashes| {
+            *hashes == (merkle.bucket_hash(*time), remote.bucket_hash(*time))
+        });
+
+        let mut diff_time = merkle.diff(remote)?;
+        while let Some(time) = diff_time.filter(|time| self.unresolved.contains_key(time)) {
+            let next = time.saturating_add(merkle.bucket_ms() as i64);
+            diff_time = merkle.diff_since(remote, next)?;
+        }
+
+        Ok(diff_time)
+    }
+
+    pub fn sync_enabled(&self) -> bool {
+        self.sync_enabled
+    }
+
+    /// Pause or resume syncing with the server. While disabled, changes
+    /// are still applied to the local storage but never sent.
+    pub fn set_sync_enabled(&mut self, enabled: bool) {
+        self.sync_enabled = enabled;
+    }
+
+    /// The start times of the buckets a sync skips, in ascending order.
+    /// A sync getting messages it can't apply, e.g. of a dataset no handler
+    /// claims, records their bucket instead of failing to converge on it.
+    /// Once the bucket changes on either side it is synced again, so only
+    /// the messages already seen are skipped.
+    pub fn unresolved_buckets(&self) -> Vec<i64> {
+        let mut times: Vec<i64> = self.unresolved.keys().copied().collect();
+        times.sort();
+        times
+    }
+
+    /// Sync the unresolved buckets again, e.g. once a handler claims the
+    /// dataset of their messages
+    pub fn clear_unresolved(&mut self) {
+        self.unresolved.clear();
     }
 
     pub fn node_name(&self) -> &str {
         &self.node_name
     }
 
+    /// The logical time of the last event sent or received
+    pub fn current_timestamp(&self) -> Timestamp {
+        self.merkle_clock.timer().clone()
+    }
+
+    /// The root hash of the local merkle trie, equal to the server's once
+    /// in sync
+    pub fn merkle_root_hash(&self) -> u64 {
+        self.merkle_clock.merkle().root_hash()
+    }
+
+    /// Rebuild the clock from the applied messages of the storage, e.g. one
+    /// persisted across a restart: the trie is replaced by the one of all
+    /// applied timestamps, so a store in sync with the server is again, and
+    /// the timer moves up to the latest of them, so new events come after.
+    pub fn restore_clock_from_store(&mut self) -> Result<(), SyncerError> {
+        let timestamps = self
+            .storage
+            .applied_messages()
+            .iter()
+            .map(|timestamp| Timestamp::parse(timestamp))
+            .collect::<Result<Vec<_>, _>>()?;
+        // Cleared in place, so the trie keeps its bucket width and key
+        // length cap
+        let trie = self.merkle_clock.merkle_mut();
+        trie.clear();
+        for timestamp in &timestamps {
+            trie.insert(timestamp);
+        }
+        let latest = timestamps.into_iter().max();
+
+        let timer = self.merkle_clock.timer_mut();
+        if let Some(latest) = latest {
+            if (latest.millis(), latest.counter()) > (timer.millis(), timer.counter()) {
+                *timer =
+                    Timestamp::new(latest.millis(), latest.counter(), timer.node().to_string())
+                        .with_counter_width(timer.counter_width())
+                        .with_duplicate_node_policy(timer.duplicate_node_policy());
+            }
+        }
+
+        Ok(())
+    }
+
     pub fn storage(&self) -> &dyn Store<Item, MERKLE_BASE> {
         self.storage.as_ref()
     }
@@ -275,3 +554,896 @@ impl<Item: MessageHandler + DeserializeOwned + Serialize + Debug, const MERKLE_B
         );
     }
 }
+
+/// Configures a [`Syncer`], every setting left out gets its default.
+pub struct SyncerBuilder<
+    Item: 'static + MessageHandler + DeserializeOwned + Serialize + Debug,
+    const MERKLE_BASE: usize = MERKLE_BASE_CONST,
+> {
+    node_name: Option<String>,
+    endpoint: Option<String>,
+    transport: Option<Box<dyn Transport<MERKLE_BASE>>>,
+    sync_enabled: bool,
+    storage: Option<Box<dyn Store<Item, MERKLE_BASE>>>,
+}
+
+impl<Item: MessageHandler + DeserializeOwned + Serialize + Debug, const MERKLE_BASE: usize> Default
+    for SyncerBuilder<Item, MERKLE_BASE>
+{
+    fn default() -> Self {
+        Self {
+            node_name: None,
+            endpoint: None,
+            transport: None,
+            sync_enabled: true,
+            storage: None,
+        }
+    }
+}
+
+impl<Item: MessageHandler + DeserializeOwned + Serialize + Debug, const MERKLE_BASE: usize>
+    SyncerBuilder<Item, MERKLE_BASE>
+{
+    /// The node id of the clock. Defaults to the `CLIENT` env var, or a
+    /// generated id when unset.
+    pub fn node_name(mut self, node_name: &str) -> Self {
+        self.node_name = Some(node_name.to_string());
+        self
+    }
+
+    /// The server the default transport syncs with, ignored when a
+    /// transport is set. Defaults to [`DEFAULT_ENDPOINT`].
+    pub fn endpoint(mut self, endpoint: &str) -> Self {
+        self.endpoint = Some(endpoint.to_string());
+        self
+    }
+
+    pub fn transport(mut self, transport: Box<dyn Transport<MERKLE_BASE>>) -> Self {
+        self.transport = Some(transport);
+        self
+    }
+
+    /// Whether the syncer starts online, see [`Syncer::set_sync_enabled`]
+    pub fn sync_enabled(mut self, enabled: bool) -> Self {
+        self.sync_enabled = enabled;
+        self
+    }
+
+    /// Where the items are kept. Defaults to a [`MemStorage`].
+    pub fn storage(mut self, storage: Box<dyn Store<Item, MERKLE_BASE>>) -> Self {
+        self.storage = Some(storage);
+        self
+    }
+
+    pub fn build(self) -> Syncer<Item, MERKLE_BASE> {
+        let node_name = self.node_name.or_else(|| env::var("CLIENT").ok());
+        let merkle_clock = match node_name {
+            Some(node_name) => MerkleClock::new(
+                Timestamp::new(0, 0, node_name),
+                MerkleTrie::<MERKLE_BASE>::new(),
+            ),
+            None => MerkleClock::local(),
+        };
+        let transport = self.transport.unwrap_or_else(|| {
+            Box::new(DefaultTransport::new(
+                self.endpoint.as_deref().unwrap_or(DEFAULT_ENDPOINT),
+            ))
+        });
+
+        Syncer {
+            node_name: merkle_clock.timer().node().to_string(),
+            merkle_clock,
+            sync_enabled: self.sync_enabled,
+            storage: self.storage.unwrap_or_else(|| Box::new(MemStorage::new())),
+            transport,
+            outbound: vec![],
+            acknowledged: HashSet::new(),
+            headers: vec![],
+            unresolved: HashMap::new(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::{HashMap, HashSet};
+    use std::sync::{Arc, Mutex};
+
+    use anyhow::bail;
+
+    use merkle_trie_clock::clock::MerkleClock;
+    use merkle_trie_clock::error::{BaseMismatch, NonConvergence};
+    use merkle_trie_clock::merkle::MerkleTrie;
+    use merkle_trie_clock::models::{Message, RowParam, ValueType};
+    use merkle_trie_clock::sync::{HandshakeRequest, HandshakeResponse, SyncRequest, SyncResponse};
+    use merkle_trie_clock::timestamp::Timestamp;
+
+    use crate::error::SyncerError;
+    use crate::mem_storage::MemStorage;
+    use crate::storage::Store;
+    use crate::syncer::{SyncStats, Syncer};
+    use crate::test_utils::{MockTransport, Note, NOTE_TABLE};
+    use crate::transport::{Headers, Transport};
+
+    #[test]
+    fn sync_disabled_test() {
+        let transport = MockTransport::default();
+        let mut s: Syncer<Note> = Syncer::with_transport(Box::new(transport.clone()));
+        assert!(s.sync_enabled());
+
+        s.set_sync_enabled(false);
+        assert!(!s.sync_enabled());
+
+        let id = s
+            .insert(
+                "group",
+                NOTE_TABLE,
+                vec![RowParam {
+                    id: None,
+                    column: "content".to_string(),
+                    value_type: ValueType::String,
+                    value: "offline".to_string(),
+                }],
+            )
+            .unwrap();
+
+        assert_eq!(s.storage().items().get(&id).unwrap().content, "offline");
+        assert!(transport.requests.lock().unwrap().is_empty());
+
+        s.set_sync_enabled(true);
+        s.flush("group").unwrap();
+        assert_eq!(transport.requests.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn hard_delete_sync_test() {
+        let transport = MockTransport::default();
+        let mut s: Syncer<Note> = Syncer::with_transport(Box::new(transport.clone()));
+        let t = |i: i64| Timestamp::new(1712898800000 + i, 0, "server0000000001".to_string());
+        let messages = vec![
+            Message::change(
+                t(0).to_string(),
+                NOTE_TABLE,
+                "1",
+                "content",
+                ValueType::String,
+                "x",
+            ),
+            Message::tombstone(t(1).to_string(), NOTE_TABLE, "1"),
+        ];
+        let server = MerkleTrie::from_timestamps(&[t(0), t(1)]);
+        *transport.merkle.lock().unwrap() = Some(server.clone());
+        *transport.messages.lock().unwrap() = messages.clone();
+        s.sync("group", vec![], None).unwrap();
+        assert!(s.storage().items().contains_key("1"));
+
+        s.hard_delete("1").unwrap();
+        assert_eq!(s.merkle_clock.merkle().diff(&server), Ok(None));
+
+        // The server sending the messages again doesn't bring the row back
+        *transport.messages.lock().unwrap() = messages;
+        let stats = s.sync("group", vec![], None).unwrap();
+        assert_eq!(stats.diff_time, None);
+        assert!(!s.storage().items().contains_key("1"));
+    }
+
+    #[test]
+    fn flush_test() {
+        let transport = MockTransport::default();
+        let mut s: Syncer<Note> = Syncer::with_transport(Box::new(transport.clone()));
+        s.set_sync_enabled(false);
+
+        for content in ["first", "second"] {
+            s.insert(
+                "group",
+                NOTE_TABLE,
+                vec![RowParam {
+                    id: None,
+                    column: "content".to_string(),
+                    value_type: ValueType::String,
+                    value: content.to_string(),
+                }],
+            )
+            .unwrap();
+        }
+        assert_eq!(s.outbound_messages().len(), 2);
+        assert!(transport.requests.lock().unwrap().is_empty());
+
+        s.set_sync_enabled(true);
+        s.flush("group").unwrap();
+        assert!(s.outbound_messages().is_empty());
+
+        let requests = transport.requests.lock().unwrap();
+        assert_eq!(requests.len(), 1);
+        let sent = requests[0]
+            .messages
+            .iter()
+            .map(|m| m.value.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(sent, vec!["first", "second"]);
+    }
+
+    #[test]
+    fn current_timestamp_test() {
+        let mut s: Syncer<Note> = Syncer::new();
+        s.set_sync_enabled(false);
+        let before = s.current_timestamp();
+        assert_eq!(before.node(), s.node_name());
+        assert_eq!(s.merkle_root_hash(), 0);
+
+        s.insert(
+            "group",
+            NOTE_TABLE,
+            vec![RowParam {
+                id: None,
+                column: "content".to_string(),
+                value_type: ValueType::String,
+                value: "x".to_string(),
+            }],
+        )
+        .unwrap();
+        let after = s.current_timestamp();
+        assert!(after > before);
+        assert_eq!(s.applied_messages().iter().next(), Some(&after.to_string()));
+        assert_eq!(
+            s.merkle_root_hash(),
+            MerkleTrie::<3>::from_timestamps(&[after]).root_hash()
+        );
+    }
+
+    #[test]
+    fn restore_clock_from_store_test() {
+        let mut s: Syncer<Note> = Syncer::new();
+        s.set_sync_enabled(false);
+        for content in ["one", "two", "three"] {
+            s.insert(
+                "group",
+                NOTE_TABLE,
+                vec![RowParam {
+                    id: None,
+                    column: "content".to_string(),
+                    value_type: ValueType::String,
+                    value: content.to_string(),
+                }],
+            )
+            .unwrap();
+        }
+
+        // A restarted client with the persisted store but a fresh clock
+        let mut storage = MemStorage::<Note, 3>::new();
+        storage.restore(&s.storage().snapshot().unwrap()).unwrap();
+        let mut restarted: Syncer<Note> = Syncer::with_storage(Box::new(storage));
+        assert_eq!(restarted.merkle_root_hash(), 0);
+
+        restarted.restore_clock_from_store().unwrap();
+        assert_eq!(restarted.merkle_root_hash(), s.merkle_root_hash());
+        let (before, after) = (s.current_timestamp(), restarted.current_timestamp());
+        assert_eq!(
+            (after.millis(), after.counter()),
+            (before.millis(), before.counter())
+        );
+        assert_eq!(after.node(), restarted.node_name());
+
+        // The trie keeps its configuration
+        let configured = MerkleTrie::new()
+            .with_bucket_ms(1000)
+            .with_max_key_len(20, 1712898800000);
+        *restarted.merkle_clock.merkle_mut() = configured.clone();
+        restarted.restore_clock_from_store().unwrap();
+        let trie = restarted.merkle_clock.merkle();
+        assert_eq!(trie.bucket_ms(), 1000);
+        assert_eq!(trie.max_key_len(), configured.max_key_len());
+        assert_eq!(trie.root_hash(), s.merkle_root_hash());
+    }
+
+    #[test]
+    fn insert_many_test() {
+        let transport = MockTransport::default();
+        let mut s: Syncer<Note> = Syncer::with_transport(Box::new(transport.clone()));
+
+        let rows = ["one", "two", "three"]
+            .into_iter()
+            .map(|content| {
+                vec![RowParam {
+                    id: None,
+                    column: "content".to_string(),
+                    value_type: ValueType::String,
+                    value: content.to_string(),
+                }]
+            })
+            .collect();
+        let ids = s.insert_many("group", NOTE_TABLE, rows).unwrap();
+
+        assert_eq!(ids.iter().collect::<HashSet<_>>().len(), 3);
+        assert_eq!(s.storage().items().get(&ids[2]).unwrap().content, "three");
+        let requests = transport.requests.lock().unwrap();
+        assert_eq!(requests.len(), 1);
+        assert_eq!(requests[0].messages.len(), 3);
+    }
+
+    #[test]
+    fn update_changed_test() {
+        let mut s: Syncer<Note> = Syncer::builder().sync_enabled(false).build();
+        let param = |id: Option<&str>, column: &str, value: &str| RowParam {
+            id: id.map(str::to_string),
+            column: column.to_string(),
+            value_type: ValueType::String,
+            value: value.to_string(),
+        };
+
+        let id = s
+            .insert(
+                "group",
+                NOTE_TABLE,
+                vec![param(None, "content", "same"), param(None, "title", "old")],
+            )
+            .unwrap();
+        assert_eq!(s.outbound_messages().len(), 2);
+
+        s.update_changed(
+            "group",
+            NOTE_TABLE,
+            vec![
+                param(Some(&id), "content", "same"),
+                param(Some(&id), "title", "new"),
+            ],
+        )
+        .unwrap();
+
+        let outbound = s.outbound_messages();
+        assert_eq!(outbound.len(), 3);
+        assert_eq!(outbound[2].column, "title");
+        let note = s.storage().items().get(&id).unwrap();
+        assert_eq!(
+            (note.content.as_str(), note.title.as_str()),
+            ("same", "new")
+        );
+    }
+
+    #[test]
+    fn skip_synced_messages_test() {
+        let transport = MockTransport::default();
+        let mut s: Syncer<Note> = Syncer::with_transport(Box::new(transport.clone()));
+
+        s.insert(
+            "group",
+            NOTE_TABLE,
+            vec![RowParam {
+                id: None,
+                column: "content".to_string(),
+                value_type: ValueType::String,
+                value: "once".to_string(),
+            }],
+        )
+        .unwrap();
+        let sent = transport.requests.lock().unwrap()[0].messages.clone();
+        assert_eq!(sent.len(), 1);
+        assert!(s.applied_messages().contains(&sent[0].timestamp));
+        assert!(s.is_synced(&sent[0].timestamp));
+
+        // Syncing the same message again doesn't upload it
+        s.sync("group", sent, None).unwrap();
+        assert_eq!(transport.requests.lock().unwrap().len(), 1);
+        assert_eq!(transport.handshakes.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn auth_header_test() {
+        let transport = MockTransport::default();
+        let mut s: Syncer<Note> =
+            Syncer::with_transport(Box::new(transport.clone())).with_auth("secret");
+        s.set_header("X-Group", "group");
+
+        s.sync("group", vec![], None).unwrap();
+
+        let headers = transport.headers.lock().unwrap();
+        assert_eq!(headers.len(), 1);
+        assert_eq!(
+            headers[0],
+            vec![
+                ("Authorization".to_string(), "Bearer secret".to_string()),
+                ("X-Group".to_string(), "group".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn diff_with_server_test() {
+        let transport = MockTransport::default();
+        let s: Syncer<Note> = Syncer::with_transport(Box::new(transport.clone()));
+
+        // In sync with an empty server
+        assert_eq!(s.diff_with_server("group").unwrap(), None);
+
+        let mut remote = MerkleTrie::<3>::new();
+        remote.insert(&Timestamp::new(1712898800000, 0, "remote".to_string()));
+        *transport.merkle.lock().unwrap() = Some(remote);
+
+        assert_eq!(s.diff_with_server("group").unwrap(), Some(0));
+        // Nothing got uploaded or applied
+        let requests = transport.requests.lock().unwrap();
+        assert!(requests.iter().all(|r| r.messages.is_empty()));
+        assert!(s.applied_messages().is_empty());
+    }
+
+    #[test]
+    fn handshake_test() {
+        let transport = MockTransport::default();
+        let mut s: Syncer<Note> = Syncer::with_transport(Box::new(transport.clone()));
+
+        // Equal root hashes: only the hash is sent
+        s.sync("group", vec![], None).unwrap();
+        assert_eq!(transport.handshakes.lock().unwrap().len(), 1);
+        assert!(transport.requests.lock().unwrap().is_empty());
+
+        // The server has more: the handshake falls back to a full sync
+        let mut remote = MerkleTrie::<3>::new();
+        remote.insert(&Timestamp::new(1712898800000, 0, "remote".to_string()));
+        *transport.merkle.lock().unwrap() = Some(remote);
+        s.sync("group", vec![], None).unwrap();
+        let handshakes = transport.handshakes.lock().unwrap();
+        assert_eq!(handshakes.len(), 2);
+        assert_eq!(handshakes[1].root_hash, 0);
+        assert_eq!(transport.requests.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn handshake_base_test() {
+        let transport = MockTransport::<16>::default();
+        let mut s: Syncer<Note, 16> = Syncer::with_transport(Box::new(transport.clone()));
+        s.sync("group", vec![], None).unwrap();
+        assert_eq!(transport.handshakes.lock().unwrap()[0].base, 16);
+
+        // A server of another base fails the sync instead of diffing
+        // incomparable tries
+        let mut s: Syncer<Note, 10> = Syncer::with_transport(Box::new(BaseTransport(3)));
+        let err = s.sync("group", vec![], None).unwrap_err();
+        assert!(matches!(
+            err,
+            SyncerError::BaseMismatch(BaseMismatch {
+                local: 10,
+                remote: 3
+            })
+        ));
+    }
+
+    #[test]
+    fn sync_non_convergence_test() {
+        let transport = MockTransport::default();
+        let sent = Timestamp::new(1712898800000, 0, "server0000000001".to_string());
+        let missing = Timestamp::new(1712898800001, 0, "server0000000001".to_string());
+        // The server claims a message it never sends
+        let server = MerkleTrie::from_timestamps(&[sent.clone(), missing]);
+        *transport.merkle.lock().unwrap() = Some(server.clone());
+        *transport.messages.lock().unwrap() = vec![Message::change(
+            sent.to_string(),
+            NOTE_TABLE,
+            "1",
+            "content",
+            ValueType::String,
+            "x",
+        )];
+        let mut s: Syncer<Note> = Syncer::with_transport(Box::new(transport.clone()));
+
+        let err = s.sync("group", vec![], None).unwrap_err();
+        let diff_time = s.merkle_clock.merkle().diff(&server).unwrap().unwrap();
+        assert!(err.is_protocol());
+        assert!(matches!(
+            err,
+            SyncerError::NonConvergence(e) if e == NonConvergence {
+                since: diff_time,
+                diff_time,
+                local_hash: s.merkle_clock.merkle().root_hash(),
+                remote_hash: server.root_hash(),
+                iterations: 2,
+            }
+        ));
+        assert_eq!(transport.requests.lock().unwrap().len(), 2);
+    }
+
+    /// A server that keeps sending all its messages, one of them of a
+    /// dataset no handler claims
+    #[derive(Clone, Default)]
+    struct UnknownDatasetTransport {
+        messages: Arc<Mutex<Vec<Message>>>,
+        merkle: Arc<Mutex<MerkleTrie<3>>>,
+    }
+
+    impl UnknownDatasetTransport {
+        fn push(&self, message: Message) {
+            let timestamp = Timestamp::parse(&message.timestamp).unwrap();
+            self.merkle.lock().unwrap().insert(&timestamp);
+            self.messages.lock().unwrap().push(message);
+        }
+    }
+
+    impl Transport<3> for UnknownDatasetTransport {
+        fn sync(
+            &self,
+            _request: &SyncRequest<3>,
+            _headers: &Headers,
+        ) -> anyhow::Result<SyncResponse<3>> {
+            Ok(SyncResponse {
+                messages: self.messages.lock().unwrap().clone(),
+                merkle: self.merkle.lock().unwrap().clone(),
+                base: 3,
+            })
+        }
+
+        fn handshake(
+            &self,
+            request: &HandshakeRequest,
+            _headers: &Headers,
+        ) -> anyhow::Result<HandshakeResponse> {
+            Ok(HandshakeResponse {
+                in_sync: request.root_hash == self.merkle.lock().unwrap().root_hash(),
+                base: 3,
+            })
+        }
+    }
+
+    fn change(timestamp: &Timestamp, dataset: &str, row: &str) -> Message {
+        Message::change(
+            timestamp.to_string(),
+            dataset,
+            row,
+            "content",
+            ValueType::String,
+            "x",
+        )
+    }
+
+    #[test]
+    fn sync_unresolved_bucket_test() {
+        let shared = Timestamp::new(1712898800000, 0, "server0000000001".to_string());
+        let unknown = Timestamp::new(1712898800001, 0, "server0000000001".to_string());
+        let transport = UnknownDatasetTransport::default();
+        transport.push(change(&shared, NOTE_TABLE, "1"));
+        transport.push(change(&unknown, "unknown", "1"));
+        let mut s: Syncer<Note> = Syncer::with_transport(Box::new(transport.clone()));
+        s.set_sync_enabled(false);
+        s.send_messages("group", vec![change(&shared, NOTE_TABLE, "1")])
+            .unwrap();
+        s.set_sync_enabled(true);
+
+        // The second round gets the same message it can't apply, and gives up
+        // on its bucket instead of failing
+        let stats = s.sync("group", vec![], None).unwrap();
+        assert_eq!(stats.iterations, 2);
+        assert_eq!(stats.diff_time, Some(unknown.millis()));
+        assert_eq!(s.unresolved_buckets(), vec![unknown.millis()]);
+
+        // Later syncs don't fetch it again
+        let stats = s.sync("group", vec![], None).unwrap();
+        assert_eq!(stats.iterations, 1);
+        assert_eq!(stats.diff_time, None);
+
+        // A message landing in an earlier bucket later on is still synced
+        let earlier = Timestamp::new(1712898799999, 0, "server0000000002".to_string());
+        transport.push(change(&earlier, NOTE_TABLE, "2"));
+        s.sync("group", vec![], None).unwrap();
+        assert!(s.storage().items().contains_key("2"));
+        assert_eq!(s.unresolved_buckets(), vec![unknown.millis()]);
+
+        // So is the unresolved bucket once it changes
+        let other = Timestamp::new(1712898800001, 0, "server0000000002".to_string());
+        transport.push(change(&other, "unknown", "2"));
+        let stats = s.sync("group", vec![], None).unwrap();
+        assert_eq!(stats.iterations, 2);
+        assert_eq!(s.unresolved_buckets(), vec![unknown.millis()]);
+
+        // And once it is cleared
+        s.clear_unresolved();
+        let stats = s.sync("group", vec![], None).unwrap();
+        assert_eq!(stats.iterations, 2);
+    }
+
+    #[test]
+    fn sync_invalid_message_test() {
+        let valid = Timestamp::new(1712898800000, 0, "server0000000001".to_string());
+        let invalid = Timestamp::new(1712898800001, 0, "server0000000001".to_string());
+        let transport = UnknownDatasetTransport::default();
+        transport.push(change(&valid, NOTE_TABLE, "1"));
+        let mut message = change(&invalid, NOTE_TABLE, "2");
+        message.column = String::new();
+        transport.push(message);
+        let mut s: Syncer<Note> = Syncer::with_transport(Box::new(transport));
+
+        // The valid message is applied, the bucket of the invalid one given up
+        let stats = s.sync("group", vec![], None).unwrap();
+        assert!(s.storage().items().contains_key("1"));
+        assert!(!s.storage().items().contains_key("2"));
+        assert_eq!(stats.diff_time, Some(invalid.millis()));
+        assert_eq!(s.unresolved_buckets(), vec![invalid.millis()]);
+
+        let stats = s.sync("group", vec![], None).unwrap();
+        assert_eq!(stats.iterations, 1);
+    }
+
+    #[test]
+    fn sync_base_mismatch_test() {
+        let mut s: Syncer<Note, 10> = Syncer::with_transport(Box::new(BaseTransport(3)));
+        // Pending messages skip the handshake, the sync response is checked
+        let err = s
+            .insert(
+                "group",
+                NOTE_TABLE,
+                vec![RowParam {
+                    id: None,
+                    column: "content".to_string(),
+                    value_type: ValueType::String,
+                    value: "x".to_string(),
+                }],
+            )
+            .unwrap_err();
+        assert!(matches!(
+            err,
+            SyncerError::BaseMismatch(BaseMismatch {
+                local: 10,
+                remote: 3
+            })
+        ));
+        assert!(matches!(
+            s.diff_with_server("group"),
+            Err(SyncerError::BaseMismatch(BaseMismatch { remote: 3, .. }))
+        ));
+    }
+
+    /// A server answering with its own base, and a trie of that base that
+    /// deserialized as one of ours
+    struct BaseTransport(usize);
+
+    impl Transport<10> for BaseTransport {
+        fn sync(
+            &self,
+            _request: &SyncRequest<10>,
+            _headers: &Headers,
+        ) -> anyhow::Result<SyncResponse<10>> {
+            Ok(SyncResponse {
+                messages: vec![],
+                merkle: MerkleTrie::new(),
+                base: self.0,
+            })
+        }
+
+        fn handshake(
+            &self,
+            _request: &HandshakeRequest,
+            _headers: &Headers,
+        ) -> anyhow::Result<HandshakeResponse> {
+            Ok(HandshakeResponse {
+                in_sync: false,
+                base: self.0,
+            })
+        }
+    }
+
+    /// A server that can't be reached
+    struct DownTransport;
+
+    impl Transport<3> for DownTransport {
+        fn sync(
+            &self,
+            _request: &SyncRequest<3>,
+            _headers: &Headers,
+        ) -> anyhow::Result<SyncResponse<3>> {
+            bail!("Connection refused")
+        }
+
+        fn handshake(
+            &self,
+            _request: &HandshakeRequest,
+            _headers: &Headers,
+        ) -> anyhow::Result<HandshakeResponse> {
+            bail!("Connection refused")
+        }
+    }
+
+    #[test]
+    fn syncer_error_test() {
+        let mut s: Syncer<Note> = Syncer::with_transport(Box::new(DownTransport));
+        let err = s
+            .insert(
+                "group",
+                NOTE_TABLE,
+                vec![RowParam {
+                    id: None,
+                    column: "content".to_string(),
+                    value_type: ValueType::String,
+                    value: "x".to_string(),
+                }],
+            )
+            .unwrap_err();
+        assert!(matches!(err, SyncerError::Transport(_)));
+        assert!(!err.is_protocol());
+        assert_eq!(err.to_string(), "Transport error: Connection refused");
+        // The message stays queued for the next flush
+        assert_eq!(s.outbound_messages().len(), 1);
+        assert!(matches!(
+            s.sync("group", vec![], None),
+            Err(SyncerError::Transport(_))
+        ));
+
+        // Invalid messages are rejected by the store
+        let invalid = Message::change(
+            "not a timestamp".to_string(),
+            NOTE_TABLE,
+            "1",
+            "content",
+            ValueType::String,
+            "x",
+        );
+        assert!(matches!(
+            s.send_messages("group", vec![invalid]),
+            Err(SyncerError::Storage(_))
+        ));
+
+        // A clock too far ahead of the physical time can't tick
+        s.merkle_clock = MerkleClock::new(
+            Timestamp::new(i64::MAX / 2, 0, "client0000000001".to_string()),
+            MerkleTrie::new(),
+        );
+        assert!(matches!(
+            s.delete("group", NOTE_TABLE, "1"),
+            Err(SyncerError::Timestamp(_))
+        ));
+    }
+
+    #[test]
+    fn sync_stats_test() {
+        let transport = MockTransport::default();
+        let mut s: Syncer<Note> = Syncer::with_transport(Box::new(transport.clone()));
+        s.set_sync_enabled(false);
+        for content in ["first", "second"] {
+            s.insert(
+                "group",
+                NOTE_TABLE,
+                vec![RowParam {
+                    id: None,
+                    column: "content".to_string(),
+                    value_type: ValueType::String,
+                    value: content.to_string(),
+                }],
+            )
+            .unwrap();
+        }
+        s.set_sync_enabled(true);
+        let outbound = s.outbound_messages().to_vec();
+
+        // The server holds both messages plus one of another client
+        let remote = Timestamp::new(1712898800000, 0, "remote".to_string());
+        let mut server = MerkleTrie::<3>::new();
+        server.insert(&remote);
+        for message in &outbound {
+            server.insert(&Timestamp::parse(&message.timestamp).unwrap());
+        }
+        *transport.merkle.lock().unwrap() = Some(server);
+        *transport.messages.lock().unwrap() = vec![Message::change(
+            remote.to_string(),
+            NOTE_TABLE,
+            "remote",
+            "content",
+            ValueType::String,
+            "third",
+        )];
+
+        let stats = s.sync("group", outbound, None).unwrap();
+        assert_eq!(
+            stats,
+            SyncStats {
+                sent: 2,
+                received: 1,
+                diff_time: None,
+                iterations: 1,
+            }
+        );
+
+        // Nothing left to exchange
+        assert_eq!(s.sync("group", vec![], None).unwrap(), SyncStats::default());
+    }
+
+    #[test]
+    fn builder_test() {
+        let transport = MockTransport::default();
+        let mut storage = MemStorage::<Note, 3>::new();
+        let mut clock = MerkleClock::<3>::local();
+        let t = Timestamp::new(1712898800000, 0, "remote".to_string()).to_string();
+        storage
+            .apply_messages(
+                &mut clock,
+                &mut vec![Message::change(
+                    &t,
+                    NOTE_TABLE,
+                    "1",
+                    "content",
+                    ValueType::String,
+                    "stored",
+                )],
+            )
+            .unwrap();
+
+        let builder = Syncer::<Note>::builder()
+            .node_name("node000000000001")
+            .endpoint("http://localhost:9000")
+            .sync_enabled(false);
+        assert_eq!(builder.endpoint.as_deref(), Some("http://localhost:9000"));
+        let mut s = builder
+            .transport(Box::new(transport.clone()))
+            .storage(Box::new(storage))
+            .build();
+
+        assert_eq!(s.node_name(), "node000000000001");
+        assert!(!s.sync_enabled());
+        assert_eq!(s.storage().items()["1"].content, "stored");
+
+        s.set_sync_enabled(true);
+        s.sync("group", vec![], None).unwrap();
+        assert_eq!(
+            transport.handshakes.lock().unwrap()[0].client_id,
+            "node000000000001"
+        );
+    }
+
+    /// A store recording the timestamps of every batch it applies
+    #[derive(Default)]
+    struct RecordingStore {
+        inner: MemStorage<Note, 3>,
+        batches: Arc<Mutex<Vec<Vec<String>>>>,
+    }
+
+    impl Store<Note, 3> for RecordingStore {
+        fn apply_messages(
+            &mut self,
+            clock: &mut MerkleClock<3>,
+            messages: &mut Vec<Message>,
+        ) -> anyhow::Result<Vec<String>> {
+            self.batches
+                .lock()
+                .unwrap()
+                .push(messages.iter().map(|m| m.timestamp.clone()).collect());
+            self.inner.apply_messages(clock, messages)
+        }
+
+        fn items(&self) -> &HashMap<String, Note> {
+            self.inner.items()
+        }
+
+        fn applied_messages(&self) -> &HashSet<String> {
+            self.inner.applied_messages()
+        }
+
+        fn snapshot(&self) -> anyhow::Result<Vec<u8>> {
+            self.inner.snapshot()
+        }
+
+        fn restore(&mut self, bytes: &[u8]) -> anyhow::Result<()> {
+            self.inner.restore(bytes)
+        }
+    }
+
+    #[test]
+    fn with_storage_test() {
+        let store = RecordingStore::default();
+        let batches = store.batches.clone();
+        let mut s: Syncer<Note> = Syncer::with_storage(Box::new(store));
+        s.set_sync_enabled(false);
+
+        let id = s
+            .insert(
+                "group",
+                NOTE_TABLE,
+                vec![RowParam {
+                    id: None,
+                    column: "content".to_string(),
+                    value_type: ValueType::String,
+                    value: "recorded".to_string(),
+                }],
+            )
+            .unwrap();
+
+        let batches = batches.lock().unwrap();
+        assert_eq!(batches.len(), 1);
+        assert_eq!(batches[0], vec![s.outbound_messages()[0].timestamp.clone()]);
+        assert_eq!(s.storage().items()[&id].content, "recorded");
+    }
+}
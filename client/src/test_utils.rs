@@ -0,0 +1,98 @@
+use std::sync::{Arc, Mutex};
+
+use anyhow::bail;
+use serde::{Deserialize, Serialize};
+
+use merkle_trie_clock::merkle::MerkleTrie;
+use merkle_trie_clock::models::Message;
+use merkle_trie_clock::sync::{HandshakeRequest, HandshakeResponse, SyncRequest, SyncResponse};
+
+use crate::storage::MessageHandler;
+use crate::transport::{Headers, Transport};
+
+pub const NOTE_TABLE: &str = "notes";
+
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Note {
+    pub id: String,
+    pub content: String,
+    pub title: String,
+    pub tombstone: i8,
+}
+
+impl MessageHandler for Note {
+    fn from_message(message: &Message) -> Self {
+        Note {
+            id: message.row.clone(),
+            ..Default::default()
+        }
+    }
+
+    fn handle_message(&mut self, message: &Message) -> anyhow::Result<()> {
+        match message.column.as_str() {
+            "content" => self.content.clone_from(&message.value),
+            "title" => self.title.clone_from(&message.value),
+            "tombstone" => self.tombstone = message.value.parse::<i8>()?,
+            _ => bail!("Unknown column: {}", message.column),
+        }
+        Ok(())
+    }
+
+    fn table_name() -> String {
+        String::from(NOTE_TABLE)
+    }
+}
+
+pub type SentHeaders = Vec<(String, String)>;
+
+/// A transport that records every request and answers as if the server
+/// was already in sync with the client, unless a server merkle is set.
+#[derive(Clone, Default)]
+pub struct MockTransport<const BASE: usize = 3> {
+    pub requests: Arc<Mutex<Vec<SyncRequest<BASE>>>>,
+    pub handshakes: Arc<Mutex<Vec<HandshakeRequest>>>,
+    pub headers: Arc<Mutex<Vec<SentHeaders>>>,
+    pub merkle: Arc<Mutex<Option<MerkleTrie<BASE>>>>,
+    /// Sent along with the next sync response
+    pub messages: Arc<Mutex<Vec<Message>>>,
+}
+
+impl<const BASE: usize> Transport<BASE> for MockTransport<BASE> {
+    fn sync(
+        &self,
+        request: &SyncRequest<BASE>,
+        headers: &Headers,
+    ) -> anyhow::Result<SyncResponse<BASE>> {
+        self.requests.lock().unwrap().push(request.clone());
+        self.headers.lock().unwrap().push(headers.to_vec());
+        Ok(SyncResponse {
+            messages: std::mem::take(&mut *self.messages.lock().unwrap()),
+            merkle: self
+                .merkle
+                .lock()
+                .unwrap()
+                .clone()
+                .unwrap_or_else(|| request.merkle.clone()),
+            base: BASE,
+        })
+    }
+
+    fn handshake(
+        &self,
+        request: &HandshakeRequest,
+        headers: &Headers,
+    ) -> anyhow::Result<HandshakeResponse> {
+        self.handshakes.lock().unwrap().push(request.clone());
+        self.headers.lock().unwrap().push(headers.to_vec());
+        let in_sync = self
+            .merkle
+            .lock()
+            .unwrap()
+            .as_ref()
+            .is_none_or(|merkle| merkle.root_hash() == request.root_hash);
+        Ok(HandshakeResponse {
+            in_sync,
+            base: BASE,
+        })
+    }
+}
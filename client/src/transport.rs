@@ -0,0 +1,227 @@
+#[cfg(not(target_arch = "wasm32"))]
+use reqwest::StatusCode;
+
+#[cfg(not(target_arch = "wasm32"))]
+use merkle_trie_clock::codec::{Codec, Format};
+use merkle_trie_clock::error::BaseMismatch;
+use merkle_trie_clock::sync::{HandshakeRequest, HandshakeResponse, SyncRequest, SyncResponse};
+
+pub const DEFAULT_ENDPOINT: &str = "http://localhost:8006";
+
+/// Extra `(name, value)` headers sent along with every sync request
+pub type Headers = [(String, String)];
+
+/// The way a sync request reaches the server. `Send` like [`crate::storage::Store`].
+pub trait Transport<const MERKLE_BASE: usize>: Send {
+    fn sync(
+        &self,
+        request: &SyncRequest<MERKLE_BASE>,
+        headers: &Headers,
+    ) -> anyhow::Result<SyncResponse<MERKLE_BASE>>;
+
+    /// Compare root hashes with the server. Defaults to never in sync, i.e.
+    /// always falling back to a full sync.
+    fn handshake(
+        &self,
+        _request: &HandshakeRequest,
+        _headers: &Headers,
+    ) -> anyhow::Result<HandshakeResponse> {
+        Ok(HandshakeResponse {
+            in_sync: false,
+            base: MERKLE_BASE,
+        })
+    }
+}
+
+/// A [`BaseMismatch`] as reported by the server, from the client's side
+pub(crate) fn from_server_mismatch(mismatch: BaseMismatch) -> BaseMismatch {
+    BaseMismatch {
+        local: mismatch.remote,
+        remote: mismatch.local,
+    }
+}
+
+/// The transport a [`crate::syncer::Syncer`] uses unless told otherwise
+#[cfg(not(target_arch = "wasm32"))]
+pub type DefaultTransport = HttpTransport;
+
+#[cfg(all(target_arch = "wasm32", feature = "wasm"))]
+pub type DefaultTransport = crate::wasm_transport::WasmTransport;
+
+#[cfg(all(target_arch = "wasm32", not(feature = "wasm")))]
+compile_error!("the `wasm` feature is needed to build the client for wasm32");
+
+/// Sends sync requests to the `/sync` endpoint of a server, as JSON unless
+/// another [`Format`] is set.
+#[cfg(not(target_arch = "wasm32"))]
+pub struct HttpTransport {
+    endpoint: String,
+    client: reqwest::blocking::Client,
+    format: Format,
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl Default for HttpTransport {
+    fn default() -> Self {
+        Self::new(DEFAULT_ENDPOINT)
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl HttpTransport {
+    pub fn new(endpoint: &str) -> Self {
+        Self {
+            endpoint: endpoint.to_string(),
+            client: reqwest::blocking::Client::new(),
+            format: Format::default(),
+        }
+    }
+
+    /// Encode sync requests in `format` and ask for responses in it. The
+    /// server answers in a format it supports, which is decoded by its
+    /// content type.
+    pub fn with_format(mut self, format: Format) -> Self {
+        self.format = format;
+        self
+    }
+
+    pub fn endpoint(&self) -> &str {
+        &self.endpoint
+    }
+
+    pub fn format(&self) -> Format {
+        self.format
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl<const MERKLE_BASE: usize> Transport<MERKLE_BASE> for HttpTransport {
+    fn sync(
+        &self,
+        request: &SyncRequest<MERKLE_BASE>,
+        headers: &Headers,
+    ) -> anyhow::Result<SyncResponse<MERKLE_BASE>> {
+        let body = self.format.encode(request)?;
+
+        let mut req = self
+            .client
+            .post(format!("{}/sync", self.endpoint))
+            .header("Content-Type", self.format.content_type())
+            .header("Accept", self.format.content_type());
+        for (name, value) in headers {
+            req = req.header(name, value);
+        }
+        // The response is decompressed by reqwest itself
+        #[cfg(feature = "compression")]
+        let req = req
+            .header("Content-Encoding", "gzip")
+            .body(compression::compress(&body)?);
+        #[cfg(not(feature = "compression"))]
+        let req = req.body(body);
+
+        let res = req.send()?;
+        let format = res
+            .headers()
+            .get("Content-Type")
+            .and_then(|value| value.to_str().ok())
+            .and_then(Format::from_content_type)
+            .unwrap_or_default();
+        // The server rejects a trie of another base, telling its own
+        if res.status() == StatusCode::CONFLICT {
+            let mismatch: BaseMismatch = format.decode(&res.bytes()?)?;
+            return Err(from_server_mismatch(mismatch).into());
+        }
+        let res = format.decode::<SyncResponse<MERKLE_BASE>>(&res.error_for_status()?.bytes()?)?;
+
+        Ok(res)
+    }
+
+    fn handshake(
+        &self,
+        request: &HandshakeRequest,
+        headers: &Headers,
+    ) -> anyhow::Result<HandshakeResponse> {
+        let mut req = self
+            .client
+            .post(format!("{}/handshake", self.endpoint))
+            .json(request);
+        for (name, value) in headers {
+            req = req.header(name, value);
+        }
+
+        let res = req.send()?;
+        // A server without handshake support gets a full sync
+        if res.status() == StatusCode::NOT_FOUND {
+            return Ok(HandshakeResponse {
+                in_sync: false,
+                base: request.base,
+            });
+        }
+
+        Ok(res.error_for_status()?.json::<HandshakeResponse>()?)
+    }
+}
+
+#[cfg(feature = "compression")]
+pub mod compression {
+    use std::io::{Read, Write};
+
+    use flate2::read::GzDecoder;
+    use flate2::write::GzEncoder;
+    use flate2::Compression;
+
+    pub fn compress(data: &[u8]) -> std::io::Result<Vec<u8>> {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(data)?;
+        encoder.finish()
+    }
+
+    pub fn decompress(data: &[u8]) -> std::io::Result<Vec<u8>> {
+        let mut decoded = Vec::new();
+        GzDecoder::new(data).read_to_end(&mut decoded)?;
+        Ok(decoded)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[cfg(feature = "compression")]
+    #[test]
+    fn compression_round_trip_test() {
+        use merkle_trie_clock::models::{Message, ValueType};
+        use merkle_trie_clock::sync::SyncRequest;
+        use merkle_trie_clock::timestamp::Timestamp;
+
+        use crate::transport::compression::{compress, decompress};
+
+        let mut request = SyncRequest::<3> {
+            group_id: "group".to_string(),
+            client_id: "client".to_string(),
+            messages: vec![],
+            merkle: Default::default(),
+            base: 3,
+        };
+        for i in 0..100 {
+            let t = Timestamp::new(1712898800000 + i * 1000, 0, "client".to_string());
+            request.merkle.insert(&t);
+            request.messages.push(Message::change(
+                t.to_string(),
+                "todos",
+                "1",
+                "content",
+                ValueType::String,
+                "It's ok!",
+            ));
+        }
+
+        let json = serde_json::to_vec(&request).unwrap();
+        let compressed = compress(&json).unwrap();
+        assert!(compressed.len() < json.len());
+
+        let decompressed = decompress(&compressed).unwrap();
+        assert_eq!(decompressed, json);
+        let decoded: SyncRequest<3> = serde_json::from_slice(&decompressed).unwrap();
+        assert_eq!(decoded.messages.len(), request.messages.len());
+        assert_eq!(decoded.merkle.stored_keys(), request.merkle.stored_keys());
+    }
+}
@@ -0,0 +1,97 @@
+use anyhow::anyhow;
+use wasm_bindgen::JsValue;
+use web_sys::XmlHttpRequest;
+
+use merkle_trie_clock::sync::{HandshakeRequest, HandshakeResponse, SyncRequest, SyncResponse};
+
+use crate::transport::{from_server_mismatch, Headers, Transport, DEFAULT_ENDPOINT};
+
+/// Sends sync requests as JSON to the `/sync` endpoint of a server from the
+/// browser.
+///
+/// [`Transport`] is blocking while `fetch` is asynchronous only, so this
+/// uses a synchronous `XMLHttpRequest`. Browsers discourage it on the main
+/// thread, run the syncer in a web worker instead. Bodies aren't
+/// compressed, the browser handles compressed responses itself.
+pub struct WasmTransport {
+    endpoint: String,
+}
+
+impl Default for WasmTransport {
+    fn default() -> Self {
+        Self::new(DEFAULT_ENDPOINT)
+    }
+}
+
+impl WasmTransport {
+    pub fn new(endpoint: &str) -> Self {
+        Self {
+            endpoint: endpoint.to_string(),
+        }
+    }
+
+    pub fn endpoint(&self) -> &str {
+        &self.endpoint
+    }
+
+    /// Post a JSON body, returning the response status and body
+    fn post(&self, path: &str, body: &str, headers: &Headers) -> anyhow::Result<(u16, String)> {
+        let xhr = XmlHttpRequest::new().map_err(js_error)?;
+        xhr.open_with_async("POST", &format!("{}{}", self.endpoint, path), false)
+            .map_err(js_error)?;
+        xhr.set_request_header("Content-Type", "application/json")
+            .map_err(js_error)?;
+        for (name, value) in headers {
+            xhr.set_request_header(name, value).map_err(js_error)?;
+        }
+        xhr.send_with_opt_str(Some(body)).map_err(js_error)?;
+
+        let status = xhr.status().map_err(js_error)?;
+        let text = xhr.response_text().map_err(js_error)?.unwrap_or_default();
+        Ok((status, text))
+    }
+}
+
+impl<const MERKLE_BASE: usize> Transport<MERKLE_BASE> for WasmTransport {
+    fn sync(
+        &self,
+        request: &SyncRequest<MERKLE_BASE>,
+        headers: &Headers,
+    ) -> anyhow::Result<SyncResponse<MERKLE_BASE>> {
+        let (status, text) = self.post("/sync", &serde_json::to_string(request)?, headers)?;
+        // The server rejects a trie of another base, telling its own
+        if status == 409 {
+            return Err(from_server_mismatch(serde_json::from_str(&text)?).into());
+        }
+        if !(200..300).contains(&status) {
+            return Err(anyhow!("Sync failed with status {}: {}", status, text));
+        }
+
+        Ok(serde_json::from_str(&text)?)
+    }
+
+    fn handshake(
+        &self,
+        request: &HandshakeRequest,
+        headers: &Headers,
+    ) -> anyhow::Result<HandshakeResponse> {
+        let (status, text) = self.post("/handshake", &serde_json::to_string(request)?, headers)?;
+        // A server without handshake support gets a full sync
+        if status == 404 {
+            return Ok(HandshakeResponse {
+                in_sync: false,
+                base: request.base,
+            });
+        }
+        if !(200..300).contains(&status) {
+            return Err(anyhow!("Handshake failed with status {}: {}", status, text));
+        }
+
+        Ok(serde_json::from_str(&text)?)
+    }
+}
+
+/// JS errors aren't `Send`, keep their description only
+fn js_error(e: JsValue) -> anyhow::Error {
+    anyhow!("{:?}", e)
+}
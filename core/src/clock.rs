@@ -1,20 +1,35 @@
-use crate::merkle::MerkleTrie;
-use crate::timestamp::Timestamp;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
 
-pub struct MerkleClock<const BASE: usize = 3> {
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+
+use crate::error::TimestampError;
+use crate::merkle::{MerkleTrie, DEFAULT_BASE};
+#[cfg(feature = "std")]
+use crate::timestamp::SystemTimeSource;
+use crate::timestamp::{TimeSource, Timestamp};
+
+/// The full logical-clock state of a node. It can be serialized to persist
+/// the clock across restarts.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MerkleClock<const BASE: usize = DEFAULT_BASE> {
     timer: Timestamp,
     merkle: MerkleTrie<BASE>,
 }
 
-unsafe impl<const BASE: usize> Send for MerkleClock<BASE> {}
-
-unsafe impl<const BASE: usize> Sync for MerkleClock<BASE> {}
-
 impl<const BASE: usize> MerkleClock<BASE> {
     pub fn new(timer: Timestamp, merkle: MerkleTrie<BASE>) -> Self {
         Self { timer, merkle }
     }
 
+    /// A fresh clock for a new node with a generated node id, see
+    /// [`Timestamp::local`]
+    #[cfg(feature = "std")]
+    pub fn local() -> Self {
+        Self::new(Timestamp::local(), MerkleTrie::new())
+    }
+
     pub fn timer(&self) -> &Timestamp {
         &self.timer
     }
@@ -30,6 +45,93 @@ impl<const BASE: usize> MerkleClock<BASE> {
     pub fn merkle_mut(&mut self) -> &mut MerkleTrie<BASE> {
         &mut self.merkle
     }
+
+    /// Advance the timer for a new local event
+    #[cfg(feature = "std")]
+    pub fn tick(&mut self) -> Result<Timestamp, TimestampError> {
+        self.tick_with(&SystemTimeSource)
+    }
+
+    /// Like [`MerkleClock::tick`], reading the wall time from `source`
+    pub fn tick_with(&mut self, source: &impl TimeSource) -> Result<Timestamp, TimestampError> {
+        self.timer.send_with(source)
+    }
+
+    /// Advance the timer for a new local event and return its timestamp as
+    /// the string a message carries
+    #[cfg(feature = "std")]
+    pub fn new_event_timestamp(&mut self) -> Result<String, TimestampError> {
+        self.new_event_timestamp_with(&SystemTimeSource)
+    }
+
+    /// Like [`MerkleClock::new_event_timestamp`], reading the wall time from
+    /// `source`
+    pub fn new_event_timestamp_with(
+        &mut self,
+        source: &impl TimeSource,
+    ) -> Result<String, TimestampError> {
+        Ok(self.tick_with(source)?.to_string())
+    }
+
+    /// Apply an event timestamp: merge it into the timer and insert it into
+    /// the merkle trie, so both always move together.
+    ///
+    /// Timestamps issued by this node (see [`MerkleClock::tick`]) are already
+    /// reflected in the timer and are only inserted into the trie.
+    #[cfg(feature = "std")]
+    pub fn apply(&mut self, timestamp: &Timestamp) -> Result<()> {
+        self.apply_with(timestamp, &SystemTimeSource)
+    }
+
+    /// Like [`MerkleClock::apply`], reading the wall time from `source`
+    pub fn apply_with(&mut self, timestamp: &Timestamp, source: &impl TimeSource) -> Result<()> {
+        if timestamp.node() != self.timer.node() {
+            self.timer.recv_with(timestamp, source)?;
+        }
+        self.merkle.insert(timestamp);
+
+        Ok(())
+    }
+
+    /// Merge a clock received from another node: the timer moves past the
+    /// remote timer and the trie becomes the union of both tries. Fails for
+    /// a bucket both tries store with different timestamps, see
+    /// [`MerkleTrie::merge`].
+    #[cfg(feature = "std")]
+    pub fn merge(&mut self, other: &MerkleClock<BASE>) -> Result<()> {
+        self.merge_with(other, &SystemTimeSource)
+    }
+
+    /// Like [`MerkleClock::merge`], reading the wall time from `source`
+    pub fn merge_with(
+        &mut self,
+        other: &MerkleClock<BASE>,
+        source: &impl TimeSource,
+    ) -> Result<()> {
+        self.timer.recv_with(&other.timer, source)?;
+        self.merkle.merge(&other.merkle)
+    }
+
+    /// Serialize the clock, e.g. for a server-provided bootstrap blob
+    pub fn snapshot(&self) -> Result<Vec<u8>> {
+        Ok(serde_json::to_vec(self)?)
+    }
+
+    /// Seed the clock from a [`MerkleClock::snapshot`]: the trie is replaced
+    /// by the snapshot trie and the timer moves past the snapshot timer,
+    /// keeping our node id unless the snapshot was taken from this node.
+    #[cfg(feature = "std")]
+    pub fn restore(&mut self, bytes: &[u8]) -> Result<()> {
+        let snapshot: MerkleClock<BASE> = serde_json::from_slice(bytes)?;
+        if snapshot.timer.node() == self.timer.node() {
+            self.timer = snapshot.timer;
+        } else {
+            self.timer.recv(&snapshot.timer)?;
+        }
+        self.merkle = snapshot.merkle;
+
+        Ok(())
+    }
 }
 
 #[cfg(test)]
@@ -71,4 +173,147 @@ mod tests {
         println!();
         assert_eq!(c.merkle.length(), 2);
     }
+
+    #[test]
+    fn tick_and_apply_test() {
+        let mut c = MerkleClock::new(
+            Timestamp::new(0, 0, "local".to_string()),
+            MerkleTrie::<3>::new(),
+        );
+
+        // Local event
+        let t1 = c.tick().unwrap();
+        c.apply(&t1).unwrap();
+        assert_eq!(c.merkle().length(), 1);
+        assert_eq!(c.merkle().root_hash(), t1.hash());
+
+        // Remote event ahead of our clock
+        let remote = Timestamp::new(t1.millis() + 10, 3, "remote".to_string());
+        c.apply(&remote).unwrap();
+        assert_eq!(c.merkle().length(), 2);
+        assert_eq!(c.merkle().root_hash(), t1.hash() ^ remote.hash());
+        assert!(c.timer().millis() >= remote.millis());
+        if c.timer().millis() == remote.millis() {
+            assert_eq!(c.timer().counter(), 4);
+        }
+
+        // The next local event is still after everything we have seen
+        let t2 = c.tick().unwrap();
+        assert!(
+            t2.millis() > remote.millis()
+                || (t2.millis() == remote.millis() && t2.counter() > remote.counter())
+        );
+    }
+
+    #[test]
+    fn new_event_timestamp_test() {
+        let mut c = MerkleClock::new(
+            Timestamp::new(0, 0, "local".to_string()),
+            MerkleTrie::<3>::new(),
+        );
+
+        let mut last = c.new_event_timestamp().unwrap();
+        for _ in 0..100 {
+            let next = c.new_event_timestamp().unwrap();
+            assert!(next > last, "{} <= {}", next, last);
+            last = next;
+        }
+        assert_eq!(last, c.timer().to_string());
+        // Only the timer moves, the trie is updated on apply
+        assert!(c.merkle().is_empty());
+
+        // A frozen wall time still gives increasing timestamps
+        let mut c = MerkleClock::new(
+            Timestamp::new(0, 0, "local".to_string()),
+            MerkleTrie::<3>::new(),
+        );
+        let first = c.new_event_timestamp_with(&|| 1712898800000).unwrap();
+        let second = c.new_event_timestamp_with(&|| 1712898800000).unwrap();
+        assert!(second > first);
+    }
+
+    #[test]
+    fn merge_test() {
+        let mut local = MerkleClock::new(
+            Timestamp::new(0, 0, "local".to_string()),
+            MerkleTrie::<3>::new(),
+        );
+        let t1 = local.tick().unwrap();
+        local.apply(&t1).unwrap();
+
+        let remote_time = Timestamp::new(t1.millis() + 5, 7, "remote".to_string());
+        let mut remote = MerkleClock::new(remote_time.clone(), MerkleTrie::<3>::new());
+        remote.apply(&remote_time).unwrap();
+
+        local.merge(&remote).unwrap();
+
+        // Timer moved past both clocks
+        assert!(local.timer().millis() >= remote_time.millis());
+        if local.timer().millis() == remote_time.millis() {
+            assert_eq!(local.timer().counter(), 8);
+        }
+
+        // Trie holds both events
+        let mut expected = MerkleTrie::<3>::new();
+        expected.insert(&t1);
+        expected.insert(&remote_time);
+        assert_eq!(local.merkle().root_hash(), expected.root_hash());
+        assert_eq!(local.merkle().length(), 2);
+    }
+
+    #[test]
+    fn serialize_deserialize_test() {
+        let mut c = MerkleClock::new(
+            Timestamp::new(0, 0, "local".to_string()),
+            MerkleTrie::<10>::new(),
+        );
+        for _ in 0..3 {
+            let t = c.tick().unwrap();
+            c.apply(&t).unwrap();
+        }
+
+        let serialized = serde_json::to_string(&c).unwrap();
+        let deserialized: MerkleClock<10> = serde_json::from_str(&serialized).unwrap();
+
+        assert_eq!(deserialized.merkle().root_hash(), c.merkle().root_hash());
+        assert_eq!(deserialized.merkle().length(), 3);
+        assert_eq!(deserialized.timer().millis(), c.timer().millis());
+        assert_eq!(deserialized.timer().counter(), c.timer().counter());
+        assert_eq!(deserialized.timer().node(), "00000000000local");
+    }
+
+    #[test]
+    fn snapshot_restore_test() {
+        let mut server = MerkleClock::new(
+            Timestamp::new(1712898800000, 0, "server".to_string()),
+            MerkleTrie::<3>::new(),
+        );
+        for i in 0..3 {
+            server
+                .apply(&Timestamp::new(1712898800000 + i, 0, "remote".to_string()))
+                .unwrap();
+        }
+        let bytes = server.snapshot().unwrap();
+
+        let mut client = MerkleClock::new(
+            Timestamp::new(0, 0, "client".to_string()),
+            MerkleTrie::<3>::new(),
+        );
+        client.restore(&bytes).unwrap();
+        assert_eq!(client.merkle().root_hash(), server.merkle().root_hash());
+        assert_eq!(client.timer().node(), "0000000000client");
+        assert!(client.timer().millis() >= server.timer().millis());
+
+        assert!(client.restore(b"not a snapshot").is_err());
+    }
+
+    #[test]
+    fn local_test() {
+        let c1 = MerkleClock::<3>::local();
+        let c2 = MerkleClock::<3>::local();
+
+        assert_eq!(c1.timer().node().len(), 16);
+        assert_ne!(c1.timer().node(), c2.timer().node());
+        assert!(c1.merkle().stored_keys().is_empty());
+    }
 }
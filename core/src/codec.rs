@@ -0,0 +1,203 @@
+use alloc::string::ToString;
+use alloc::vec::Vec;
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use crate::error::CodecError;
+
+/// The wire format of sync payloads
+pub trait Codec {
+    /// The MIME type announcing the format in `Content-Type` and `Accept`
+    fn content_type(&self) -> &'static str;
+
+    fn encode<T: Serialize>(&self, value: &T) -> Result<Vec<u8>, CodecError>;
+
+    fn decode<T: DeserializeOwned>(&self, bytes: &[u8]) -> Result<T, CodecError>;
+}
+
+/// The formats known to the client and the server, picked at runtime by
+/// content type. JSON unless told otherwise, the other formats are behind
+/// the `messagepack` and `cbor` features.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Format {
+    #[default]
+    Json,
+    #[cfg(feature = "messagepack")]
+    MessagePack,
+    #[cfg(feature = "cbor")]
+    Cbor,
+}
+
+impl Format {
+    /// The format of a `Content-Type` or `Accept` value, `None` for an
+    /// unsupported one. Parameters like `; charset=utf-8` are ignored.
+    pub fn from_content_type(content_type: &str) -> Option<Self> {
+        let mime = content_type.split(';').next()?.trim();
+        match mime {
+            "application/json" => Some(Format::Json),
+            #[cfg(feature = "messagepack")]
+            "application/msgpack" | "application/x-msgpack" => Some(Format::MessagePack),
+            #[cfg(feature = "cbor")]
+            "application/cbor" => Some(Format::Cbor),
+            _ => None,
+        }
+    }
+
+    /// The first supported format of an `Accept` header listing several
+    pub fn from_accept(accept: &str) -> Option<Self> {
+        accept.split(',').find_map(Self::from_content_type)
+    }
+}
+
+impl Codec for Format {
+    fn content_type(&self) -> &'static str {
+        match self {
+            Format::Json => "application/json",
+            #[cfg(feature = "messagepack")]
+            Format::MessagePack => "application/msgpack",
+            #[cfg(feature = "cbor")]
+            Format::Cbor => "application/cbor",
+        }
+    }
+
+    fn encode<T: Serialize>(&self, value: &T) -> Result<Vec<u8>, CodecError> {
+        match self {
+            Format::Json => {
+                serde_json::to_vec(value).map_err(|e| CodecError::Encode(e.to_string()))
+            }
+            // Structs as maps, so peers can read them by field name
+            #[cfg(feature = "messagepack")]
+            Format::MessagePack => {
+                rmp_serde::to_vec_named(value).map_err(|e| CodecError::Encode(e.to_string()))
+            }
+            #[cfg(feature = "cbor")]
+            Format::Cbor => {
+                let mut bytes = Vec::new();
+                ciborium::into_writer(value, &mut bytes)
+                    .map_err(|e| CodecError::Encode(e.to_string()))?;
+                Ok(bytes)
+            }
+        }
+    }
+
+    fn decode<T: DeserializeOwned>(&self, bytes: &[u8]) -> Result<T, CodecError> {
+        match self {
+            Format::Json => {
+                serde_json::from_slice(bytes).map_err(|e| CodecError::Decode(e.to_string()))
+            }
+            #[cfg(feature = "messagepack")]
+            Format::MessagePack => {
+                rmp_serde::from_slice(bytes).map_err(|e| CodecError::Decode(e.to_string()))
+            }
+            #[cfg(feature = "cbor")]
+            Format::Cbor => {
+                ciborium::from_reader(bytes).map_err(|e| CodecError::Decode(e.to_string()))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::string::ToString;
+    use alloc::vec;
+    use alloc::vec::Vec;
+
+    use crate::codec::{Codec, Format};
+    use crate::error::CodecError;
+    use crate::models::{Message, ValueType};
+    use crate::sync::SyncRequest;
+    use crate::timestamp::Timestamp;
+
+    #[test]
+    fn codec_round_trip_test() {
+        let mut request = SyncRequest::<3> {
+            group_id: "group".to_string(),
+            client_id: "client0000000001".to_string(),
+            messages: vec![],
+            merkle: Default::default(),
+            base: 3,
+        };
+        for i in 0..20 {
+            let t = Timestamp::new(1712898800000 + i * 1000, 0, "client0000000001".to_string());
+            request.merkle.insert(&t);
+            request.messages.push(Message::change(
+                t.to_string(),
+                "todos",
+                "1",
+                "content",
+                ValueType::String,
+                "It's ok!",
+            ));
+        }
+        // A bucket of several timestamps carries their count
+        let shared = Timestamp::new(1712898800000, 1, "client0000000002".to_string());
+        request.merkle.insert(&shared);
+
+        let formats = [
+            Format::Json,
+            #[cfg(feature = "messagepack")]
+            Format::MessagePack,
+            #[cfg(feature = "cbor")]
+            Format::Cbor,
+        ];
+        for format in formats {
+            assert_eq!(
+                Format::from_content_type(format.content_type()),
+                Some(format)
+            );
+
+            let bytes = format.encode(&request).unwrap();
+            let decoded: SyncRequest<3> = format.decode(&bytes).unwrap();
+            assert_eq!(decoded.group_id, request.group_id);
+            assert_eq!(decoded.client_id, request.client_id);
+            assert_eq!(decoded.base, request.base);
+            assert_eq!(
+                decoded
+                    .messages
+                    .iter()
+                    .map(|m| (&m.timestamp, &m.value))
+                    .collect::<Vec<_>>(),
+                request
+                    .messages
+                    .iter()
+                    .map(|m| (&m.timestamp, &m.value))
+                    .collect::<Vec<_>>()
+            );
+            assert_eq!(decoded.merkle.root_hash(), request.merkle.root_hash());
+            assert_eq!(decoded.merkle.diff(&request.merkle), Ok(None));
+            let mut pruned = decoded.merkle.clone();
+            pruned.prune_before(1712898800001);
+            assert_eq!(pruned.length(), request.merkle.length() - 2);
+
+            assert!(matches!(
+                format.decode::<SyncRequest<3>>(b"\xff\x00garbage"),
+                Err(CodecError::Decode(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn from_content_type_test() {
+        assert_eq!(
+            Format::from_content_type("application/json; charset=utf-8"),
+            Some(Format::Json)
+        );
+        assert_eq!(Format::from_content_type("text/plain"), None);
+        assert_eq!(
+            Format::from_accept("text/html, application/json"),
+            Some(Format::Json)
+        );
+        #[cfg(feature = "messagepack")]
+        assert_eq!(
+            Format::from_accept("application/msgpack, application/json"),
+            Some(Format::MessagePack)
+        );
+        #[cfg(feature = "cbor")]
+        assert_eq!(
+            Format::from_content_type("application/cbor"),
+            Some(Format::Cbor)
+        );
+    }
+}
@@ -0,0 +1,135 @@
+use std::sync::{RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+use crate::error::DiffError;
+use crate::merkle::{MerkleTrie, DEFAULT_BASE};
+use crate::timestamp::Timestamp;
+
+/// A merkle trie shared between threads: any number of concurrent readers
+/// (`diff`, `root_hash`, ...) or one writer at a time (`insert`, `merge`).
+///
+/// # Safety
+///
+/// `MerkleTrie` is `Send + Sync` by `unsafe impl`, as its nodes are raw
+/// pointers. That is sound because the trie owns its nodes exclusively, like
+/// a `Box`: no node is shared with another trie (`Clone` copies them deeply)
+/// and nodes are only mutated through `&mut MerkleTrie`. Reading a trie from
+/// several threads through `&MerkleTrie` is therefore fine, and the lock
+/// makes sure nothing reads while a writer holds the `&mut`.
+#[derive(Debug, Default)]
+pub struct ConcurrentMerkleTrie<const BASE: usize = DEFAULT_BASE> {
+    trie: RwLock<MerkleTrie<BASE>>,
+}
+
+impl<const BASE: usize> ConcurrentMerkleTrie<BASE> {
+    pub fn new(trie: MerkleTrie<BASE>) -> Self {
+        Self {
+            trie: RwLock::new(trie),
+        }
+    }
+
+    /// Shared access to the trie, blocking while a writer holds it
+    pub fn read(&self) -> RwLockReadGuard<'_, MerkleTrie<BASE>> {
+        self.trie.read().unwrap()
+    }
+
+    /// Exclusive access to the trie, blocking until all readers are done
+    pub fn write(&self) -> RwLockWriteGuard<'_, MerkleTrie<BASE>> {
+        self.trie.write().unwrap()
+    }
+
+    pub fn insert(&self, timestamp: &Timestamp) {
+        self.write().insert(timestamp);
+    }
+
+    pub fn merge(&self, other: &MerkleTrie<BASE>) -> anyhow::Result<()> {
+        self.write().merge(other)
+    }
+
+    pub fn root_hash(&self) -> u64 {
+        self.read().root_hash()
+    }
+
+    /// See [`MerkleTrie::diff`]. Diffing against another shared trie takes a
+    /// [`ConcurrentMerkleTrie::snapshot`] of it first, as holding both locks
+    /// at once could deadlock against a diff the other way round.
+    pub fn diff(&self, other: &MerkleTrie<BASE>) -> Result<Option<i64>, DiffError> {
+        self.read().diff(other)
+    }
+
+    /// A copy of the trie as it is now
+    pub fn snapshot(&self) -> MerkleTrie<BASE> {
+        self.read().clone()
+    }
+
+    pub fn into_inner(self) -> MerkleTrie<BASE> {
+        self.trie.into_inner().unwrap()
+    }
+}
+
+impl<const BASE: usize> From<MerkleTrie<BASE>> for ConcurrentMerkleTrie<BASE> {
+    fn from(trie: MerkleTrie<BASE>) -> Self {
+        Self::new(trie)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::thread;
+
+    use crate::concurrent::ConcurrentMerkleTrie;
+    use crate::merkle::MerkleTrie;
+    use crate::timestamp::Timestamp;
+
+    fn timestamp(thread: usize, i: usize) -> Timestamp {
+        Timestamp::new(
+            1712898800000 + (i * 8 + thread) as i64 * 1000,
+            0,
+            format!("client000000000{}", thread),
+        )
+    }
+
+    #[test]
+    fn concurrent_insert_test() {
+        let shared = Arc::new(ConcurrentMerkleTrie::<3>::default());
+
+        let writers = (0..4)
+            .map(|thread| {
+                let shared = shared.clone();
+                thread::spawn(move || {
+                    for i in 0..200 {
+                        shared.insert(&timestamp(thread, i));
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+        let readers = (0..4)
+            .map(|_| {
+                let shared = shared.clone();
+                thread::spawn(move || {
+                    for _ in 0..200 {
+                        let trie = shared.read();
+                        // Every read sees a consistent trie
+                        assert_eq!(trie.stored_keys().len() as u64, trie.length());
+                        drop(trie);
+                        let _ = shared.diff(&MerkleTrie::new()).unwrap();
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+        for handle in writers.into_iter().chain(readers) {
+            handle.join().unwrap();
+        }
+
+        let expected: MerkleTrie<3> = MerkleTrie::from_timestamps(
+            &(0..4)
+                .flat_map(|thread| (0..200).map(move |i| timestamp(thread, i)))
+                .collect::<Vec<_>>(),
+        );
+        assert_eq!(shared.root_hash(), expected.root_hash());
+        assert_eq!(shared.diff(&expected), Ok(None));
+
+        let trie = Arc::into_inner(shared).unwrap().into_inner();
+        assert_eq!(trie.length(), 800);
+    }
+}
@@ -0,0 +1,118 @@
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+pub enum TimestampError {
+    /// The logical time is further ahead of the physical time than allowed
+    #[error("ClockDriftError: {got}, {phys}, {max}")]
+    ClockDrift { got: i64, phys: i64, max: i64 },
+
+    /// The physical clock is further behind the logical time than the
+    /// allowed drift, so new timestamps could only bump the counter
+    #[error("ClockWentBackwardsError: logical: {logical}, phys: {phys}")]
+    ClockWentBackwards { logical: i64, phys: i64 },
+
+    /// The counter doesn't fit into the timestamp string anymore
+    #[error("OverflowError")]
+    CounterOverflow,
+
+    /// A remote timestamp carries the same node id as ours
+    #[error("DuplicateNodeError: {0}")]
+    DuplicateNode(String),
+
+    #[error("Parse timestamp failed: {0}")]
+    Parse(String),
+
+    /// The time is before 1970, which has no merkle key
+    #[error("BeforeEpochError: {0}")]
+    BeforeEpoch(i64),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+pub enum DiffError {
+    /// The tries group timestamps into buckets of a different width
+    #[error("Mismatched bucket width, got: {got}, expected: {expected}")]
+    BucketMismatch { got: u64, expected: u64 },
+
+    /// A trie doesn't have the structure of a merkle trie
+    #[error("Malformed merkle trie: {0}")]
+    Malformed(String),
+
+    /// A differing key of the tries is out of the millisecond range
+    #[error(transparent)]
+    Overflow(#[from] KeyOverflow),
+}
+
+/// A sync payload failed to go through a [`crate::codec::Codec`]
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+pub enum CodecError {
+    #[error("Encode error: {0}")]
+    Encode(String),
+
+    #[error("Decode error: {0}")]
+    Decode(String),
+}
+
+/// A merkle key whose time doesn't fit into the millisecond range
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+#[error("Merkle key out of the millisecond range: {key:?}")]
+pub struct KeyOverflow {
+    pub key: Vec<usize>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+pub enum MessageError {
+    /// The message timestamp isn't a valid timestamp string
+    #[error("Invalid message timestamp: {0}")]
+    Timestamp(#[from] TimestampError),
+
+    /// One of dataset, row or column is empty
+    #[error("Empty message field: {0}")]
+    EmptyField(&'static str),
+
+    /// The value doesn't parse as its declared value type
+    #[error("Invalid {value_type} value: {value:?}")]
+    InvalidValue { value_type: String, value: String },
+}
+
+/// A peer keys its merkle trie with another base than ours, so the tries
+/// can't be compared
+#[derive(Debug, Clone, PartialEq, Eq, Error, Serialize, Deserialize)]
+#[error("Mismatched merkle base, local: {local}, remote: {remote}")]
+pub struct BaseMismatch {
+    pub local: usize,
+    pub remote: usize,
+}
+
+/// A sync kept finding the tries differing at the same time, i.e. the
+/// server didn't send what the client lacks, or didn't store what the client
+/// sent. It shouldn't happen, the fields tell where the tries diverged.
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+#[error(
+    "Sync didn't converge after {iterations} iterations, since: {since}, diff time: {diff_time}, \
+     local root hash: {local_hash}, remote root hash: {remote_hash}"
+)]
+pub struct NonConvergence {
+    /// The time the last round synced from
+    pub since: i64,
+    /// The time since which the tries still differ
+    pub diff_time: i64,
+    pub local_hash: u64,
+    pub remote_hash: u64,
+    /// The sync requests sent
+    pub iterations: usize,
+}
+
+/// A node of a merkle trie whose hash isn't the XOR of its children's
+/// hashes, see [`crate::merkle::MerkleTrie::verify`]
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+#[error("Inconsistent merkle node {key:?}, hash: {found}, expected: {expected}")]
+pub struct InconsistencyReport {
+    /// The path from the root to the node
+    pub key: Vec<usize>,
+    pub expected: u64,
+    pub found: u64,
+}
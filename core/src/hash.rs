@@ -0,0 +1,64 @@
+//! MurmurHash3 (x86, 32 bits), which timestamps are hashed with. It has to
+//! stay bit for bit identical on every node, the hashes end up in the
+//! merkle tries peers compare.
+
+const C1: u32 = 0xcc9e_2d51;
+const C2: u32 = 0x1b87_3593;
+const D: u32 = 0xe654_6b64;
+const SEED: u32 = 3_242_157_231;
+const FMIX1: u32 = 0x85eb_ca6b;
+const FMIX2: u32 = 0xc2b2_ae35;
+
+fn fmix32(mut h: u32) -> u32 {
+    h ^= h >> 16;
+    h = h.wrapping_mul(FMIX1);
+    h ^= h >> 13;
+    h = h.wrapping_mul(FMIX2);
+    h ^= h >> 16;
+    h
+}
+
+fn mix_k(k: u32) -> u32 {
+    k.wrapping_mul(C1).rotate_left(15).wrapping_mul(C2)
+}
+
+pub(crate) fn murmurhash3(key: &[u8]) -> u32 {
+    let mut h = SEED;
+
+    let mut chunks = key.chunks_exact(4);
+    for chunk in &mut chunks {
+        h ^= mix_k(u32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]));
+        h = h.rotate_left(13).wrapping_mul(5).wrapping_add(D);
+    }
+
+    let remainder = chunks.remainder();
+    if !remainder.is_empty() {
+        let k = remainder
+            .iter()
+            .rev()
+            .fold(0u32, |k, byte| (k << 8) ^ u32::from(*byte));
+        h ^= mix_k(k);
+    }
+
+    fmix32(h ^ key.len() as u32)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::hash::murmurhash3;
+
+    #[test]
+    fn murmurhash3_test() {
+        assert_eq!(murmurhash3(b""), 36_859_204);
+        assert_eq!(murmurhash3(b"a"), 3_144_985_375);
+        assert_eq!(murmurhash3(b"ab"), 3_262_304_301);
+        assert_eq!(murmurhash3(b"abc"), 476_091_040);
+        assert_eq!(murmurhash3(b"abcd"), 412_992_581);
+        assert_eq!(murmurhash3(b"abcde"), 2_747_833_956);
+        assert_eq!(murmurhash3(b"abcdefghijklmnop"), 2_078_305_053);
+        assert_eq!(
+            murmurhash3(b"2024-04-12T05:13:20.831+00:00-0000-5ef35ca3375b14c8"),
+            2_592_369_424
+        );
+    }
+}
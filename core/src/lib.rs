@@ -1,4 +1,14 @@
+#![cfg_attr(not(feature = "std"), no_std)]
+
+extern crate alloc;
+
 pub mod clock;
+pub mod codec;
+#[cfg(feature = "std")]
+pub mod concurrent;
+pub mod error;
+mod hash;
 pub mod merkle;
 pub mod models;
+pub mod sync;
 pub mod timestamp;
@@ -1,16 +1,33 @@
 #![allow(clippy::only_used_in_recursion)]
 
-use std::cmp::min;
-use std::collections::BTreeMap;
-use std::ptr::NonNull;
-
+use alloc::boxed::Box;
+use alloc::collections::{btree_set, BTreeMap, BTreeSet};
+use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::vec;
+use alloc::vec::Vec;
+use core::fmt::Write;
+use core::marker::PhantomData;
+use core::ptr::NonNull;
+
+use anyhow::bail;
 use serde::ser::SerializeStruct;
 use serde::{Deserialize, Deserializer, Serialize, Serializer};
 
+use crate::error::{DiffError, InconsistencyReport, KeyOverflow, TimestampError};
 use crate::timestamp::Timestamp;
 
-#[derive(Debug, Clone)]
-struct MerkleTrieNode<const BASE: usize = 3> {
+/// One leaf per millisecond
+pub const DEFAULT_BUCKET_MS: u64 = 1;
+
+/// The base of the trie keys unless chosen otherwise. Clients and servers
+/// must agree on the base to compare their tries.
+pub const DEFAULT_BASE: usize = 3;
+
+/// A node of the trie. Every node exclusively owns its children, which are
+/// freed when the node is dropped.
+#[derive(Debug)]
+struct MerkleTrieNode<const BASE: usize = DEFAULT_BASE> {
     /// The children of this trie
     children: Option<BTreeMap<usize, NonNull<MerkleTrieNode<BASE>>>>,
 
@@ -19,8 +36,15 @@ struct MerkleTrieNode<const BASE: usize = 3> {
 
     /// Whether this node stored the corresponding data
     stored: bool,
+
+    /// The number of timestamps in the bucket of this node, 0 unless stored
+    count: u64,
 }
 
+// SAFETY: a node owns its children exclusively, like a `Box` would: every
+// child pointer comes from `Box::into_raw`, is never shared with another
+// node or trie, and is freed once by `Drop`. Children are only mutated
+// through `&mut` of their parent, so `&MerkleTrieNode` is read-only.
 unsafe impl<const BASE: usize> Send for MerkleTrieNode<BASE> {}
 
 unsafe impl<const BASE: usize> Sync for MerkleTrieNode<BASE> {}
@@ -31,7 +55,213 @@ impl<const BASE: usize> Default for MerkleTrieNode<BASE> {
             children: None,
             hash: 0,
             stored: false,
+            count: 0,
+        }
+    }
+}
+
+impl<const BASE: usize> Clone for MerkleTrieNode<BASE> {
+    /// Copy this node and its whole subtree into newly allocated nodes
+    fn clone(&self) -> Self {
+        let children = self.children.as_ref().map(|children| {
+            children
+                .iter()
+                .map(|(k, child)| {
+                    let child = unsafe { child.as_ref().clone() };
+                    (*k, NonNull::new(Box::into_raw(Box::new(child))).unwrap())
+                })
+                .collect()
+        });
+
+        Self {
+            children,
+            hash: self.hash,
+            stored: self.stored,
+            count: self.count,
+        }
+    }
+}
+
+impl<const BASE: usize> Drop for MerkleTrieNode<BASE> {
+    fn drop(&mut self) {
+        if let Some(children) = self.children.take() {
+            for child in children.into_values() {
+                unsafe { drop(Box::from_raw(child.as_ptr())) };
+            }
+        }
+    }
+}
+
+impl<const BASE: usize> MerkleTrieNode<BASE> {
+    /// The XOR of the hashes of all direct children
+    fn children_hash(&self) -> u64 {
+        self.children.as_ref().map_or(0, |children| {
+            children
+                .values()
+                .fold(0, |acc, child| acc ^ unsafe { child.as_ref().hash })
+        })
+    }
+
+    /// The number of stored nodes in this subtree
+    fn stored_count(&self) -> u64 {
+        let children = self.children.as_ref().map_or(0, |children| {
+            children
+                .values()
+                .map(|child| unsafe { child.as_ref().stored_count() })
+                .sum()
+        });
+
+        children + self.stored as u64
+    }
+
+    /// The number of timestamps in the buckets of this subtree
+    fn timestamp_count(&self) -> u64 {
+        let children = self.children.as_ref().map_or(0, |children| {
+            children
+                .values()
+                .map(|child| unsafe { child.as_ref().timestamp_count() })
+                .sum()
+        });
+
+        children + self.count
+    }
+
+    /// The number of nodes in this subtree, itself included
+    fn node_count(&self) -> usize {
+        let children = self.children.as_ref().map_or(0, |children| {
+            children
+                .values()
+                .map(|child| unsafe { child.as_ref().node_count() })
+                .sum()
+        });
+
+        children + 1
+    }
+
+    /// Collect the depths below this node at which nodes are stored
+    fn stored_depths(&self, depth: usize, depths: &mut BTreeSet<usize>) {
+        if self.stored {
+            depths.insert(depth);
         }
+        if let Some(children) = &self.children {
+            for child in children.values() {
+                unsafe { child.as_ref().stored_depths(depth + 1, depths) };
+            }
+        }
+    }
+
+    /// Unstore the buckets of this subtree before the bucket `since`, this
+    /// node being the bucket `value`, and free the nodes left empty. Returns
+    /// the XOR of the removed hashes and the number of removed timestamps.
+    fn prune_before(&mut self, value: u64, since: u64) -> (u64, u64) {
+        let mut removed_hash = 0;
+        let mut removed = 0;
+        if let Some(children) = &mut self.children {
+            let mut empty = vec![];
+            for (k, child) in children.iter_mut() {
+                let child = unsafe { child.as_mut() };
+                let (hash, count) = child.prune_before(value * BASE as u64 + *k as u64, since);
+                removed_hash ^= hash;
+                removed += count;
+                if child.is_empty() {
+                    empty.push(*k);
+                }
+            }
+            for k in empty {
+                if let Some(child) = children.remove(&k) {
+                    unsafe { drop(Box::from_raw(child.as_ptr())) };
+                }
+            }
+        }
+        if self.children.as_ref().is_some_and(|c| c.is_empty()) {
+            self.children = None;
+        }
+        self.hash ^= removed_hash;
+
+        if self.stored && value < since {
+            let own = self.hash ^ self.children_hash();
+            self.hash ^= own;
+            self.stored = false;
+            removed_hash ^= own;
+            removed += self.count;
+            self.count = 0;
+        }
+
+        (removed_hash, removed)
+    }
+
+    /// Remove `hash` from the bucket at `key` below this node, dropping the
+    /// nodes left empty. Returns whether the bucket was stored.
+    fn remove(&mut self, key: &[usize], hash: u64) -> bool {
+        let Some((child_key, rest)) = key.split_first() else {
+            if !self.stored {
+                return false;
+            }
+            self.hash ^= hash;
+            self.count = self.count.saturating_sub(1);
+            // The bucket is gone once no timestamp is left in its own hash
+            if self.hash == self.children_hash() {
+                self.stored = false;
+                self.count = 0;
+            }
+            return true;
+        };
+
+        let Some(children) = self.children.as_mut() else {
+            return false;
+        };
+        let Some(child) = children.get_mut(child_key) else {
+            return false;
+        };
+        let child = unsafe { child.as_mut() };
+        if !child.remove(rest, hash) {
+            return false;
+        }
+        if child.is_empty() {
+            if let Some(child) = children.remove(child_key) {
+                unsafe { drop(Box::from_raw(child.as_ptr())) };
+            }
+            if children.is_empty() {
+                self.children = None;
+            }
+        }
+        self.hash ^= hash;
+
+        true
+    }
+
+    /// Add this subtree, at `depth` below the root, to `stats`
+    fn collect_stats(&self, depth: usize, stats: &mut TrieStats, depth_sum: &mut usize) {
+        stats.node_count += 1;
+        stats.max_depth = stats.max_depth.max(depth);
+        if self.stored {
+            stats.stored_count += 1;
+            *depth_sum += depth;
+        }
+        if let Some(children) = self.children.as_ref().filter(|c| !c.is_empty()) {
+            stats.internal_count += 1;
+            for child in children.values() {
+                unsafe { child.as_ref().collect_stats(depth + 1, stats, depth_sum) };
+            }
+        }
+    }
+
+    /// Neither stored nor the parent of a stored node
+    fn is_empty(&self) -> bool {
+        !self.stored && self.children.as_ref().is_none_or(|c| c.is_empty())
+    }
+
+    /// The number of edges on the longest path down to a leaf
+    fn depth(&self) -> usize {
+        self.children
+            .as_ref()
+            .and_then(|children| {
+                children
+                    .values()
+                    .map(|child| unsafe { child.as_ref().depth() } + 1)
+                    .max()
+            })
+            .unwrap_or(0)
     }
 }
 
@@ -40,7 +270,8 @@ impl<const BASE: usize> Serialize for MerkleTrieNode<BASE> {
     where
         S: Serializer,
     {
-        let mut state = serializer.serialize_struct("MerkleTrieNode", 3)?;
+        let fields = 3 + (self.count > 1) as usize;
+        let mut state = serializer.serialize_struct("MerkleTrieNode", fields)?;
 
         // Serialize the hash
         state.serialize_field("hash", &self.hash)?;
@@ -48,36 +279,45 @@ impl<const BASE: usize> Serialize for MerkleTrieNode<BASE> {
         // Serialize the stored flag
         state.serialize_field("stored", &self.stored)?;
 
-        // Serialize the children recursively
-        if let Some(children) = &self.children {
-            let mut serialized_children = BTreeMap::new();
-            unsafe {
-                for (k, v) in children {
-                    let boxed_node = Box::new(v.as_ref());
-                    serialized_children.insert(*k, boxed_node);
-                }
-            }
-            state.serialize_field("children", &serialized_children)?;
-        } else {
-            state.serialize_field(
-                "children",
-                &None::<BTreeMap<usize, Box<MerkleTrieNode<BASE>>>>,
-            )?;
+        // Serialize the timestamp count, only for a bucket of several
+        if self.count > 1 {
+            state.serialize_field("count", &self.count)?;
         }
 
+        // Serialize the children recursively, straight from the child map
+        state.serialize_field("children", &self.children.as_ref().map(Children))?;
+
         state.end()
     }
 }
 
+/// Serializes a child map in place, as a map of key to node
+struct Children<'a, const BASE: usize>(&'a BTreeMap<usize, NonNull<MerkleTrieNode<BASE>>>);
+
+impl<const BASE: usize> Serialize for Children<'_, BASE> {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.collect_map(
+            self.0
+                .iter()
+                .map(|(k, child)| (k, unsafe { child.as_ref() })),
+        )
+    }
+}
+
 impl<'de, const BASE: usize> Deserialize<'de> for MerkleTrieNode<BASE> {
     fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
     where
         D: Deserializer<'de>,
     {
         #[derive(Deserialize)]
-        struct NodeData<const BASE: usize = 3> {
+        struct NodeData<const BASE: usize = DEFAULT_BASE> {
             hash: u64,
             stored: bool,
+            #[serde(default)]
+            count: Option<u64>,
             children: Option<BTreeMap<usize, Box<MerkleTrieNode<BASE>>>>,
         }
 
@@ -95,38 +335,116 @@ impl<'de, const BASE: usize> Deserialize<'de> for MerkleTrieNode<BASE> {
             children = Some(new_map);
         }
 
+        // A stored bucket without a count holds a single timestamp
+        let count = match node_data.stored {
+            true => node_data.count.unwrap_or(1),
+            false => 0,
+        };
+
         Ok(MerkleTrieNode {
             hash: node_data.hash,
             stored: node_data.stored,
+            count,
             children,
         })
     }
 }
 
-#[derive(Debug, Clone)]
-pub struct MerkleTrie<const BASE: usize = 3> {
+/// The shape of a trie, see [`MerkleTrie::stats`]. A high base shows as few
+/// children per internal node, i.e. sparse child maps, a low one as deep
+/// stored buckets.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct TrieStats {
+    /// All nodes, including the root
+    pub node_count: usize,
+    /// The stored buckets
+    pub stored_count: usize,
+    /// The nodes having children
+    pub internal_count: usize,
+    /// The length of the longest path from the root to a leaf
+    pub max_depth: usize,
+    /// The average length of the path from the root to a stored bucket
+    pub avg_depth: f64,
+    /// The average number of children of an internal node, at most the base
+    pub avg_children: f64,
+}
+
+/// The buckets before `before` ms keep only the first `len` significant
+/// digits of their keys, see [`MerkleTrie::with_max_key_len`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct KeyLenCap {
+    pub len: usize,
+    pub before: i64,
+}
+
+/// How two tries compare, see [`MerkleTrie::diff_detailed`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DiffResult {
+    /// Both tries hold the same timestamps, and some at least
+    Identical,
+    /// The tries differ from this time on. When only one of them holds
+    /// anything they have no common history, which is reported as `0`.
+    Divergent(i64),
+    /// Neither trie holds any timestamp
+    BothEmpty,
+}
+
+#[derive(Debug)]
+pub struct MerkleTrie<const BASE: usize = DEFAULT_BASE> {
     /// The root of this trie
     root: NonNull<MerkleTrieNode<BASE>>,
 
     /// The size of the trie
     length: u64,
+
+    /// The width of a time bucket in ms, every leaf of the trie covers one
+    /// bucket
+    bucket_ms: u64,
+
+    /// How the keys of old buckets are coarsened, if they are
+    max_key_len: Option<KeyLenCap>,
 }
 
+// SAFETY: the same ownership as for the nodes, the root is only mutated
+// through `&mut MerkleTrie` and `Clone` copies the nodes deeply. Methods
+// taking `&self` must never write through a node pointer.
 unsafe impl<const BASE: usize> Send for MerkleTrie<BASE> {}
 
 unsafe impl<const BASE: usize> Sync for MerkleTrie<BASE> {}
 
+impl<const BASE: usize> Clone for MerkleTrie<BASE> {
+    fn clone(&self) -> Self {
+        let root = unsafe { self.root.as_ref().clone() };
+
+        Self {
+            root: NonNull::new(Box::into_raw(Box::new(root))).unwrap(),
+            length: self.length,
+            bucket_ms: self.bucket_ms,
+            max_key_len: self.max_key_len,
+        }
+    }
+}
+
+impl<const BASE: usize> Drop for MerkleTrie<BASE> {
+    fn drop(&mut self) {
+        unsafe { drop(Box::from_raw(self.root.as_ptr())) };
+    }
+}
+
 impl<const BASE: usize> Default for MerkleTrie<BASE> {
     fn default() -> Self {
         let m = MerkleTrieNode {
             children: None,
             hash: 0,
             stored: false,
+            count: 0,
         };
 
         Self {
             root: NonNull::new(Box::leak(Box::new(m))).unwrap(),
             length: 0,
+            bucket_ms: DEFAULT_BUCKET_MS,
+            max_key_len: None,
         }
     }
 }
@@ -136,20 +454,114 @@ impl<const BASE: usize> MerkleTrie<BASE> {
         Default::default()
     }
 
+    /// Build a trie from scratch out of all the timestamps it covers
+    pub fn from_timestamps<'a>(timestamps: impl IntoIterator<Item = &'a Timestamp>) -> Self {
+        let mut trie = Self::new();
+        for timestamp in timestamps {
+            trie.insert(timestamp);
+        }
+        trie
+    }
+
+    /// Group timestamps into buckets of `bucket_ms` milliseconds (e.g.
+    /// `60000` for one leaf per minute), which bounds the depth of the trie
+    /// at the cost of a coarser diff time. Peers syncing together must use
+    /// the same bucket width.
+    pub fn with_bucket_ms(mut self, bucket_ms: u64) -> Self {
+        assert!(bucket_ms > 0, "bucket_ms must be positive");
+        self.bucket_ms = bucket_ms;
+        self
+    }
+
+    /// The time bucket granularity of this trie, in ms
+    pub fn bucket_ms(&self) -> u64 {
+        self.bucket_ms
+    }
+
+    /// Coarsen the buckets before `before` ms, keeping the recent ones at
+    /// full resolution: the key of an older time keeps its first `len`
+    /// digits and the others are zeroed, so it is the key of the coarse
+    /// bucket of `BASE^(key length - len)` buckets it falls into. The
+    /// coarse bucket keys are real times, its start, so a diff in old
+    /// history reports where the coarse bucket begins.
+    ///
+    /// Keys still have their full length, the depth of a path doesn't
+    /// shrink, but old history shares its nodes, a single chain of zero
+    /// digits below each coarse bucket, so the size of the trie stops
+    /// growing with it. Peers syncing together must use the same cap and
+    /// horizon, like the same bucket width.
+    pub fn with_max_key_len(mut self, len: usize, before: i64) -> Self {
+        assert!(len > 0, "max_key_len must be positive");
+        self.max_key_len = Some(KeyLenCap { len, before });
+        self
+    }
+
+    /// How old buckets are coarsened, `None` when they aren't
+    pub fn max_key_len(&self) -> Option<KeyLenCap> {
+        self.max_key_len
+    }
+
     pub fn root_hash(&self) -> u64 {
-        unsafe { (*self.root.as_ptr()).hash }
+        unsafe { self.root.as_ref().hash }
     }
 
+    /// Whether no bucket is stored, and the root has no hash left either
     pub fn is_empty(&self) -> bool {
-        self.length == 0
+        let root = unsafe { self.root.as_ref() };
+        root.is_empty() && root.hash == 0
     }
 
-    #[allow(clippy::only_used_in_recursion)]
+    /// Whether the bucket of the timestamp is stored. With buckets wider than
+    /// a millisecond, any timestamp of a stored bucket is reported.
+    pub fn contains(&self, timestamp: &Timestamp) -> bool {
+        let Ok(key) = self.timestamp_to_key(timestamp) else {
+            return false;
+        };
+
+        self.node_at(&key).is_some_and(|node| node.stored)
+    }
+
+    /// The XOR of the hashes of the timestamps in the bucket of `millis`,
+    /// `None` if the bucket isn't stored. Unlike a diff, which only tells
+    /// where two tries differ, it tells whether a bucket changed since.
+    pub fn bucket_hash(&self, millis: i64) -> Option<u64> {
+        if millis < 0 {
+            return None;
+        }
+        let node = self.node_at(&self.millis_to_key(millis))?;
+
+        node.stored.then(|| node.hash ^ node.children_hash())
+    }
+
+    /// The node at `key`, if there is one
+    fn node_at(&self, key: &[usize]) -> Option<&MerkleTrieNode<BASE>> {
+        let mut node = unsafe { self.root.as_ref() };
+        for child_key in key {
+            node = unsafe { node.children.as_ref()?.get(child_key)?.as_ref() };
+        }
+
+        Some(node)
+    }
+
+    /// Insert a timestamp, see [`MerkleTrie::try_insert`].
+    ///
+    /// # Panics
+    ///
+    /// If the timestamp is before 1970. Parsed timestamps never are, they
+    /// fail to parse instead.
     pub fn insert(&mut self, timestamp: &Timestamp) {
+        if let Err(e) = self.try_insert(timestamp) {
+            panic!("{}", e);
+        }
+    }
+
+    /// Insert a timestamp, failing for a time before 1970 which has no key
+    pub fn try_insert(&mut self, timestamp: &Timestamp) -> Result<(), TimestampError> {
         let hash = timestamp.hash();
 
         // Convert the timestamp's logical time (i.e., its "milliseconds since
-        // 1970") to minutes, then convert that to a BASE STRING.
+        // 1970") to buckets of `bucket_ms` (a single millisecond by default),
+        // then convert that to a BASE STRING.
         // For example, Base 3 meaning:
         // 0 => '0', 1 => '1', 2 => '2', 3 => '10', 2938 => '11000211'.
         //
@@ -167,9 +579,37 @@ impl<const BASE: usize> MerkleTrie<BASE> {
         // bitwise operators only work on 32-bit integers, so it causes the 64-bit
         // float to be converted to an integer). For example, this causes:
         // "1211121022121110.11221000121012222" to become "1211121022121110".
-        let key = self.timestamp_to_key(timestamp);
+        let key = self.timestamp_to_key(timestamp)?;
+        self.insert_key(&key, hash);
+
+        Ok(())
+    }
+
+    /// Insert a precomputed `hash` at the bucket of `millis`, e.g. to rebuild
+    /// a trie from stored hashes without parsing the timestamps again. With
+    /// the [`Timestamp::hash`] of a timestamp this is the same as inserting
+    /// the timestamp.
+    ///
+    /// # Panics
+    ///
+    /// If `millis` is before 1970.
+    pub fn insert_millis(&mut self, millis: i64, hash: u64) {
+        if millis < 0 {
+            panic!("{}", TimestampError::BeforeEpoch(millis));
+        }
+
+        let key = self.millis_to_key(millis);
+        self.insert_key(&key, hash);
+    }
+
+    fn insert_key(&mut self, key: &[usize], hash: u64) {
+        self.insert_bucket(key, hash, 1);
+    }
 
-        // Create a new object that has the same tree and a NEW root hash. Note that
+    /// Insert the XOR `hash` of `count` timestamps at the bucket `key`
+    fn insert_bucket(&mut self, key: &[usize], hash: u64, count: u64) {
+        // Walk down the path and update the hash of every node on the way
+        // (creating the missing ones), starting from the root. Note that
         // "bitwise hashing" is being used here to make a new hash. Bitwise XOR
         // treats both operands as a sequence of 32 bits. It returns a new sequence
         // of 32 bits where each bit is the result of combining the corresponding
@@ -177,292 +617,950 @@ impl<const BASE: usize> MerkleTrie<BASE> {
         // returns a 1 in each bit position for which the corresponding bits of
         // either but not both operands are 1s.
         unsafe {
-            let root_node = self.root.as_ptr();
-            (*root_node).hash ^= hash;
+            let mut node = self.root.as_mut();
+            node.hash ^= hash;
+
+            for child_key in key {
+                let child = node
+                    .children
+                    .get_or_insert_with(BTreeMap::new)
+                    .entry(*child_key)
+                    .or_insert_with(|| NonNull::new(Box::into_raw(Box::default())).unwrap());
+                node = child.as_mut();
+                node.hash ^= hash;
+            }
 
-            let new_root = self.insert_key(root_node.as_mut().unwrap(), &key, hash);
-            self.root = NonNull::new(Box::leak(Box::new(new_root))).unwrap();
+            // The last node of the path stores the data
+            if !key.is_empty() {
+                node.stored = true;
+                node.count += count;
+            }
         }
 
-        self.length += 1;
+        self.length += count;
     }
 
-    fn insert_key(
-        &mut self,
-        current_trie: &MerkleTrieNode<BASE>,
-        key: &[usize],
-        timestamp_hash: u64,
-    ) -> MerkleTrieNode<BASE> {
-        if key.is_empty() {
-            return current_trie.clone();
+    /// Merge another trie into this one, so that it holds the union of the
+    /// timestamps of both tries.
+    ///
+    /// A bucket only keeps the XOR of its timestamps' hashes, so a bucket
+    /// stored on both sides with different content can't be merged, and
+    /// this fails leaving the trie untouched. Tries holding some of the same
+    /// timestamps merge fine as long as they have whole buckets in common.
+    pub fn merge(&mut self, other: &MerkleTrie<BASE>) -> anyhow::Result<()> {
+        if self.bucket_ms != other.bucket_ms {
+            bail!(
+                "Mismatched bucket width, got: {}, expected: {}",
+                other.bucket_ms,
+                self.bucket_ms
+            );
         }
-
-        let child_key = key[0];
-
-        let curr_child = current_trie
-            .children
-            .as_ref()
-            .and_then(|map| map.get(&child_key))
-            .map_or_else(
-                || MerkleTrieNode {
-                    children: None,
-                    hash: 0,
-                    stored: key.len() == 1,
-                },
-                |child_ptr| unsafe { child_ptr.as_ref().clone() },
+        if self.max_key_len != other.max_key_len {
+            bail!(
+                "Mismatched key length cap, got: {:?}, expected: {:?}",
+                other.max_key_len,
+                self.max_key_len
             );
+        }
+        if other.is_empty() || self.root_hash() == other.root_hash() {
+            return Ok(());
+        }
 
-        let new_child = MerkleTrieNode {
-            children: self
-                .insert_key(&curr_child, &key[1..], timestamp_hash)
-                .children,
-            hash: curr_child.hash ^ timestamp_hash,
-            stored: curr_child.stored || key.len() == 1,
-        };
-
-        let mut new_children = current_trie.children.clone().unwrap_or_default();
-        new_children.insert(
-            child_key,
-            NonNull::new(Box::into_raw(Box::new(new_child))).unwrap(),
-        );
+        unsafe {
+            let conflict =
+                Self::merge_conflict(self.root.as_ref(), other.root.as_ref(), &mut vec![]);
+            if let Some(key) = conflict {
+                bail!("Conflicting bucket: {}", self.key_to_timestamp_millis(key));
+            }
 
-        MerkleTrieNode {
-            children: Some(new_children),
-            hash: current_trie.hash,
-            stored: key.len() == 1,
+            self.length += Self::merge_node(self.root.as_mut(), other.root.as_ref());
         }
+
+        Ok(())
     }
 
-    /// Find the first diff element in the merkle tree
-    pub fn diff(&self, other: &MerkleTrie<BASE>) -> Option<i64> {
-        if self.is_empty() && other.is_empty() {
+    /// Find the key of the first bucket stored on both sides with a
+    /// different content
+    fn merge_conflict(
+        current: &MerkleTrieNode<BASE>,
+        other: &MerkleTrieNode<BASE>,
+        key: &mut Vec<usize>,
+    ) -> Option<Vec<usize>> {
+        if current.hash == other.hash {
             return None;
         }
-        if self.is_empty() || other.is_empty() {
-            return Some(0);
+
+        // The hash a stored node holds for its own bucket
+        if current.stored
+            && other.stored
+            && current.hash ^ current.children_hash() != other.hash ^ other.children_hash()
+        {
+            return Some(key.clone());
         }
-        if other.is_empty() {
-            unsafe { return Some(self.find_first_key_by_prefix(Some(other.root.as_ref()), &[])) }
+
+        let (children, other_children) = (current.children.as_ref()?, other.children.as_ref()?);
+        for (k, child) in children {
+            if let Some(other_child) = other_children.get(k) {
+                key.push(*k);
+                let conflict =
+                    unsafe { Self::merge_conflict(child.as_ref(), other_child.as_ref(), key) };
+                if conflict.is_some() {
+                    return conflict;
+                }
+                key.pop();
+            }
         }
 
-        if self.root_hash() == other.root_hash() {
-            return None;
+        None
+    }
+
+    /// Merge `other` into `current`, returning the number of timestamps
+    /// added
+    fn merge_node(current: &mut MerkleTrieNode<BASE>, other: &MerkleTrieNode<BASE>) -> u64 {
+        if current.hash == other.hash {
+            return 0;
         }
 
-        unsafe {
-            // Find the prefix
-            let mut node1 = Some(self.root.as_ref());
-            let mut node2 = Some(other.root.as_ref());
-            let mut node1_prev_stored = false;
-            let mut node2_prev_stored = false;
-            let mut key_diff_prefix = vec![];
-
-            loop {
-                let key_diff: Option<usize>;
-
-                match (node1, node2) {
-                    (Some(node1), Some(node2)) => {
-                        let mut keyset: Vec<usize> = Vec::new();
-                        node1_prev_stored = node1.stored;
-                        node2_prev_stored = node2.stored;
-
-                        // We reached to the leaf node, stop!
-                        if node1.children.as_ref().map_or(true, |c| c.is_empty())
-                            || node2.children.as_ref().map_or(true, |c| c.is_empty())
-                        {
-                            break;
-                        }
+        let mut added = 0;
+        let own = if current.stored {
+            current.hash ^ current.children_hash()
+        } else if other.stored {
+            current.count = other.count;
+            added += other.count;
+            other.hash ^ other.children_hash()
+        } else {
+            0
+        };
 
-                        node1.children.as_ref().and_then(|children| {
-                            keyset.extend(children.keys());
-                            None::<()>
-                        });
-                        node2.children.as_ref().and_then(|children| {
-                            keyset.extend(children.keys());
-                            None::<()>
-                        });
-                        keyset.sort();
-
-                        key_diff = keyset.into_iter().find(|k| {
-                            match (node1.children.as_ref(), node2.children.as_ref()) {
-                                (Some(children1), Some(children2)) => {
-                                    let child_node1_hash = children1
-                                        .get(k)
-                                        .map(|node| node.as_ref().hash)
-                                        .unwrap_or(0);
-                                    let child_node2_hash = children2
-                                        .get(k)
-                                        .map(|node| node.as_ref().hash)
-                                        .unwrap_or(0);
-                                    child_node1_hash != child_node2_hash
-                                }
-                                (None, None) => false,
-                                _ => true,
-                            };
-                            true
-                        });
-                    }
-                    (Some(_), None) => {
-                        break;
+        if let Some(other_children) = &other.children {
+            let children = current.children.get_or_insert_with(BTreeMap::new);
+            for (k, other_child) in other_children {
+                let other_child = unsafe { other_child.as_ref() };
+                match children.get_mut(k) {
+                    Some(child) => {
+                        added += Self::merge_node(unsafe { child.as_mut() }, other_child);
                     }
-                    (None, Some(_)) => {
-                        break;
-                    }
-                    (None, None) => {
-                        break;
-                    }
-                };
-
-                match key_diff {
                     None => {
-                        break;
-                    }
-                    Some(key_diff) => {
-                        key_diff_prefix.push(key_diff);
-                        node1 = node1.and_then(|node| {
-                            node.children.as_ref().and_then(|children| {
-                                children.get(&key_diff).map(|node| node.as_ref())
-                            })
-                        });
-                        node2 = node2.and_then(|node| {
-                            node.children.as_ref().and_then(|children| {
-                                children.get(&key_diff).map(|node| node.as_ref())
-                            })
-                        });
+                        added += other_child.timestamp_count();
+                        children.insert(
+                            *k,
+                            NonNull::new(Box::into_raw(Box::new(other_child.clone()))).unwrap(),
+                        );
                     }
-                };
-            }
-            assert!(!key_diff_prefix.is_empty());
-
-            // If the path is already a store node, then the minimum key is the prefix key!
-            if node1_prev_stored || node2_prev_stored {
-                return Some(self.key_to_timestamp_millis(key_diff_prefix));
-            }
-            // Continue to find the first diff node that stores the data
-            match (node1, node2) {
-                (Some(node1), None) => {
-                    Some(self.find_first_key_by_prefix(Some(node1), &key_diff_prefix))
-                }
-                (None, Some(node2)) => {
-                    Some(self.find_first_key_by_prefix(Some(node2), &key_diff_prefix))
-                }
-                (None, None) => {
-                    // Only the last node is different!
-                    Some(self.key_to_timestamp_millis(key_diff_prefix))
-                }
-                (Some(node1), Some(node2)) => {
-                    // There can be no circumstances for both not none!
-                    Some(min(
-                        self.find_first_key_by_prefix(Some(node1), &key_diff_prefix),
-                        self.find_first_key_by_prefix(Some(node2), &key_diff_prefix),
-                    ))
                 }
             }
         }
+
+        current.hash = own ^ current.children_hash();
+        current.stored |= other.stored;
+
+        added
+    }
+
+    /// The start time of the earliest bucket whose content differs between
+    /// the tries, `None` if they hold the same timestamps. Against an empty
+    /// trie everything has to be synced, which is reported as `0`.
+    ///
+    /// The other trie usually comes from a remote peer, so a trie that
+    /// doesn't have the structure of a merkle trie is reported as an error.
+    pub fn diff(&self, other: &MerkleTrie<BASE>) -> Result<Option<i64>, DiffError> {
+        Ok(match self.diff_detailed(other)? {
+            DiffResult::Divergent(time) => Some(time),
+            DiffResult::Identical | DiffResult::BothEmpty => None,
+        })
+    }
+
+    /// Like [`MerkleTrie::diff`], but tells two empty tries from two tries
+    /// holding the same timestamps
+    pub fn diff_detailed(&self, other: &MerkleTrie<BASE>) -> Result<DiffResult, DiffError> {
+        if self.bucket_ms != other.bucket_ms {
+            return Err(DiffError::BucketMismatch {
+                got: other.bucket_ms,
+                expected: self.bucket_ms,
+            });
+        }
+        // Both sides are handled the same way below, so the result doesn't
+        // depend on the order of the tries
+        match (self.is_empty(), other.is_empty()) {
+            (true, true) => return Ok(DiffResult::BothEmpty),
+            // Everything has to be synced
+            (true, false) | (false, true) => return Ok(DiffResult::Divergent(0)),
+            (false, false) => {}
+        }
+
+        if self.root_hash() == other.root_hash() {
+            return Ok(DiffResult::Identical);
+        }
+
+        // The root hashes differ, so some bucket must differ too
+        match self.first_diff_key(other, 0)? {
+            Some(key) => Ok(DiffResult::Divergent(
+                self.checked_key_to_timestamp_millis(&key)?,
+            )),
+            None => Err(DiffError::Malformed(
+                "root hashes differ without any differing bucket".to_string(),
+            )),
+        }
     }
 
-    fn find_first_key_by_prefix(
+    /// Like [`MerkleTrie::diff`], but ignores the divergence before
+    /// `since_millis`: only the subtrees that can hold times at or after it
+    /// are compared. Returns the first differing time from the cutoff on,
+    /// e.g. for a node that doesn't keep the older history.
+    pub fn diff_since(
         &self,
-        mut tree: Option<&MerkleTrieNode<{ BASE }>>,
-        key_prefix: &[usize],
-    ) -> i64 {
-        let mut key = Vec::from(key_prefix);
+        other: &MerkleTrie<BASE>,
+        since_millis: i64,
+    ) -> Result<Option<i64>, DiffError> {
+        if self.bucket_ms != other.bucket_ms {
+            return Err(DiffError::BucketMismatch {
+                got: other.bucket_ms,
+                expected: self.bucket_ms,
+            });
+        }
+        let since = (since_millis.max(0) / self.bucket_ms as i64) as u64;
 
-        if tree.is_none() {
-            return i64::MAX;
+        match self.first_diff_key(other, since)? {
+            Some(key) => Ok(Some(self.checked_key_to_timestamp_millis(&key)?)),
+            None => Ok(None),
         }
+    }
 
-        while let Some(node) = tree {
-            if node.stored {
-                return self.key_to_timestamp_millis(key);
+    /// The key of the first bucket at or after the bucket `since` whose
+    /// content differs between the tries. Subtrees with matching hashes are
+    /// skipped, and so are the ones starting after the earliest differing
+    /// bucket found so far: a longer key is a later time, so the subtree of
+    /// a later sibling can still hold an earlier bucket than the deep ones
+    /// of the first differing child.
+    fn first_diff_key(
+        &self,
+        other: &MerkleTrie<BASE>,
+        since: u64,
+    ) -> Result<Option<Vec<usize>>, DiffError> {
+        struct Search {
+            since: u64,
+            max_depth: usize,
+            /// The path to the nodes visited
+            key: Vec<usize>,
+            /// The earliest differing bucket so far, and its key
+            first: Option<(u64, Vec<usize>)>,
+        }
+
+        fn visit<const BASE: usize>(
+            a: Option<&MerkleTrieNode<BASE>>,
+            b: Option<&MerkleTrieNode<BASE>>,
+            value: u64,
+            search: &mut Search,
+        ) -> Result<(), DiffError> {
+            // The hash of a node that doesn't store anything is the one of
+            // its children, unless the trie is malformed
+            for node in [a, b].into_iter().flatten() {
+                if !node.stored && node.hash != node.children_hash() {
+                    return Err(DiffError::Malformed(format!(
+                        "node {:?} doesn't store any data but has its own hash",
+                        search.key
+                    )));
+                }
+            }
+            if search
+                .first
+                .as_ref()
+                .is_some_and(|(first, _)| value >= *first)
+            {
+                return Ok(());
+            }
+            // The last bucket of the subtree is its prefix followed by the
+            // highest digits down to the deepest level
+            if search.since > 0 {
+                let last = (BASE as u64)
+                    .checked_pow((search.max_depth - search.key.len()) as u32)
+                    .and_then(|width| (value + 1).checked_mul(width));
+                if last.is_some_and(|last| last <= search.since) {
+                    return Ok(());
+                }
+            }
+
+            let own = |node: Option<&MerkleTrieNode<BASE>>| {
+                node.filter(|node| node.stored)
+                    .map(|node| node.hash ^ node.children_hash())
             };
+            // Every bucket below is later than this one
+            if value >= search.since && own(a) != own(b) {
+                search.first = Some((value, search.key.clone()));
+                return Ok(());
+            }
+
+            let mut keys = BTreeSet::new();
+            for node in [a, b].into_iter().flatten() {
+                keys.extend(node.children.iter().flat_map(|children| children.keys()));
+            }
+            let child = |node: Option<&MerkleTrieNode<BASE>>, k: &usize| {
+                node.and_then(|node| node.children.as_ref()?.get(k))
+                    .map(|child| unsafe { child.as_ref() })
+            };
+            let hash = |node: Option<&MerkleTrieNode<BASE>>| node.map_or(0, |node| node.hash);
+            for k in keys {
+                let (a, b) = (child(a, &k), child(b, &k));
+                if hash(a) == hash(b) {
+                    continue;
+                }
+                search.key.push(k);
+                match value
+                    .checked_mul(BASE as u64)
+                    .and_then(|value| value.checked_add(k as u64))
+                {
+                    Some(value) => visit(a, b, value, search)?,
+                    // Out of the millisecond range, so later than any bucket
+                    // found already
+                    None if search.first.is_some() => {}
+                    None => {
+                        return Err(KeyOverflow {
+                            key: search.key.clone(),
+                        }
+                        .into())
+                    }
+                }
+                search.key.pop();
+            }
 
-            // Leaf node must be a store node!
-            assert!(node.children.is_some());
+            Ok(())
+        }
 
+        let mut search = Search {
+            since,
+            max_depth: if since > 0 {
+                self.max_depth().max(other.max_depth())
+            } else {
+                0
+            },
+            key: vec![],
+            first: None,
+        };
+        if self.root_hash() != other.root_hash() {
             unsafe {
-                tree = node.children.as_ref().and_then(|children| {
-                    children.first_key_value().map(|kv| {
-                        key.push(*kv.0);
-                        kv.1.as_ref()
-                    })
-                });
-            }
+                visit(
+                    Some(self.root.as_ref()),
+                    Some(other.root.as_ref()),
+                    0,
+                    &mut search,
+                )?
+            };
         }
 
-        self.key_to_timestamp_millis(key)
+        Ok(search.first.map(|(_, key)| key))
+    }
+
+    /// The start time of the bucket of a key, saturating at `i64::MAX` for
+    /// a key out of the millisecond range, e.g. from a malformed remote trie.
+    pub fn key_to_timestamp_millis(&self, key: Vec<usize>) -> i64 {
+        self.checked_key_to_timestamp_millis(&key)
+            .unwrap_or_else(|e| {
+                log::warn!("{}, saturating", e);
+                i64::MAX
+            })
+    }
+
+    /// The start time of the bucket of a key, failing instead of wrapping
+    /// around for a key out of the millisecond range
+    pub fn checked_key_to_timestamp_millis(&self, key: &[usize]) -> Result<i64, KeyOverflow> {
+        let overflow = || KeyOverflow { key: key.to_vec() };
+        // Computed in 64 bits, as a millisecond time doesn't fit into the
+        // usize of 32-bit targets
+        let bucket = key.iter().try_fold(0u64, |current, x| {
+            current.checked_mul(BASE as u64)?.checked_add(*x as u64)
+        });
+
+        bucket
+            .and_then(|bucket| i64::try_from(bucket).ok())
+            .and_then(|bucket| bucket.checked_mul(self.bucket_ms as i64))
+            .ok_or_else(overflow)
+    }
+
+    /// The key of a timestamp as a readable path, e.g. for debugging: a digit
+    /// character per level (`0-9a-z`) up to base 36, and the digits as
+    /// numbers separated by `.` for larger bases.
+    pub fn timestamp_to_key_string(&self, timestamp: &Timestamp) -> Result<String, TimestampError> {
+        let key = self.timestamp_to_key(timestamp)?;
+        if BASE <= 36 {
+            Ok(key
+                .iter()
+                .filter_map(|digit| char::from_digit(*digit as u32, BASE as u32))
+                .collect())
+        } else {
+            Ok(key
+                .iter()
+                .map(ToString::to_string)
+                .collect::<Vec<_>>()
+                .join("."))
+        }
     }
 
-    pub fn key_to_timestamp_millis(&self, mut key: Vec<usize>) -> i64 {
-        let mut base = 1;
-        let mut current = 0;
-        key.reverse();
-        for x in key {
-            current += x * base;
-            base *= BASE;
+    /// The key of the bucket of a timestamp, times before 1970 have none
+    pub fn timestamp_to_key(&self, timestamp: &Timestamp) -> Result<Vec<usize>, TimestampError> {
+        if timestamp.millis() < 0 {
+            return Err(TimestampError::BeforeEpoch(timestamp.millis()));
         }
 
-        current as i64
+        Ok(self.millis_to_key(timestamp.millis()))
     }
 
-    pub fn timestamp_to_key(&self, timestamp: &Timestamp) -> Vec<usize> {
+    fn millis_to_key(&self, millis: i64) -> Vec<usize> {
         let mut v: Vec<usize> = vec![];
-        let mut current = timestamp.millis() as usize;
-        let mut res: usize;
+        // A plain cast would wrap a time before the epoch around
+        let bucket = millis / self.bucket_ms as i64;
+        let mut current = u64::try_from(bucket).unwrap_or_else(|_| {
+            log::warn!("Bucket {} out of the key range, saturating", bucket);
+            0
+        });
+        if let Some(cap) = self.max_key_len.filter(|cap| millis < cap.before) {
+            current = Self::coarsen(current, cap.len);
+        }
+        // Only the digits, smaller than the base, are narrowed to usize
         while current != 0 {
-            res = current % BASE;
-            v.push(res);
-            current /= BASE;
+            v.push((current % BASE as u64) as usize);
+            current /= BASE as u64;
+        }
+        // The first bucket is the single digit 0, like any other bucket it
+        // is a node below the root
+        if v.is_empty() {
+            v.push(0);
         }
         v.reverse();
 
         v
     }
 
+    /// The start of the coarse bucket of `bucket`, which keeps the first
+    /// `len` of its digits
+    fn coarsen(bucket: u64, len: usize) -> u64 {
+        let mut scale = 1u64;
+        let mut rest = bucket;
+        for _ in 0..len {
+            rest /= BASE as u64;
+        }
+        // One scale step per digit past the first `len`
+        while rest != 0 {
+            rest /= BASE as u64;
+            scale *= BASE as u64;
+        }
+
+        bucket - bucket % scale
+    }
+
+    /// The number of timestamps inserted, less those removed. Timestamps
+    /// sharing a bucket count one each, see [`MerkleTrie::stored_count`] for
+    /// the number of buckets.
     pub fn length(&self) -> u64 {
         self.length
     }
 
-    pub fn debug(&self) {
-        self.print_node_recursive(unsafe { &*self.root.as_ptr() }, 0);
+    /// The number of stored buckets, which is what [`MerkleTrie::length`]
+    /// counts once every bucket holds a single timestamp
+    pub fn stored_count(&self) -> u64 {
+        unsafe { self.root.as_ref().stored_count() }
     }
 
-    #[allow(clippy::only_used_in_recursion)]
-    fn print_node_recursive(&self, node: &MerkleTrieNode<BASE>, ident: usize) {
-        println!("{}Node Hash: {}", " ".repeat(ident), node.hash);
+    /// The times of all stored buckets, in ascending order
+    pub fn stored_keys(&self) -> Vec<i64> {
+        let mut keys = self
+            .stored_buckets()
+            .into_iter()
+            .map(|(key, _)| self.key_to_timestamp_millis(key))
+            .collect::<Vec<_>>();
+        keys.sort();
 
-        if let Some(children) = &node.children {
-            let ident = ident + 2;
-            for (key, child_ptr) in children {
-                unsafe {
-                    let child = child_ptr.as_ptr();
-                    println!(
-                        "{}Child Key: {}, Child Hash: {}, Stored: {}",
-                        " ".repeat(ident),
-                        key,
-                        (*child).hash,
-                        (*child).stored
-                    );
-                    self.print_node_recursive(&*child, ident);
-                }
-            }
-        }
+        keys
     }
-}
 
-impl<const BASE: usize> Serialize for MerkleTrie<BASE> {
-    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
-    where
-        S: Serializer,
-    {
-        let mut state = serializer.serialize_struct("MerkleTrie", 2)?;
+    /// The time of the latest stored bucket, e.g. to report when a client
+    /// last synced. Keys have no leading zeros, so the latest bucket is the
+    /// largest key among the deepest stored nodes, which isn't always down
+    /// the largest child at each level.
+    pub fn max_key(&self) -> Option<i64> {
+        /// The depth and value of the latest stored bucket of a subtree
+        fn latest<const BASE: usize>(
+            node: &MerkleTrieNode<BASE>,
+            depth: usize,
+            value: u64,
+        ) -> Option<(usize, u64)> {
+            let below = node.children.iter().flatten().filter_map(|(k, child)| {
+                latest(
+                    unsafe { child.as_ref() },
+                    depth + 1,
+                    value * BASE as u64 + *k as u64,
+                )
+            });
+            below.chain(node.stored.then_some((depth, value))).max()
+        }
 
-        // Serialize the root
-        let root_node = unsafe { self.root.as_ref() };
-        state.serialize_field("root", &root_node)?;
+        let (_, bucket) = latest(unsafe { self.root.as_ref() }, 0, 0)?;
+        Some((bucket as i64).saturating_mul(self.bucket_ms as i64))
+    }
 
-        // Serialize the length
-        state.serialize_field("length", &self.length)?;
+    /// The time of the earliest stored bucket, e.g. for retention decisions:
+    /// the smallest key among the shallowest stored nodes, so the trie is
+    /// walked level by level only down to the first level storing any.
+    pub fn min_key(&self) -> Option<i64> {
+        let mut level = vec![(unsafe { self.root.as_ref() }, 0u64)];
+        while !level.is_empty() {
+            if let Some(bucket) = level
+                .iter()
+                .filter(|(node, _)| node.stored)
+                .map(|(_, value)| *value)
+                .min()
+            {
+                return Some((bucket as i64).saturating_mul(self.bucket_ms as i64));
+            }
+
+            level = level
+                .into_iter()
+                .flat_map(|(node, value)| {
+                    node.children.iter().flatten().map(move |(k, child)| {
+                        (unsafe { child.as_ref() }, value * BASE as u64 + *k as u64)
+                    })
+                })
+                .collect();
+        }
+
+        None
+    }
+
+    /// The times of the stored buckets in `[from, to)`, in ascending order.
+    /// Only the subtrees whose buckets overlap the range are walked.
+    pub fn keys_in_range(&self, from: i64, to: i64) -> Vec<i64> {
+        /// Collect the stored buckets in `[lo, hi)` of the subtree `value`
+        fn collect<const BASE: usize>(
+            node: &MerkleTrieNode<BASE>,
+            value: u64,
+            depth: usize,
+            max_depth: usize,
+            (lo, hi): (u64, u64),
+            buckets: &mut Vec<u64>,
+        ) {
+            // Every bucket below is later than this one, up to the prefix
+            // followed by the highest digits down to the deepest level
+            if value >= hi {
+                return;
+            }
+            let last = (BASE as u64)
+                .checked_pow((max_depth - depth) as u32)
+                .and_then(|width| (value + 1).checked_mul(width));
+            if last.is_some_and(|last| last <= lo) {
+                return;
+            }
+
+            if node.stored && (lo..hi).contains(&value) {
+                buckets.push(value);
+            }
+            if let Some(children) = &node.children {
+                for (k, child) in children {
+                    collect(
+                        unsafe { child.as_ref() },
+                        value * BASE as u64 + *k as u64,
+                        depth + 1,
+                        max_depth,
+                        (lo, hi),
+                        buckets,
+                    );
+                }
+            }
+        }
+
+        // The buckets whose time is in the range
+        let width = self.bucket_ms;
+        let bucket = |millis: i64| (millis.max(0) as u64).div_ceil(width);
+        let (lo, hi) = (bucket(from), bucket(to));
+        if lo >= hi {
+            return vec![];
+        }
+
+        let mut buckets = vec![];
+        collect(
+            unsafe { self.root.as_ref() },
+            0,
+            0,
+            self.max_depth(),
+            (lo, hi),
+            &mut buckets,
+        );
+        buckets.sort();
+
+        buckets
+            .into_iter()
+            .map(|bucket| (bucket as i64).saturating_mul(width as i64))
+            .collect()
+    }
+
+    /// The times of the stored buckets only in this trie and of those only in
+    /// `other`, both in ascending order. Only differing subtrees are walked.
+    ///
+    /// A bucket stored in both tries with different contents, i.e. other
+    /// timestamps in the same bucket, is in both lists. With different
+    /// bucket widths no bucket is comparable, so these are all stored times.
+    pub fn symmetric_difference(&self, other: &MerkleTrie<BASE>) -> (Vec<i64>, Vec<i64>) {
+        /// Collect the differing buckets of the subtrees `a` and `b`
+        fn collect<const BASE: usize>(
+            a: Option<&MerkleTrieNode<BASE>>,
+            b: Option<&MerkleTrieNode<BASE>>,
+            value: u64,
+            only_a: &mut Vec<u64>,
+            only_b: &mut Vec<u64>,
+        ) {
+            let hash = |node: Option<&MerkleTrieNode<BASE>>| node.map_or(0, |node| node.hash);
+            if hash(a) == hash(b) {
+                return;
+            }
+
+            let own = |node: Option<&MerkleTrieNode<BASE>>| {
+                node.filter(|node| node.stored)
+                    .map(|node| node.hash ^ node.children_hash())
+            };
+            let (own_a, own_b) = (own(a), own(b));
+            if own_a != own_b {
+                if own_a.is_some() {
+                    only_a.push(value);
+                }
+                if own_b.is_some() {
+                    only_b.push(value);
+                }
+            }
+
+            let mut keys = BTreeSet::new();
+            for node in [a, b].into_iter().flatten() {
+                keys.extend(node.children.iter().flat_map(|children| children.keys()));
+            }
+            let child = |node: Option<&MerkleTrieNode<BASE>>, k: &usize| {
+                node.and_then(|node| node.children.as_ref()?.get(k))
+                    .map(|child| unsafe { child.as_ref() })
+            };
+            for k in keys {
+                collect(
+                    child(a, &k),
+                    child(b, &k),
+                    value * BASE as u64 + k as u64,
+                    only_a,
+                    only_b,
+                );
+            }
+        }
+
+        if self.bucket_ms != other.bucket_ms {
+            return (self.stored_keys(), other.stored_keys());
+        }
+
+        let (mut only_self, mut only_other) = (vec![], vec![]);
+        unsafe {
+            collect(
+                Some(self.root.as_ref()),
+                Some(other.root.as_ref()),
+                0,
+                &mut only_self,
+                &mut only_other,
+            );
+        }
+
+        let width = self.bucket_ms as i64;
+        let times = |mut buckets: Vec<u64>| {
+            buckets.sort();
+            buckets
+                .into_iter()
+                .map(|bucket| (bucket as i64).saturating_mul(width))
+                .collect()
+        };
+        (times(only_self), times(only_other))
+    }
+
+    /// The key and own hash of every stored node
+    fn stored_buckets(&self) -> Vec<(Vec<usize>, u64)> {
+        self.counted_buckets()
+            .into_iter()
+            .map(|(key, hash, _)| (key, hash))
+            .collect()
+    }
+
+    /// The key, own hash and timestamp count of every stored node
+    fn counted_buckets(&self) -> Vec<(Vec<usize>, u64, u64)> {
+        fn collect<const BASE: usize>(
+            node: &MerkleTrieNode<BASE>,
+            key: &mut Vec<usize>,
+            buckets: &mut Vec<(Vec<usize>, u64, u64)>,
+        ) {
+            if node.stored {
+                let hash = node.hash ^ node.children_hash();
+                buckets.push((key.clone(), hash, node.count));
+            }
+            if let Some(children) = &node.children {
+                for (k, child) in children {
+                    key.push(*k);
+                    collect(unsafe { child.as_ref() }, key, buckets);
+                    key.pop();
+                }
+            }
+        }
+
+        let mut buckets = vec![];
+        collect(unsafe { self.root.as_ref() }, &mut vec![], &mut buckets);
+
+        buckets
+    }
+
+    /// Convert this trie into a trie of another base, keeping the stored
+    /// buckets, their hashes, the bucket width and the key length cap. With
+    /// a cap, the coarse buckets are coarsened again in the new base.
+    pub fn rebase<const NEW: usize>(&self) -> MerkleTrie<NEW> {
+        let mut trie = MerkleTrie::<NEW>::new().with_bucket_ms(self.bucket_ms);
+        trie.max_key_len = self.max_key_len;
+        for (key, hash, count) in self.counted_buckets() {
+            let key = trie.millis_to_key(self.key_to_timestamp_millis(key));
+            trie.insert_bucket(&key, hash, count);
+        }
+        trie.length = self.length;
+
+        trie
+    }
+
+    /// Check that the hash of every node not storing a bucket is the XOR of
+    /// its children's hashes, e.g. for a trie received from a peer before
+    /// diffing against it. The deepest inconsistent node is reported first.
+    ///
+    /// A stored node also holds the hash of its own bucket, which can't be
+    /// recomputed from the trie alone.
+    pub fn verify(&self) -> Result<(), InconsistencyReport> {
+        fn verify_node<const BASE: usize>(
+            node: &MerkleTrieNode<BASE>,
+            key: &mut Vec<usize>,
+        ) -> Result<(), InconsistencyReport> {
+            if let Some(children) = &node.children {
+                for (k, child) in children {
+                    key.push(*k);
+                    verify_node(unsafe { child.as_ref() }, key)?;
+                    key.pop();
+                }
+            }
+
+            let expected = node.children_hash();
+            if !node.stored && node.hash != expected {
+                return Err(InconsistencyReport {
+                    key: key.clone(),
+                    expected,
+                    found: node.hash,
+                });
+            }
+            Ok(())
+        }
+
+        verify_node(unsafe { self.root.as_ref() }, &mut vec![])
+    }
+
+    /// Iterate the times of all stored buckets in ascending order, like
+    /// [`MerkleTrie::stored_keys`] without collecting them
+    pub fn iter(&self) -> Iter<'_, BASE> {
+        self.into_iter()
+    }
+
+    /// Remove every timestamp from the trie, freeing all of its nodes. The
+    /// bucket width and key length cap are kept.
+    pub fn clear(&mut self) {
+        let mut trie = Self::new().with_bucket_ms(self.bucket_ms);
+        trie.max_key_len = self.max_key_len;
+        *self = trie;
+    }
+
+    /// Drop the stored buckets before `millis`, e.g. to keep a retention
+    /// window instead of every bucket forever. Their hashes are removed
+    /// from the path up to the root, and the length shrinks by the number of
+    /// timestamps they held.
+    ///
+    /// This changes the root hash: peers only stay comparable if they all
+    /// prune with the same cutoff, otherwise they diff at the pruned buckets.
+    pub fn prune_before(&mut self, millis: i64) {
+        let since = (millis.max(0) / self.bucket_ms as i64) as u64;
+        let (_, removed) = unsafe { self.root.as_mut().prune_before(0, since) };
+        self.length = self.length.saturating_sub(removed);
+    }
+
+    /// Remove a timestamp inserted before, e.g. of a message deleted for
+    /// good: its hash is removed from the path up to the root and the length
+    /// shrinks by one. A bucket shared with other timestamps stays stored
+    /// until the last of them is removed. Returns false, changing nothing,
+    /// when the bucket of the timestamp isn't stored.
+    ///
+    /// Only the bucket is checked, removing a timestamp that was never
+    /// inserted into a stored bucket leaves it with a wrong hash.
+    pub fn remove(&mut self, timestamp: &Timestamp) -> bool {
+        let Ok(key) = self.timestamp_to_key(timestamp) else {
+            return false;
+        };
+        if !unsafe { self.root.as_mut().remove(&key, timestamp.hash()) } {
+            return false;
+        }
+        self.length = self.length.saturating_sub(1);
+
+        true
+    }
+
+    /// The number of allocated nodes in the trie, including the root
+    pub fn node_count(&self) -> usize {
+        unsafe { self.root.as_ref().node_count() }
+    }
+
+    /// Node counts, depths and branching of the trie, in a single walk
+    pub fn stats(&self) -> TrieStats {
+        let mut stats = TrieStats::default();
+        let mut depth_sum = 0;
+        unsafe {
+            self.root
+                .as_ref()
+                .collect_stats(0, &mut stats, &mut depth_sum)
+        };
+
+        if stats.stored_count > 0 {
+            stats.avg_depth = depth_sum as f64 / stats.stored_count as f64;
+        }
+        if stats.internal_count > 0 {
+            // Every node but the root is the child of an internal node
+            stats.avg_children = (stats.node_count - 1) as f64 / stats.internal_count as f64;
+        }
+
+        stats
+    }
+
+    /// The length of the longest path from the root to a leaf
+    pub fn max_depth(&self) -> usize {
+        unsafe { self.root.as_ref().depth() }
+    }
+
+    /// Render this trie as a Graphviz DOT digraph, one node per trie node
+    /// labelled with its key digit and hash. Stored buckets are drawn as
+    /// double circles.
+    pub fn to_dot(&self) -> String {
+        unsafe { Self::render_dot(Some(self.root.as_ref()), None) }
+    }
+
+    /// Render this trie and `other` overlaid as a Graphviz DOT digraph, to
+    /// see where two tries of a stuck sync fork. A node whose hash differs
+    /// between the tries, or which is in one of them only, is red. Labels
+    /// carry the hashes of both tries, `-` where a trie lacks the node.
+    pub fn diff_dot(&self, other: &MerkleTrie<BASE>) -> String {
+        unsafe { Self::render_dot(Some(self.root.as_ref()), Some(other.root.as_ref())) }
+    }
+
+    /// The DOT digraph of the subtree `a`, overlaid with `b` if there is one
+    fn render_dot(a: Option<&MerkleTrieNode<BASE>>, b: Option<&MerkleTrieNode<BASE>>) -> String {
+        fn render<const BASE: usize>(
+            a: Option<&MerkleTrieNode<BASE>>,
+            b: Option<Option<&MerkleTrieNode<BASE>>>,
+            id: &str,
+            label: &str,
+            out: &mut String,
+        ) {
+            let hash = |node: Option<&MerkleTrieNode<BASE>>| {
+                node.map_or(String::from("-"), |node| format!("{:x}", node.hash))
+            };
+            let stored =
+                a.is_some_and(|node| node.stored) || b.flatten().is_some_and(|node| node.stored);
+            let mut attrs = match b {
+                Some(b) => format!("label=\"{}\\n{} / {}\"", label, hash(a), hash(b)),
+                None => format!("label=\"{}\\n{}\"", label, hash(a)),
+            };
+            if stored {
+                attrs.push_str(", shape=doublecircle");
+            }
+            if b.is_some_and(|b| a.map(|node| node.hash) != b.map(|node| node.hash)) {
+                attrs.push_str(", color=red, fontcolor=red");
+            }
+            let _ = writeln!(out, "  {} [{}];", id, attrs);
+
+            let mut keys = BTreeSet::new();
+            for node in [a, b.flatten()].into_iter().flatten() {
+                keys.extend(node.children.iter().flat_map(|children| children.keys()));
+            }
+            let child = |node: Option<&MerkleTrieNode<BASE>>, k: &usize| {
+                node.and_then(|node| node.children.as_ref()?.get(k))
+                    .map(|child| unsafe { child.as_ref() })
+            };
+            for k in keys {
+                let child_id = format!("{}_{}", id, k);
+                let _ = writeln!(out, "  {} -> {};", id, child_id);
+                render(
+                    child(a, &k),
+                    b.map(|b| child(b, &k)),
+                    &child_id,
+                    &k.to_string(),
+                    out,
+                );
+            }
+        }
+
+        let mut out = String::from("digraph merkle {\n");
+        render(a, b.map(Some), "n", "root", &mut out);
+        out.push_str("}\n");
+
+        out
+    }
+
+    #[cfg(feature = "std")]
+    pub fn debug(&self) {
+        self.print_node_recursive(unsafe { self.root.as_ref() }, 0);
+    }
+
+    #[cfg(feature = "std")]
+    #[allow(clippy::only_used_in_recursion)]
+    fn print_node_recursive(&self, node: &MerkleTrieNode<BASE>, ident: usize) {
+        println!("{}Node Hash: {}", " ".repeat(ident), node.hash);
+
+        if let Some(children) = &node.children {
+            let ident = ident + 2;
+            for (key, child_ptr) in children {
+                let child = unsafe { child_ptr.as_ref() };
+                println!(
+                    "{}Child Key: {}, Child Hash: {}, Stored: {}",
+                    " ".repeat(ident),
+                    key,
+                    child.hash,
+                    child.stored
+                );
+                self.print_node_recursive(child, ident);
+            }
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl<const BASE: usize> MerkleTrie<BASE> {
+    /// Write this trie as JSON to `writer`, the nodes pre-order as they are
+    /// walked. Nothing but the output of `writer` is buffered, so the peak
+    /// memory stays at the size of the trie itself, where `to_string` holds
+    /// the whole JSON too.
+    ///
+    /// Read it back with `serde_json::from_reader` or `from_slice`.
+    pub fn write_to<W: std::io::Write>(&self, writer: W) -> serde_json::Result<()> {
+        serde_json::to_writer(writer, self)
+    }
+}
+
+impl<const BASE: usize> Serialize for MerkleTrie<BASE> {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let fields = 3 + self.max_key_len.is_some() as usize;
+        let mut state = serializer.serialize_struct("MerkleTrie", fields)?;
+
+        // Serialize the root
+        let root_node = unsafe { self.root.as_ref() };
+        state.serialize_field("root", &root_node)?;
+
+        // Serialize the length
+        state.serialize_field("length", &self.length)?;
+
+        // Serialize the bucket width
+        state.serialize_field("bucket_ms", &self.bucket_ms)?;
+
+        // Serialize the key length cap, if any
+        if let Some(cap) = &self.max_key_len {
+            state.serialize_field("max_key_len", cap)?;
+        }
 
         state.end()
     }
@@ -474,9 +1572,17 @@ impl<'de, const BASE: usize> Deserialize<'de> for MerkleTrie<BASE> {
         D: Deserializer<'de>,
     {
         #[derive(Deserialize)]
-        struct TrieData<const BASE: usize = 3> {
+        struct TrieData<const BASE: usize = DEFAULT_BASE> {
             root: Box<MerkleTrieNode<BASE>>,
             length: u64,
+            #[serde(default = "default_bucket_ms")]
+            bucket_ms: u64,
+            #[serde(default)]
+            max_key_len: Option<KeyLenCap>,
+        }
+
+        fn default_bucket_ms() -> u64 {
+            DEFAULT_BUCKET_MS
         }
 
         let trie_data = TrieData::deserialize(deserializer)?;
@@ -487,21 +1593,218 @@ impl<'de, const BASE: usize> Deserialize<'de> for MerkleTrie<BASE> {
         Ok(MerkleTrie {
             root,
             length: trie_data.length,
+            bucket_ms: trie_data.bucket_ms,
+            max_key_len: trie_data.max_key_len,
         })
     }
 }
 
+/// A DFS over the stored nodes of a trie. A longer key is always a later
+/// time, as keys have no leading zeros, so the trie is walked once per depth
+/// holding stored nodes, each walk only descending down to that depth.
+struct Traversal<const BASE: usize> {
+    root: NonNull<MerkleTrieNode<BASE>>,
+    bucket_ms: u64,
+    /// The depths still to walk
+    depths: btree_set::IntoIter<usize>,
+    /// The depth of the current walk
+    depth: usize,
+    /// The nodes to visit with their depth and key value
+    stack: Vec<(NonNull<MerkleTrieNode<BASE>>, usize, u64)>,
+}
+
+impl<const BASE: usize> Traversal<BASE> {
+    fn new(trie: &MerkleTrie<BASE>) -> Self {
+        let mut depths = BTreeSet::new();
+        unsafe { trie.root.as_ref().stored_depths(0, &mut depths) };
+
+        Self {
+            root: trie.root,
+            bucket_ms: trie.bucket_ms,
+            depths: depths.into_iter(),
+            depth: 0,
+            stack: vec![],
+        }
+    }
+
+    /// Safety: the trie the traversal was created from must still be alive
+    /// and unchanged
+    unsafe fn next(&mut self) -> Option<i64> {
+        loop {
+            while let Some((node, depth, value)) = self.stack.pop() {
+                let node = unsafe { node.as_ref() };
+                if depth == self.depth {
+                    if node.stored {
+                        return Some(value as i64 * self.bucket_ms as i64);
+                    }
+                    continue;
+                }
+                if let Some(children) = &node.children {
+                    // Reversed, so the smallest key is popped first
+                    for (k, child) in children.iter().rev() {
+                        self.stack
+                            .push((*child, depth + 1, value * BASE as u64 + *k as u64));
+                    }
+                }
+            }
+
+            self.depth = self.depths.next()?;
+            self.stack.push((self.root, 0, 0));
+        }
+    }
+}
+
+/// Borrowing iterator over the stored times of a trie, see
+/// [`MerkleTrie::iter`]
+pub struct Iter<'a, const BASE: usize> {
+    traversal: Traversal<BASE>,
+    _trie: PhantomData<&'a MerkleTrie<BASE>>,
+}
+
+impl<const BASE: usize> Iterator for Iter<'_, BASE> {
+    type Item = i64;
+
+    fn next(&mut self) -> Option<i64> {
+        // The trie is borrowed for the lifetime of the iterator
+        unsafe { self.traversal.next() }
+    }
+}
+
+impl<'a, const BASE: usize> IntoIterator for &'a MerkleTrie<BASE> {
+    type Item = i64;
+    type IntoIter = Iter<'a, BASE>;
+
+    fn into_iter(self) -> Iter<'a, BASE> {
+        Iter {
+            traversal: Traversal::new(self),
+            _trie: PhantomData,
+        }
+    }
+}
+
+/// Owning iterator over the stored times of a trie
+pub struct IntoIter<const BASE: usize> {
+    traversal: Traversal<BASE>,
+    /// Keeps the nodes alive, they don't move along with the trie
+    _trie: MerkleTrie<BASE>,
+}
+
+impl<const BASE: usize> Iterator for IntoIter<BASE> {
+    type Item = i64;
+
+    fn next(&mut self) -> Option<i64> {
+        // The trie is owned by the iterator
+        unsafe { self.traversal.next() }
+    }
+}
+
+impl<const BASE: usize> IntoIterator for MerkleTrie<BASE> {
+    type Item = i64;
+    type IntoIter = IntoIter<BASE>;
+
+    fn into_iter(self) -> IntoIter<BASE> {
+        IntoIter {
+            traversal: Traversal::new(&self),
+            _trie: self,
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
-    use crate::merkle::MerkleTrie;
+    use alloc::collections::{BTreeMap, BTreeSet};
+
+    use proptest::prelude::*;
+
+    use crate::error::{DiffError, KeyOverflow, TimestampError};
+    use crate::merkle::{DiffResult, MerkleTrie, TrieStats};
     use crate::timestamp::Timestamp;
 
+    /// Assert that the diff doesn't depend on the order of the tries
+    fn assert_diff_symmetric<const BASE: usize>(
+        m1: &MerkleTrie<BASE>,
+        m2: &MerkleTrie<BASE>,
+    ) -> Option<i64> {
+        let diff = m1.diff(m2).unwrap();
+        assert_eq!(diff, m2.diff(m1).unwrap(), "diff is not commutative");
+        diff
+    }
+
+    /// A small xorshift generator, so the random tests are reproducible
+    struct Rng(u64);
+
+    impl Rng {
+        fn next(&mut self) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0
+        }
+    }
+
+    fn random_tries<const BASE: usize>(seed: u64) -> (MerkleTrie<BASE>, MerkleTrie<BASE>) {
+        let mut rng = Rng(seed.wrapping_mul(0x9E3779B97F4A7C15) | 1);
+        let mut m1 = MerkleTrie::new();
+        let mut m2 = MerkleTrie::new();
+        for _ in 0..rng.next() % 20 {
+            let t = Timestamp::new(
+                1712898800000 + (rng.next() % 100000) as i64,
+                (rng.next() % 3) as usize,
+                format!("node{}", rng.next() % 3),
+            );
+            // Shared, only in the first trie, or only in the second one
+            match rng.next() % 3 {
+                0 => {
+                    m1.insert(&t);
+                    m2.insert(&t);
+                }
+                1 => m1.insert(&t),
+                _ => m2.insert(&t),
+            }
+        }
+
+        (m1, m2)
+    }
+
     #[test]
     fn debug_test() {
         let m: MerkleTrie<3> = MerkleTrie::new();
         m.debug();
     }
 
+    #[test]
+    fn diff_dot_test() {
+        let t = |millis: i64| Timestamp::new(millis, 0, String::from("node"));
+        let mut m1: MerkleTrie<10> = MerkleTrie::new();
+        let mut m2: MerkleTrie<10> = MerkleTrie::new();
+        for millis in [3, 44, 127] {
+            m1.insert(&t(millis));
+            m2.insert(&t(millis));
+        }
+        m1.insert(&t(345));
+
+        let dot = m1.diff_dot(&m2);
+        assert!(dot.starts_with("digraph merkle {\n"));
+        let node = |id: &str| {
+            dot.lines()
+                .find(|line| line.starts_with(&format!("  {} [", id)))
+                .unwrap()
+        };
+        for id in ["n", "n_3", "n_3_4", "n_3_4_5"] {
+            assert!(node(id).contains("color=red"), "{}", id);
+        }
+        for id in ["n_4", "n_4_4", "n_1", "n_1_2", "n_1_2_7"] {
+            assert!(!node(id).contains("color=red"), "{}", id);
+        }
+        assert!(node("n_3_4_5").contains(" / -"));
+        assert!(node("n_3_4_5").contains("shape=doublecircle"));
+        assert!(dot.contains("  n_3_4 -> n_3_4_5;"));
+
+        assert!(!m1.diff_dot(&m1.clone()).contains("color=red"));
+        assert!(!m1.to_dot().contains("color=red"));
+        assert!(!m1.to_dot().contains(" / "));
+    }
+
     #[test]
     fn key_to_timestamp_base3_test() {
         let m: MerkleTrie<3> = MerkleTrie::new();
@@ -512,6 +1815,80 @@ mod tests {
         assert_eq!(cur, 2938);
     }
 
+    #[test]
+    fn key_overflow_test() {
+        let m: MerkleTrie<10> = MerkleTrie::new();
+        // 10^30 doesn't fit into 64 bits
+        let key = [&[1][..], &[0; 30][..]].concat();
+        assert_eq!(
+            m.checked_key_to_timestamp_millis(&key),
+            Err(KeyOverflow { key: key.clone() })
+        );
+        assert_eq!(m.key_to_timestamp_millis(key.clone()), i64::MAX);
+        // Fits into usize but not once multiplied by the bucket width
+        let m: MerkleTrie<10> = MerkleTrie::new().with_bucket_ms(1000);
+        assert!(m.checked_key_to_timestamp_millis(&[1; 19]).is_err());
+        assert_eq!(m.checked_key_to_timestamp_millis(&[1, 2]), Ok(12000));
+        // A time out of the key range saturates instead of wrapping around
+        assert_eq!(m.millis_to_key(-1000), vec![0]);
+
+        // A remote trie with such a key can't be diffed
+        let mut remote: MerkleTrie<10> = MerkleTrie::new();
+        remote.insert_key(&key, 1);
+        let mut local: MerkleTrie<10> = MerkleTrie::new();
+        local.insert_key(&[2], 2);
+        assert!(matches!(
+            remote.diff(&local),
+            Err(DiffError::Overflow(KeyOverflow { .. }))
+        ));
+    }
+
+    #[test]
+    fn realistic_millis_test() {
+        // Beyond 2^32, so it would be truncated in a 32-bit usize
+        let millis = 1712898800831;
+        let mut m: MerkleTrie<3> = MerkleTrie::new();
+        m.insert(&Timestamp::new(millis, 0, String::from("local")));
+
+        let key = m.millis_to_key(millis);
+        assert_eq!(m.checked_key_to_timestamp_millis(&key), Ok(millis));
+        assert_eq!(m.stored_keys(), vec![millis]);
+        assert_eq!(m.iter().collect::<Vec<_>>(), vec![millis]);
+
+        let mut other: MerkleTrie<3> = MerkleTrie::new();
+        other.insert(&Timestamp::new(millis + 1, 0, String::from("local")));
+        assert_eq!(m.diff_since(&other, millis), Ok(Some(millis)));
+    }
+
+    #[test]
+    fn before_epoch_test() {
+        let mut m: MerkleTrie<3> = MerkleTrie::new();
+        m.insert(&Timestamp::new(1712898800831, 0, String::from("local")));
+        let hash = m.root_hash();
+
+        let t = Timestamp::new(-86400000, 0, String::from("local"));
+        assert_eq!(
+            m.timestamp_to_key(&t),
+            Err(TimestampError::BeforeEpoch(-86400000))
+        );
+        assert_eq!(
+            m.try_insert(&t),
+            Err(TimestampError::BeforeEpoch(-86400000))
+        );
+        assert!(!m.contains(&t));
+        // The trie is left untouched
+        assert_eq!(m.root_hash(), hash);
+        assert_eq!(m.length(), 1);
+        assert_eq!(m.stored_keys(), vec![1712898800831]);
+    }
+
+    #[test]
+    #[should_panic(expected = "BeforeEpochError")]
+    fn insert_before_epoch_test() {
+        let mut m: MerkleTrie<3> = MerkleTrie::new();
+        m.insert(&Timestamp::new(-1, 0, String::from("local")));
+    }
+
     #[test]
     fn key_to_timestamp_base10_test() {
         let m: MerkleTrie<10> = MerkleTrie::new();
@@ -519,26 +1896,205 @@ mod tests {
         assert_eq!(cur, 9247);
     }
 
+    #[test]
+    fn insert_millis_test() {
+        let timestamps = [
+            Timestamp::new(1712898800000, 0, String::from("local")),
+            Timestamp::new(1712898800000, 1, String::from("local")),
+            Timestamp::new(1712898859999, 0, String::from("remote")),
+        ];
+
+        let mut m1: MerkleTrie = MerkleTrie::new().with_bucket_ms(60000);
+        let mut m2: MerkleTrie = MerkleTrie::new().with_bucket_ms(60000);
+        for t in &timestamps {
+            m1.insert(t);
+            m2.insert_millis(t.millis(), t.hash());
+        }
+
+        assert_eq!(m1.root_hash(), m2.root_hash());
+        assert_eq!(m1.length(), m2.length());
+        assert_eq!(m1.stored_keys(), m2.stored_keys());
+        assert_eq!(m1.diff(&m2), Ok(None));
+    }
+
+    #[test]
+    #[should_panic(expected = "BeforeEpochError")]
+    fn insert_millis_before_epoch_test() {
+        let mut m: MerkleTrie = MerkleTrie::new();
+        m.insert_millis(-1, 42);
+    }
+
+    #[test]
+    fn keys_in_range_test() {
+        let mut m: MerkleTrie<10> = MerkleTrie::new();
+        for millis in [3, 44, 127, 345, 346, 999, 1000, 2500] {
+            m.insert(&Timestamp::new(millis, 0, String::from("local")));
+        }
+
+        assert_eq!(m.keys_in_range(44, 1000), vec![44, 127, 345, 346, 999]);
+        assert_eq!(m.keys_in_range(45, 346), vec![127, 345]);
+        assert_eq!(m.keys_in_range(0, i64::MAX), m.stored_keys());
+        assert_eq!(m.keys_in_range(-100, 4), vec![3]);
+        assert!(m.keys_in_range(128, 345).is_empty());
+        assert!(m.keys_in_range(1000, 1000).is_empty());
+        assert!(m.keys_in_range(2500, 3).is_empty());
+        assert!(MerkleTrie::<10>::new().keys_in_range(0, 100).is_empty());
+
+        // Against stored_keys + filter, with buckets wider than a millisecond
+        let mut m: MerkleTrie = MerkleTrie::new().with_bucket_ms(60000);
+        let mut seed = 42u64;
+        for _ in 0..200 {
+            seed = seed.wrapping_mul(6364136223846793005).wrapping_add(1);
+            let millis = 1712898800000 + (seed >> 33) as i64 % 100_000_000;
+            m.insert(&Timestamp::new(millis, 0, String::from("local")));
+        }
+        let keys = m.stored_keys();
+        for (from, to) in [
+            (keys[10], keys[50]),
+            (keys[10] + 1, keys[50] + 1),
+            (keys[0] - 60000, keys[5]),
+            (keys[100], i64::MAX),
+        ] {
+            let expected: Vec<i64> = keys
+                .iter()
+                .copied()
+                .filter(|t| (from..to).contains(t))
+                .collect();
+            assert_eq!(m.keys_in_range(from, to), expected);
+        }
+    }
+
     #[test]
     fn timestamp_to_key_base3_test() {
         let m: MerkleTrie = MerkleTrie::new();
         let t1 = Timestamp::new(0, 0, String::from("1"));
-        let cur = m.timestamp_to_key(&t1);
-        assert!(cur.is_empty());
+        let cur = m.timestamp_to_key(&t1).unwrap();
+        assert_eq!(cur, vec![0]);
 
         let t2 = Timestamp::new(2938, 0, String::from("1"));
-        let cur = m.timestamp_to_key(&t2);
+        let cur = m.timestamp_to_key(&t2).unwrap();
         assert_eq!(cur, vec![1, 1, 0, 0, 0, 2, 1, 1]);
     }
 
+    #[test]
+    fn bucket_hash_test() {
+        let t =
+            |millis: i64, counter: usize| Timestamp::new(millis, counter, String::from("local"));
+        let mut m: MerkleTrie<3> = MerkleTrie::new();
+        m.insert(&t(12, 0));
+        m.insert(&t(127, 0));
+        assert_eq!(m.bucket_hash(12), Some(t(12, 0).hash()));
+        assert_eq!(m.bucket_hash(127), Some(t(127, 0).hash()));
+        assert_eq!(m.bucket_hash(13), None);
+        assert_eq!(m.bucket_hash(-1), None);
+        // The bucket of 4 is an inner node on the path to 12, 110 in base 3
+        assert_eq!(m.bucket_hash(4), None);
+
+        m.insert(&t(12, 1));
+        assert_eq!(m.bucket_hash(12), Some(t(12, 0).hash() ^ t(12, 1).hash()));
+        assert_eq!(m.bucket_hash(127), Some(t(127, 0).hash()));
+    }
+
+    #[test]
+    fn max_key_len_test() {
+        let t = |millis: i64| Timestamp::new(millis, 0, String::from("local"));
+        // Keys of times before 10000 keep 3 significant digits
+        let mut m: MerkleTrie<10> = MerkleTrie::new().with_max_key_len(3, 10000);
+        assert_eq!(m.timestamp_to_key(&t(5678)).unwrap(), vec![5, 6, 7, 0]);
+        assert_eq!(m.timestamp_to_key(&t(5671)).unwrap(), vec![5, 6, 7, 0]);
+        assert_eq!(m.timestamp_to_key(&t(456)).unwrap(), vec![4, 5, 6]);
+        // Recent times keep full resolution
+        assert_eq!(m.timestamp_to_key(&t(12345)).unwrap(), vec![1, 2, 3, 4, 5]);
+
+        // Both old times land in one coarse bucket, read as its start
+        for millis in [5678, 5671, 456, 12345, 12399] {
+            m.insert(&t(millis));
+        }
+        assert_eq!(m.stored_count(), 4);
+        assert_eq!(m.stored_keys(), vec![456, 5670, 12345, 12399]);
+        assert!(m.contains(&t(5675)));
+        assert!(!m.contains(&t(12346)));
+
+        let mut other = MerkleTrie::<10>::new().with_max_key_len(3, 10000);
+        for millis in [5678, 456, 12345, 12399] {
+            other.insert(&t(millis));
+        }
+        assert_eq!(m.diff(&other), Ok(Some(5670)));
+        other.insert(&t(5671));
+        other.insert(&t(12346));
+        assert_eq!(m.diff(&other), Ok(Some(12346)));
+
+        // The cap is kept along with the trie
+        let cap = m.max_key_len();
+        assert!(cap.is_some());
+        let read: MerkleTrie<10> =
+            serde_json::from_str(&serde_json::to_string(&m).unwrap()).unwrap();
+        assert_eq!(read.max_key_len(), cap);
+        assert_eq!(read.stored_keys(), m.stored_keys());
+        assert_eq!(m.rebase::<10>().max_key_len(), cap);
+        assert_eq!(MerkleTrie::<10>::new().max_key_len(), None);
+        m.clear();
+        assert_eq!(m.max_key_len(), cap);
+    }
+
     #[test]
     fn timestamp_to_key_base10_test() {
         let m: MerkleTrie<10> = MerkleTrie::new();
         let t = Timestamp::new(9247, 0, String::from("1"));
-        let cur = m.timestamp_to_key(&t);
+        let cur = m.timestamp_to_key(&t).unwrap();
         assert_eq!(cur, vec![9, 2, 4, 7]);
     }
 
+    #[test]
+    fn timestamp_to_key_string_test() {
+        let t = Timestamp::new(1712898800831, 0, String::from("1"));
+
+        let m: MerkleTrie<16> = MerkleTrie::new();
+        assert_eq!(m.timestamp_to_key_string(&t).unwrap(), "18ed0b97cbf");
+        assert_eq!(
+            m.timestamp_to_key_string(&t).unwrap(),
+            format!("{:x}", 1712898800831i64)
+        );
+
+        let m: MerkleTrie<3> = MerkleTrie::new();
+        let t = Timestamp::new(2938, 0, String::from("1"));
+        assert_eq!(m.timestamp_to_key_string(&t).unwrap(), "11000211");
+
+        let m: MerkleTrie<100> = MerkleTrie::new();
+        let t = Timestamp::new(1_020_304, 0, String::from("1"));
+        assert_eq!(m.timestamp_to_key_string(&t).unwrap(), "1.2.3.4");
+
+        let before = Timestamp::new(-1, 0, String::from("1"));
+        assert_eq!(
+            m.timestamp_to_key_string(&before),
+            Err(TimestampError::BeforeEpoch(-1))
+        );
+    }
+
+    #[test]
+    fn bucket_ms_test() {
+        let m: MerkleTrie<10> = MerkleTrie::new().with_bucket_ms(60000);
+        assert_eq!(m.bucket_ms(), 60000);
+
+        // 2024-04-12T05:13:00.000 and 05:13:59.999 share the same minute
+        let t1 = Timestamp::new(1712898780000, 0, String::from("1"));
+        let t2 = Timestamp::new(1712898839999, 0, String::from("1"));
+        let t3 = Timestamp::new(1712898840000, 0, String::from("1"));
+
+        let key = m.timestamp_to_key(&t1).unwrap();
+        assert_eq!(key, m.timestamp_to_key(&t2).unwrap());
+        assert_ne!(key, m.timestamp_to_key(&t3).unwrap());
+        assert_eq!(m.key_to_timestamp_millis(key), 1712898780000);
+
+        // The default keeps one bucket per millisecond
+        let m: MerkleTrie<10> = MerkleTrie::new();
+        assert_eq!(m.bucket_ms(), 1);
+        assert_ne!(
+            m.timestamp_to_key(&t1).unwrap(),
+            m.timestamp_to_key(&t2).unwrap()
+        );
+    }
+
     #[test]
     fn insert_test() {
         let mut m: MerkleTrie<10> = MerkleTrie::new();
@@ -548,11 +2104,327 @@ mod tests {
         m.insert(&Timestamp::new(44, 0, String::from("local")));
         m.insert(&Timestamp::new(127, 0, String::from("local")));
 
-        assert_eq!(m.length, 5);
+        assert_eq!(m.length(), 5);
 
         m.debug()
     }
 
+    #[test]
+    fn node_count_and_depth_test() {
+        let mut m: MerkleTrie<10> = MerkleTrie::new();
+        assert_eq!(m.node_count(), 1);
+        assert_eq!(m.max_depth(), 0);
+
+        m.insert(&Timestamp::new(1, 0, String::from("local")));
+        m.insert(&Timestamp::new(2, 0, String::from("local")));
+        m.insert(&Timestamp::new(3, 0, String::from("local")));
+        m.insert(&Timestamp::new(44, 0, String::from("local")));
+        m.insert(&Timestamp::new(127, 0, String::from("local")));
+
+        // root, 1, 2, 3, 4, 44, 12, 127
+        assert_eq!(m.node_count(), 8);
+        assert_eq!(m.max_depth(), 3);
+    }
+
+    #[test]
+    fn clear_test() {
+        let mut m: MerkleTrie<10> = MerkleTrie::new().with_bucket_ms(10);
+        m.insert(&Timestamp::new(1270, 0, String::from("local")));
+        m.insert(&Timestamp::new(4400, 0, String::from("local")));
+
+        m.clear();
+        assert!(m.is_empty());
+        assert_eq!(m.root_hash(), 0);
+        assert_eq!(m.node_count(), 1);
+        assert_eq!(m.bucket_ms(), 10);
+
+        let t = Timestamp::new(1270, 0, String::from("local"));
+        m.insert(&t);
+        assert_eq!(m.length(), 1);
+        assert_eq!(m.root_hash(), t.hash());
+    }
+
+    #[test]
+    fn clone_test() {
+        let mut m1: MerkleTrie<10> = MerkleTrie::new();
+        m1.insert(&Timestamp::new(127, 0, String::from("local")));
+
+        let m2 = m1.clone();
+        m1.insert(&Timestamp::new(128, 0, String::from("local")));
+        m1.clear();
+
+        // The clone doesn't share any node with the original
+        assert_eq!(m2.length(), 1);
+        assert_eq!(
+            m2.root_hash(),
+            Timestamp::new(127, 0, String::from("local")).hash()
+        );
+        assert_eq!(m2.node_count(), 4);
+    }
+
+    #[test]
+    fn stored_keys_test() {
+        let mut m: MerkleTrie<10> = MerkleTrie::new();
+        assert!(m.stored_keys().is_empty());
+
+        m.insert(&Timestamp::new(127, 0, String::from("local")));
+        m.insert(&Timestamp::new(2, 0, String::from("local")));
+        m.insert(&Timestamp::new(12, 0, String::from("local")));
+        m.insert(&Timestamp::new(12, 1, String::from("local")));
+
+        assert_eq!(m.stored_keys(), vec![2, 12, 127]);
+    }
+
+    #[test]
+    fn contains_test() {
+        let present = Timestamp::new(1712898800831, 0, String::from("local"));
+        let mut m: MerkleTrie<3> = MerkleTrie::new();
+        assert!(!m.contains(&present));
+
+        m.insert(&present);
+        assert!(m.contains(&present));
+        // Other counters and nodes fall into the same bucket
+        assert!(m.contains(&Timestamp::new(1712898800831, 1, String::from("remote"))));
+        assert!(!m.contains(&Timestamp::new(1712898800832, 0, String::from("local"))));
+        // An inner node on the path isn't stored
+        assert!(!m.contains(&Timestamp::new(1712898800831 / 3, 0, String::from("local"))));
+    }
+
+    #[test]
+    fn verify_test() {
+        let mut m: MerkleTrie<3> = MerkleTrie::new();
+        assert_eq!(m.verify(), Ok(()));
+        for millis in [1712898800831, 1712898800832, 1712898801000] {
+            m.insert(&Timestamp::new(millis, 0, String::from("local")));
+        }
+        assert_eq!(m.verify(), Ok(()));
+
+        // Corrupt the hash of the stored node of the first timestamp, which
+        // breaks its (unstored) parent
+        let key = m.millis_to_key(1712898800831);
+        let mut node = unsafe { m.root.as_mut() };
+        for k in &key {
+            node = unsafe { node.children.as_mut().unwrap().get_mut(k).unwrap().as_mut() };
+        }
+        node.hash ^= 1;
+
+        let report = m.verify().unwrap_err();
+        assert_eq!(report.key, key[..key.len() - 1]);
+        assert_eq!(report.found ^ report.expected, 1);
+
+        // A deserialized copy is just as inconsistent
+        let copy: MerkleTrie<3> =
+            serde_json::from_str(&serde_json::to_string(&m).unwrap()).unwrap();
+        assert_eq!(copy.verify(), Err(report));
+    }
+
+    #[test]
+    fn prune_before_test() {
+        let times = [5, 12, 127, 345, 346, 1200, 1712898800000, 1712898800831];
+        let cutoff = 346;
+        let mut m: MerkleTrie<10> = MerkleTrie::new();
+        for millis in times {
+            m.insert(&Timestamp::new(millis, 0, String::from("local")));
+        }
+
+        m.prune_before(cutoff);
+
+        assert_eq!(
+            m.stored_keys(),
+            vec![346, 1200, 1712898800000, 1712898800831]
+        );
+        assert!(m.stored_keys().iter().all(|t| *t >= cutoff));
+        assert_eq!(m.length(), 4);
+        assert_eq!(m.verify(), Ok(()));
+        // Same as if the pruned timestamps were never inserted
+        let kept: Vec<Timestamp> = times
+            .iter()
+            .filter(|t| **t >= cutoff)
+            .map(|t| Timestamp::new(*t, 0, String::from("local")))
+            .collect();
+        let expected = MerkleTrie::<10>::from_timestamps(&kept);
+        assert_eq!(m.root_hash(), expected.root_hash());
+        assert_eq!(m.node_count(), expected.node_count());
+        assert_eq!(m.diff(&expected), Ok(None));
+
+        m.prune_before(i64::MAX);
+        assert!(m.is_empty());
+        assert_eq!(m.root_hash(), 0);
+        assert_eq!(m.node_count(), 1);
+    }
+
+    #[test]
+    fn prune_before_shared_bucket_test() {
+        let t = |millis: i64, node: &str| Timestamp::new(millis, 0, String::from(node));
+        let mut m: MerkleTrie<10> =
+            MerkleTrie::from_timestamps(&[t(5, "a"), t(5, "b"), t(12, "a"), t(12, "b")]);
+
+        m.prune_before(12);
+        assert_eq!(m.length(), 2);
+        assert_eq!(m.stored_keys(), vec![12]);
+
+        m.prune_before(i64::MAX);
+        assert_eq!(m.length(), 0);
+        assert!(m.is_empty());
+        assert_eq!(
+            m.diff_detailed(&MerkleTrie::new()),
+            Ok(DiffResult::BothEmpty)
+        );
+        assert_eq!(m.diff(&MerkleTrie::new()), Ok(None));
+    }
+
+    #[test]
+    fn stored_count_test() {
+        let t =
+            |millis: i64, counter: usize| Timestamp::new(millis, counter, String::from("local"));
+        let mut m: MerkleTrie<3> = MerkleTrie::new();
+        assert_eq!(m.stored_count(), 0);
+
+        for millis in [0, 5, 12, 127, 345, 1712898800000] {
+            m.insert(&t(millis, 0));
+            assert_eq!(m.stored_count(), m.stored_keys().len() as u64);
+        }
+        // A second timestamp in a stored bucket
+        m.insert(&t(127, 1));
+        assert_eq!(m.stored_count(), 6);
+        assert_eq!(m.length(), 7);
+
+        for (millis, counter) in [(127, 0), (5, 0), (127, 1), (1712898800000, 0), (6, 0)] {
+            m.remove(&t(millis, counter));
+            assert_eq!(m.stored_count(), m.stored_keys().len() as u64);
+        }
+        assert_eq!(m.stored_count(), 3);
+        assert_eq!(m.length(), m.stored_count());
+    }
+
+    #[test]
+    fn remove_test() {
+        let timestamps: Vec<Timestamp> = [5, 12, 127, 345, 1712898800000, 1712898800831]
+            .into_iter()
+            .map(|millis| Timestamp::new(millis, 0, String::from("local")))
+            .collect();
+        let mut m: MerkleTrie<3> = MerkleTrie::from_timestamps(&timestamps);
+        // A second timestamp in the bucket of 127
+        let shared = Timestamp::new(127, 1, String::from("local"));
+        m.insert(&shared);
+
+        assert!(m.remove(&timestamps[4]));
+        assert!(m.remove(&timestamps[2]));
+        // The bucket still holds the other timestamp
+        assert!(m.contains(&shared));
+        assert!(!m.remove(&timestamps[4]));
+        assert!(!m.remove(&Timestamp::new(6, 0, String::from("local"))));
+
+        // Same as if the removed timestamps were never inserted
+        let expected = MerkleTrie::<3>::from_timestamps([
+            &timestamps[0],
+            &timestamps[1],
+            &timestamps[3],
+            &timestamps[5],
+            &shared,
+        ]);
+        assert_eq!(m.length(), 5);
+        assert_eq!(m.root_hash(), expected.root_hash());
+        assert_eq!(m.node_count(), expected.node_count());
+        assert_eq!(m.diff(&expected), Ok(None));
+        assert_eq!(m.verify(), Ok(()));
+
+        for t in [
+            &timestamps[0],
+            &timestamps[1],
+            &timestamps[3],
+            &timestamps[5],
+            &shared,
+        ] {
+            assert!(m.remove(t));
+        }
+        assert!(m.is_empty());
+        assert_eq!(m.root_hash(), 0);
+        assert_eq!(m.node_count(), 1);
+    }
+
+    #[test]
+    fn iter_test() {
+        let mut m: MerkleTrie<3> = MerkleTrie::new();
+        assert_eq!(m.iter().next(), None);
+
+        // Keys of different lengths, e.g. 3 is below 1 in the trie
+        for millis in [100, 2, 9, 3, 1, 5, 1712898800831] {
+            m.insert(&Timestamp::new(millis, 0, String::from("local")));
+        }
+        let expected = vec![1, 2, 3, 5, 9, 100, 1712898800831];
+
+        let mut iterated = vec![];
+        for t in &m {
+            iterated.push(t);
+        }
+        assert_eq!(iterated, expected);
+        assert_eq!(m.iter().collect::<Vec<_>>(), m.stored_keys());
+
+        let mut bucketed = MerkleTrie::<3>::new().with_bucket_ms(1000);
+        bucketed.insert(&Timestamp::new(1712898800831, 0, String::from("local")));
+        bucketed.insert(&Timestamp::new(1712898801000, 0, String::from("local")));
+        assert_eq!(
+            bucketed.into_iter().collect::<Vec<_>>(),
+            vec![1712898800000, 1712898801000]
+        );
+        assert_eq!(m.into_iter().collect::<Vec<_>>(), expected);
+    }
+
+    #[test]
+    fn from_timestamps_test() {
+        let timestamps = [
+            Timestamp::new(1712898800831, 0, String::from("local")),
+            Timestamp::new(1712898800831, 1, String::from("local")),
+            Timestamp::new(1712898801000, 0, String::from("remote")),
+        ];
+
+        let mut inserted: MerkleTrie<3> = MerkleTrie::new();
+        for t in timestamps.iter().rev() {
+            inserted.insert(t);
+        }
+        let built: MerkleTrie<3> = MerkleTrie::from_timestamps(&timestamps);
+
+        assert_eq!(built.root_hash(), inserted.root_hash());
+        assert_eq!(built.length(), inserted.length());
+        assert_eq!(built.diff(&inserted), Ok(None));
+        assert!(MerkleTrie::<3>::from_timestamps(&[]).is_empty());
+    }
+
+    #[test]
+    fn rebase_test() {
+        let timestamps = [
+            Timestamp::new(1712898800831, 0, String::from("local")),
+            Timestamp::new(1712898800831, 1, String::from("local")),
+            Timestamp::new(1712898801000, 0, String::from("remote")),
+            Timestamp::new(1712898900000, 0, String::from("remote")),
+        ];
+
+        let mut m: MerkleTrie<3> = MerkleTrie::new();
+        let mut reference3: MerkleTrie<3> = MerkleTrie::new();
+        let mut reference10: MerkleTrie<10> = MerkleTrie::new();
+        for t in &timestamps {
+            m.insert(t);
+            reference10.insert(t);
+        }
+        for t in &timestamps[..2] {
+            reference3.insert(t);
+        }
+
+        let rebased: MerkleTrie<10> = m.rebase();
+        assert_eq!(rebased.length(), m.length());
+        assert_eq!(rebased.root_hash(), m.root_hash());
+        assert_eq!(rebased.stored_keys(), m.stored_keys());
+        assert_eq!(rebased.diff(&reference10), Ok(None));
+
+        let mut reference10: MerkleTrie<10> = MerkleTrie::new();
+        for t in &timestamps[..2] {
+            reference10.insert(t);
+        }
+        assert_eq!(m.diff(&reference3), Ok(Some(1712898801000)));
+        assert_eq!(rebased.diff(&reference10), m.diff(&reference3));
+    }
+
     #[test]
     fn diff_test1() {
         let mut m1: MerkleTrie<10> = MerkleTrie::new();
@@ -561,8 +2433,8 @@ mod tests {
         let mut m2: MerkleTrie<10> = MerkleTrie::new();
         m2.insert(&Timestamp::new(12768, 0, String::from("remote")));
 
-        assert_eq!(m1.diff(&m2), Some(12768));
-        assert_eq!(m1.diff(&m2), m2.diff(&m1));
+        assert_eq!(m1.diff(&m2), Ok(Some(12768)));
+        assert_diff_symmetric(&m1, &m2);
     }
 
     #[test]
@@ -573,8 +2445,8 @@ mod tests {
         let mut m2: MerkleTrie<10> = MerkleTrie::new();
         m2.insert(&Timestamp::new(12787, 0, String::from("remote")));
 
-        assert_eq!(m1.diff(&m2), Some(12786));
-        assert_eq!(m1.diff(&m2), m2.diff(&m1));
+        assert_eq!(m1.diff(&m2), Ok(Some(12786)));
+        assert_diff_symmetric(&m1, &m2);
     }
 
     #[test]
@@ -588,8 +2460,8 @@ mod tests {
         println!();
         m2.debug();
 
-        assert_eq!(m1.diff(&m2), Some(0));
-        assert_eq!(m1.diff(&m2), m2.diff(&m1));
+        assert_eq!(m1.diff(&m2), Ok(Some(0)));
+        assert_diff_symmetric(&m1, &m2);
     }
 
     #[test]
@@ -600,8 +2472,455 @@ mod tests {
         let mut m2: MerkleTrie<10> = MerkleTrie::new();
         m2.insert(&Timestamp::new(12787, 0, String::from("remote")));
 
-        assert_eq!(m1.diff(&m2), Some(127));
-        assert_eq!(m1.diff(&m2), m2.diff(&m1));
+        assert_eq!(m1.diff(&m2), Ok(Some(127)));
+        assert_diff_symmetric(&m1, &m2);
+    }
+
+    #[test]
+    fn diff_detailed_test() {
+        let t = |millis: i64, node: &str| Timestamp::new(millis, 0, String::from(node));
+        let empty: MerkleTrie<10> = MerkleTrie::new();
+        let m1: MerkleTrie<10> = MerkleTrie::from_timestamps([&t(127, "local")]);
+        let m2: MerkleTrie<10> =
+            MerkleTrie::from_timestamps([&t(127, "local"), &t(12787, "remote")]);
+
+        assert_eq!(
+            empty.diff_detailed(&MerkleTrie::new()),
+            Ok(DiffResult::BothEmpty)
+        );
+        assert_eq!(m1.diff_detailed(&m1.clone()), Ok(DiffResult::Identical));
+        assert_eq!(m1.diff_detailed(&m2), Ok(DiffResult::Divergent(12787)));
+        // No common history
+        assert_eq!(empty.diff_detailed(&m1), Ok(DiffResult::Divergent(0)));
+        assert_eq!(m1.diff_detailed(&empty), Ok(DiffResult::Divergent(0)));
+
+        // diff can't tell the first two apart
+        assert_eq!(empty.diff(&MerkleTrie::new()), Ok(None));
+        assert_eq!(m1.diff(&m1.clone()), Ok(None));
+        assert_eq!(m1.diff(&m2), Ok(Some(12787)));
+
+        let wide: MerkleTrie<10> = MerkleTrie::new().with_bucket_ms(10);
+        assert!(matches!(
+            m1.diff_detailed(&wide),
+            Err(DiffError::BucketMismatch { .. })
+        ));
+    }
+
+    #[test]
+    fn merge_test() {
+        let t1 = Timestamp::new(12, 0, String::from("local"));
+        let t2 = Timestamp::new(127, 0, String::from("local"));
+        let t3 = Timestamp::new(128, 0, String::from("remote"));
+        let t4 = Timestamp::new(4501, 0, String::from("remote"));
+
+        let mut m1: MerkleTrie<10> = MerkleTrie::new();
+        m1.insert(&t1);
+        m1.insert(&t2);
+
+        let mut m2: MerkleTrie<10> = MerkleTrie::new();
+        m2.insert(&t2);
+        m2.insert(&t3);
+        m2.insert(&t4);
+
+        let mut expected: MerkleTrie<10> = MerkleTrie::new();
+        for t in [&t1, &t2, &t3, &t4] {
+            expected.insert(t);
+        }
+
+        m1.merge(&m2).unwrap();
+        assert_eq!(m1.root_hash(), expected.root_hash());
+        assert_eq!(m1.length(), 4);
+        assert_eq!(m1.diff(&expected), Ok(None));
+
+        // Merging again changes nothing
+        m1.merge(&m2).unwrap();
+        assert_eq!(m1.root_hash(), expected.root_hash());
+        assert_eq!(m1.length(), 4);
+    }
+
+    #[test]
+    fn merge_conflict_test() {
+        let t = |node: &str| Timestamp::new(1712898800000, 0, String::from(node));
+        let (a, b, c) = (t("a"), t("b"), t("c"));
+
+        // The same bucket holding different timestamps on both sides, even
+        // when some of them are shared
+        for (local, remote) in [(vec![&b], vec![&c]), (vec![&a, &b], vec![&a, &c])] {
+            let mut m1: MerkleTrie<10> = MerkleTrie::from_timestamps(local);
+            let m2: MerkleTrie<10> = MerkleTrie::from_timestamps(remote);
+
+            let (hash, length) = (m1.root_hash(), m1.length());
+            assert!(m1.merge(&m2).is_err());
+            assert_eq!(m1.root_hash(), hash);
+            assert_eq!(m1.length(), length);
+        }
+
+        // Tries capping their keys differently have different buckets
+        let mut m1: MerkleTrie<10> = MerkleTrie::from_timestamps([&a]);
+        let m2 = MerkleTrie::from_timestamps([&b]).with_max_key_len(4, i64::MAX);
+        assert!(m1.merge(&m2).is_err());
+    }
+
+    #[test]
+    fn merge_shared_buckets_test() {
+        // Buckets of several timestamps, one of them on both sides
+        let t = |millis: i64, node: &str| Timestamp::new(millis, 0, String::from(node));
+        let shared = [t(1500, "a"), t(1600, "b")];
+        let (local, remote) = ([t(2100, "a"), t(2200, "a")], [t(3700, "b"), t(3800, "b")]);
+        let trie = |timestamps: Vec<&Timestamp>| {
+            let mut m: MerkleTrie<10> = MerkleTrie::new().with_bucket_ms(1000);
+            for t in timestamps {
+                m.insert(t);
+            }
+            m
+        };
+        let mut m1 = trie(shared.iter().chain(&local).collect());
+        let m2 = trie(shared.iter().chain(&remote).collect());
+        let expected = trie(shared.iter().chain(&local).chain(&remote).collect());
+
+        m1.merge(&m2).unwrap();
+        assert_eq!(m1.diff(&expected), Ok(None));
+        assert_eq!(m1.length(), 6);
+        assert_eq!(m1.stored_count(), 3);
+
+        // The timestamp counts of the buckets survive a round trip
+        let copy: MerkleTrie<10> =
+            serde_json::from_str(&serde_json::to_string(&m1).unwrap()).unwrap();
+        let mut empty = trie(vec![]);
+        empty.merge(&copy).unwrap();
+        assert_eq!(empty.length(), 6);
+    }
+
+    #[test]
+    fn diff_shared_lowest_key_test() {
+        // Both tries share the subtree of key 1, and only differ under key 3
+        let mut m1: MerkleTrie<10> = MerkleTrie::new();
+        m1.insert(&Timestamp::new(127, 0, String::from("local")));
+        m1.insert(&Timestamp::new(345, 0, String::from("local")));
+
+        let mut m2: MerkleTrie<10> = MerkleTrie::new();
+        m2.insert(&Timestamp::new(127, 0, String::from("local")));
+        m2.insert(&Timestamp::new(346, 0, String::from("remote")));
+
+        assert_eq!(m1.diff(&m2), Ok(Some(345)));
+        assert_diff_symmetric(&m1, &m2);
+    }
+
+    #[test]
+    fn diff_one_sided_subtree_test() {
+        // Only the second trie has a subtree under key 1
+        let mut m1: MerkleTrie<10> = MerkleTrie::new();
+        m1.insert(&Timestamp::new(345, 0, String::from("local")));
+
+        let mut m2: MerkleTrie<10> = MerkleTrie::new();
+        m2.insert(&Timestamp::new(127, 0, String::from("remote")));
+        m2.insert(&Timestamp::new(346, 0, String::from("remote")));
+
+        assert_eq!(m1.diff(&m2), Ok(Some(127)));
+        assert_eq!(assert_diff_symmetric(&m1, &m2), Some(127));
+    }
+
+    #[test]
+    fn diff_later_sibling_test() {
+        // Key 1 is the first differing child, but only deep down at 16,
+        // while key 2 differs right away
+        let t = |millis: i64| Timestamp::new(millis, 0, String::from("local"));
+        let m1: MerkleTrie<10> = MerkleTrie::from_timestamps(&[t(15), t(2)]);
+        let m2: MerkleTrie<10> = MerkleTrie::from_timestamps(&[t(15), t(16), t(3)]);
+
+        assert_eq!(assert_diff_symmetric(&m1, &m2), Some(2));
+    }
+
+    #[test]
+    fn zero_millis_test() {
+        let t = Timestamp::new(0, 0, String::from("local"));
+        let mut m: MerkleTrie<3> = MerkleTrie::new();
+        m.insert(&t);
+
+        // Stored below the root like any other bucket
+        assert!(m.contains(&t));
+        assert_eq!(m.stored_keys(), vec![0]);
+        assert_eq!(m.verify(), Ok(()));
+
+        let other = MerkleTrie::from_timestamps(&[Timestamp::new(0, 0, String::from("remote"))]);
+        assert_eq!(assert_diff_symmetric(&m, &other), Some(0));
+        assert!(m.remove(&t));
+        assert!(m.is_empty());
+    }
+
+    #[test]
+    fn diff_never_max_random_test() {
+        for seed in 0..500 {
+            let (m1, m2) = random_tries::<3>(seed);
+            assert_ne!(m1.diff(&m2), Ok(Some(i64::MAX)));
+        }
+    }
+
+    #[test]
+    fn symmetric_difference_test() {
+        let t = |millis: i64, node: &str| Timestamp::new(millis, 0, String::from(node));
+        let mut m1: MerkleTrie<10> = MerkleTrie::new();
+        let mut m2: MerkleTrie<10> = MerkleTrie::new();
+        for millis in [3, 44, 127, 345] {
+            m1.insert(&t(millis, "shared"));
+            m2.insert(&t(millis, "shared"));
+        }
+        m1.insert(&t(12, "local"));
+        m1.insert(&t(3460, "local"));
+        m2.insert(&t(346, "remote"));
+        // The same bucket with different timestamps in it
+        m1.insert(&t(999, "local"));
+        m2.insert(&t(999, "remote"));
+
+        assert_eq!(
+            m1.symmetric_difference(&m2),
+            (vec![12, 999, 3460], vec![346, 999])
+        );
+        assert_eq!(
+            m2.symmetric_difference(&m1),
+            (vec![346, 999], vec![12, 999, 3460])
+        );
+        assert_eq!(m1.symmetric_difference(&m1.clone()), (vec![], vec![]));
+        assert_eq!(
+            m1.symmetric_difference(&MerkleTrie::new()),
+            (m1.stored_keys(), vec![])
+        );
+
+        // Against comparing the own hashes of all stored buckets
+        for seed in 0..200 {
+            let (m1, m2) = random_tries::<3>(seed);
+            let (b1, b2): (BTreeMap<_, _>, BTreeMap<_, _>) = (
+                m1.stored_buckets().into_iter().collect(),
+                m2.stored_buckets().into_iter().collect(),
+            );
+            let only = |a: &BTreeMap<Vec<usize>, u64>, b: &BTreeMap<Vec<usize>, u64>| {
+                let mut times: Vec<i64> = a
+                    .iter()
+                    .filter(|(key, hash)| b.get(*key) != Some(hash))
+                    .map(|(key, _)| m1.key_to_timestamp_millis(key.clone()))
+                    .collect();
+                times.sort();
+                times
+            };
+            assert_eq!(
+                m1.symmetric_difference(&m2),
+                (only(&b1, &b2), only(&b2, &b1))
+            );
+        }
+    }
+
+    #[test]
+    fn max_key_test() {
+        let mut m: MerkleTrie<10> = MerkleTrie::new();
+        assert_eq!(m.max_key(), None);
+
+        for millis in [1, 2, 3, 44, 127] {
+            m.insert(&Timestamp::new(millis, 0, String::from("local")));
+        }
+        assert_eq!(m.max_key(), Some(127));
+
+        // 90 is down the largest child but shorter than 127
+        m.insert(&Timestamp::new(90, 0, String::from("local")));
+        assert_eq!(m.max_key(), Some(127));
+
+        // The start of the latest bucket
+        let mut m: MerkleTrie = MerkleTrie::new().with_bucket_ms(60000);
+        m.insert(&Timestamp::new(1712898859999, 0, String::from("local")));
+        assert_eq!(m.max_key(), Some(1712898840000));
+
+        for seed in 0..50 {
+            let (m1, _) = random_tries::<3>(seed);
+            assert_eq!(m1.max_key(), m1.stored_keys().last().copied());
+        }
+    }
+
+    #[test]
+    fn min_key_test() {
+        let mut m: MerkleTrie<10> = MerkleTrie::new();
+        assert_eq!(m.min_key(), None);
+
+        for millis in [127, 44, 3, 2, 1] {
+            m.insert(&Timestamp::new(millis, 0, String::from("local")));
+        }
+        assert_eq!(m.min_key(), Some(1));
+        assert_eq!(m.min_key(), m.stored_keys().first().copied());
+
+        for seed in 0..50 {
+            let (m1, _) = random_tries::<3>(seed);
+            assert_eq!(m1.min_key(), m1.stored_keys().first().copied());
+        }
+    }
+
+    #[test]
+    fn stats_test() {
+        let mut m: MerkleTrie<10> = MerkleTrie::new();
+        assert_eq!(
+            m.stats(),
+            TrieStats {
+                node_count: 1,
+                ..Default::default()
+            }
+        );
+
+        for millis in [3, 44, 127, 345, 346] {
+            m.insert(&Timestamp::new(millis, 0, String::from("local")));
+        }
+        let stats = m.stats();
+        assert_eq!(
+            stats,
+            TrieStats {
+                node_count: 10,
+                stored_count: 5,
+                internal_count: 6,
+                max_depth: 3,
+                // (1 + 2 + 3 + 3 + 3) / 5
+                avg_depth: 2.4,
+                // 9 children of the root, 1, 3, 4, 12 and 34
+                avg_children: 1.5,
+            }
+        );
+        assert_eq!(stats.node_count, m.node_count());
+        assert_eq!(stats.max_depth, m.max_depth());
+    }
+
+    #[test]
+    fn send_sync_test() {
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<MerkleTrie>();
+        assert_send_sync::<crate::clock::MerkleClock>();
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn concurrent_diff_test() {
+        let (m1, m2) = random_tries::<3>(7);
+        let expected = m1.diff(&m2);
+
+        // Read-only diffs of the same tries from several threads at once
+        std::thread::scope(|scope| {
+            for _ in 0..8 {
+                scope.spawn(|| {
+                    for _ in 0..50 {
+                        assert_eq!(m1.diff(&m2), expected);
+                        assert_eq!(m2.diff(&m1), expected);
+                    }
+                });
+            }
+        });
+    }
+
+    #[test]
+    fn diff_symmetric_random_test() {
+        for seed in 0..500 {
+            let (m1, m2) = random_tries::<3>(seed);
+            assert_diff_symmetric(&m1, &m2);
+            assert_eq!(m1.diff(&m1), Ok(None));
+
+            let (m1, m2) = random_tries::<10>(seed);
+            assert_diff_symmetric(&m1, &m2);
+            assert_eq!(m2.diff(&m2), Ok(None));
+        }
+    }
+
+    #[test]
+    fn diff_since_test() {
+        let mut m1: MerkleTrie<3> = MerkleTrie::new();
+        let mut m2: MerkleTrie<3> = MerkleTrie::new();
+        for millis in [1712898800000, 1712898900000] {
+            let t = Timestamp::new(millis, 0, String::from("shared"));
+            m1.insert(&t);
+            m2.insert(&t);
+        }
+        // Only an old message differs
+        m1.insert(&Timestamp::new(1712898700000, 0, String::from("local")));
+
+        assert_eq!(m1.diff(&m2), Ok(Some(1712898700000)));
+        assert_eq!(m1.diff_since(&m2, 1712898800000), Ok(None));
+        assert_eq!(m2.diff_since(&m1, 1712898800000), Ok(None));
+        assert_eq!(m1.diff_since(&m2, 0), Ok(Some(1712898700000)));
+
+        // A recent one is found, the old one still ignored
+        m2.insert(&Timestamp::new(1712898850000, 0, String::from("remote")));
+        assert_eq!(m1.diff_since(&m2, 1712898800000), Ok(Some(1712898850000)));
+        assert_eq!(m1.diff_since(&m2, 1712898850000), Ok(Some(1712898850000)));
+        assert_eq!(m1.diff_since(&m2, 1712898850001), Ok(None));
+
+        let m3: MerkleTrie<3> = MerkleTrie::new().with_bucket_ms(1000);
+        assert!(m1.diff_since(&m3, 0).is_err());
+    }
+
+    #[test]
+    fn diff_since_random_test() {
+        for seed in 0..500 {
+            let (m1, m2) = random_tries::<3>(seed);
+            let since = m1.diff_since(&m2, 0).unwrap();
+            assert_eq!(since, m2.diff_since(&m1, 0).unwrap());
+            // The diff can only report an earlier time, e.g. the time of a
+            // stored prefix of the first differing bucket
+            match m1.diff(&m2).unwrap() {
+                Some(diff) if !m1.is_empty() && !m2.is_empty() => {
+                    assert!(since.is_some_and(|since| since >= diff))
+                }
+                Some(_) => assert!(since.is_some()),
+                None => assert_eq!(since, None),
+            }
+            // It is exactly the first bucket differing from the cutoff on
+            let buckets = |m: &MerkleTrie<3>| {
+                m.stored_buckets()
+                    .into_iter()
+                    .map(|(key, hash)| (m.key_to_timestamp_millis(key), hash))
+                    .collect::<BTreeMap<_, _>>()
+            };
+            let (b1, b2) = (buckets(&m1), buckets(&m2));
+            let differing: Vec<i64> = b1
+                .keys()
+                .chain(b2.keys())
+                .filter(|t| b1.get(t) != b2.get(t))
+                .copied()
+                .collect::<BTreeSet<_>>()
+                .into_iter()
+                .collect();
+            assert_eq!(since, differing.first().copied());
+            for t in differing {
+                assert_eq!(m1.diff_since(&m2, t), Ok(Some(t)));
+            }
+        }
+    }
+
+    #[test]
+    fn diff_malformed_test() {
+        let mut m: MerkleTrie<10> = MerkleTrie::new();
+        m.insert(&Timestamp::new(2, 0, String::from("local")));
+
+        // A leaf that doesn't store anything
+        let malformed: MerkleTrie<10> = serde_json::from_str(
+            r#"{"root":{"hash":7,"stored":false,"children":{"1":{"hash":7,"stored":false,"children":null}}},"length":1}"#,
+        )
+        .unwrap();
+        assert!(matches!(m.diff(&malformed), Err(DiffError::Malformed(_))));
+        assert!(matches!(malformed.diff(&m), Err(DiffError::Malformed(_))));
+
+        // A root hash not backed by any child
+        let malformed: MerkleTrie<10> = serde_json::from_str(
+            r#"{"root":{"hash":9,"stored":false,"children":null},"length":1}"#,
+        )
+        .unwrap();
+        assert!(matches!(m.diff(&malformed), Err(DiffError::Malformed(_))));
+    }
+
+    #[test]
+    fn diff_bucket_mismatch_test() {
+        let mut m1: MerkleTrie<10> = MerkleTrie::new();
+        m1.insert(&Timestamp::new(2, 0, String::from("local")));
+        let m2: MerkleTrie<10> = MerkleTrie::new().with_bucket_ms(1000);
+
+        assert_eq!(
+            m1.diff(&m2),
+            Err(DiffError::BucketMismatch {
+                got: 1000,
+                expected: 1
+            })
+        );
     }
 
     #[test]
@@ -628,7 +2947,32 @@ mod tests {
 
         println!("Deserialized: ");
         deserialized.debug();
-        assert_eq!(deserialized.length, 5);
+        assert_eq!(deserialized.length(), 5);
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn write_to_test() {
+        let mut m: MerkleTrie<3> = MerkleTrie::new();
+        for i in 0..10_000 {
+            m.insert(&Timestamp::new(
+                1712898800000 + i * 60_000,
+                0,
+                String::from("local"),
+            ));
+        }
+        assert_eq!(m.stored_keys().len(), 10_000);
+
+        let mut sink: Vec<u8> = Vec::new();
+        m.write_to(&mut sink).unwrap();
+        // The same JSON as the serde serializer
+        assert_eq!(sink, serde_json::to_vec(&m).unwrap());
+
+        let read: MerkleTrie<3> = serde_json::from_slice(&sink).unwrap();
+        assert_eq!(read.length(), m.length());
+        assert_eq!(read.root_hash(), m.root_hash());
+        assert_eq!(read.stored_keys(), m.stored_keys());
+        assert_eq!(read.diff(&m), Ok(None));
     }
 
     #[test]
@@ -651,4 +2995,96 @@ mod tests {
         println!("Deserialized: ");
         deserialized.debug();
     }
+
+    /// Distinct timestamps, each shared by both tries, only in the first
+    /// one or only in the second one. Both early times with short keys and
+    /// realistic ones, in the same millisecond too.
+    fn overlapping_timestamps() -> impl Strategy<Value = Vec<(Timestamp, u8)>> {
+        let millis = prop_oneof![0i64..3000, 1712898800000i64..1712898803000];
+        prop::collection::btree_map((millis, 0usize..3, 0u8..3), 0u8..3, 0..40).prop_map(
+            |timestamps| {
+                timestamps
+                    .into_iter()
+                    .map(|((millis, counter, node), side)| {
+                        (
+                            Timestamp::new(millis, counter, format!("node{}", node)),
+                            side,
+                        )
+                    })
+                    .collect()
+            },
+        )
+    }
+
+    fn split_tries<const BASE: usize>(
+        timestamps: &[(Timestamp, u8)],
+    ) -> (MerkleTrie<BASE>, MerkleTrie<BASE>) {
+        let side = |sides: &'static [u8]| {
+            timestamps
+                .iter()
+                .filter(move |(_, side)| sides.contains(side))
+                .map(|(t, _)| t)
+        };
+        (
+            MerkleTrie::from_timestamps(side(&[0, 1])),
+            MerkleTrie::from_timestamps(side(&[0, 2])),
+        )
+    }
+
+    fn check_diff<const BASE: usize>(timestamps: &[(Timestamp, u8)]) -> Result<(), TestCaseError> {
+        let (m1, m2) = split_tries::<BASE>(timestamps);
+        let diff = m1.diff(&m2).unwrap();
+        prop_assert_eq!(diff, m2.diff(&m1).unwrap(), "diff is not commutative");
+        prop_assert_eq!(m1.diff(&m1.clone()), Ok(None));
+
+        let first_divergent = timestamps
+            .iter()
+            .filter(|(_, side)| *side != 0)
+            .map(|(t, _)| t.millis())
+            .min();
+        match (diff, first_divergent) {
+            (None, None) => {}
+            (Some(diff), Some(first)) => {
+                prop_assert!(diff <= first, "diff {} after {}", diff, first);
+                // Exactly the first divergent time, unless a side is empty
+                if !m1.is_empty() && !m2.is_empty() {
+                    prop_assert_eq!(diff, first);
+                }
+            }
+            (diff, first) => prop_assert!(false, "diff {:?}, divergent {:?}", diff, first),
+        }
+
+        Ok(())
+    }
+
+    proptest! {
+        #[test]
+        fn diff_earliest_divergence_prop(timestamps in overlapping_timestamps()) {
+            check_diff::<3>(&timestamps)?;
+            check_diff::<10>(&timestamps)?;
+            check_diff::<16>(&timestamps)?;
+        }
+
+        #[test]
+        fn insert_order_prop(
+            timestamps in overlapping_timestamps().prop_shuffle(),
+            reversed in any::<bool>(),
+        ) {
+            let (mut shuffled, mut sorted): (Vec<Timestamp>, Vec<Timestamp>) = timestamps
+                .into_iter()
+                .map(|(t, _)| (t.clone(), t))
+                .unzip();
+            sorted.sort();
+            if reversed {
+                shuffled.reverse();
+            }
+            let m1: MerkleTrie<3> = MerkleTrie::from_timestamps(&shuffled);
+            let m2: MerkleTrie<3> = MerkleTrie::from_timestamps(&sorted);
+
+            prop_assert_eq!(m1.root_hash(), m2.root_hash());
+            prop_assert_eq!(m1.stored_keys(), m2.stored_keys());
+            prop_assert_eq!(m1.diff(&m2), Ok(None));
+            prop_assert_eq!(m1.verify(), Ok(()));
+        }
+    }
 }
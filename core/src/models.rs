@@ -1,7 +1,12 @@
-use std::fmt::{Display, Formatter};
+use alloc::string::{String, ToString};
+use core::cmp::Ordering;
+use core::fmt::{Display, Formatter};
 
 use serde::{Deserialize, Serialize};
 
+use crate::error::MessageError;
+use crate::timestamp::Timestamp;
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Message {
     pub timestamp: String,
@@ -12,11 +17,142 @@ pub struct Message {
     pub value: String,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+/// The column a deletion is recorded in
+pub const TOMBSTONE_COLUMN: &str = "tombstone";
+
+impl Message {
+    /// A message setting `column` of a row to a new value
+    pub fn change(
+        timestamp: impl Into<String>,
+        dataset: impl Into<String>,
+        row: impl Into<String>,
+        column: impl Into<String>,
+        value_type: ValueType,
+        value: impl Into<String>,
+    ) -> Self {
+        Self {
+            timestamp: timestamp.into(),
+            dataset: dataset.into(),
+            row: row.into(),
+            column: column.into(),
+            value_type,
+            value: value.into(),
+        }
+    }
+
+    /// A message marking a row as deleted
+    pub fn tombstone(
+        timestamp: impl Into<String>,
+        dataset: impl Into<String>,
+        row: impl Into<String>,
+    ) -> Self {
+        Self::change(
+            timestamp,
+            dataset,
+            row,
+            TOMBSTONE_COLUMN,
+            ValueType::Number,
+            "1",
+        )
+    }
+
+    /// Order messages by their parsed timestamps, i.e. by millis, counter
+    /// and node, independent of the string format. A timestamp failing to
+    /// parse sorts first, ties fall back to the timestamp strings.
+    pub fn cmp_by_timestamp(&self, other: &Message) -> Ordering {
+        let a = Timestamp::parse(&self.timestamp).ok();
+        let b = Timestamp::parse(&other.timestamp).ok();
+        a.cmp(&b).then_with(|| self.timestamp.cmp(&other.timestamp))
+    }
+
+    /// Checks that the message can be applied: the timestamp parses,
+    /// dataset/row/column are non-empty and the value matches its type.
+    pub fn validate(&self) -> Result<(), MessageError> {
+        Timestamp::parse(&self.timestamp)?;
+
+        for (name, field) in [
+            ("dataset", &self.dataset),
+            ("row", &self.row),
+            ("column", &self.column),
+        ] {
+            if field.is_empty() {
+                return Err(MessageError::EmptyField(name));
+            }
+        }
+
+        let valid = match self.value_type {
+            ValueType::None => self.value.is_empty(),
+            ValueType::Number => self.value.parse::<f64>().is_ok(),
+            ValueType::String => true,
+        };
+        if !valid {
+            return Err(MessageError::InvalidValue {
+                value_type: self.value_type.to_string(),
+                value: self.value.clone(),
+            });
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[repr(u8)]
 pub enum ValueType {
-    None,
-    Number,
-    String,
+    None = 0,
+    Number = 1,
+    String = 2,
+}
+
+impl ValueType {
+    /// The discriminant, for stores keeping the type in a single byte
+    pub fn as_u8(self) -> u8 {
+        self as u8
+    }
+
+    /// The value type of a discriminant from [`ValueType::as_u8`]
+    pub fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            0 => Some(ValueType::None),
+            1 => Some(ValueType::Number),
+            2 => Some(ValueType::String),
+            _ => None,
+        }
+    }
+}
+
+/// Serde representation of a [`ValueType`] as its discriminant, instead of
+/// the variant name:
+///
+/// ```
+/// # use merkle_trie_clock::models::ValueType;
+/// #[derive(serde::Serialize, serde::Deserialize)]
+/// struct Row {
+///     #[serde(with = "merkle_trie_clock::models::value_type_as_u8")]
+///     value_type: ValueType,
+/// }
+/// ```
+pub mod value_type_as_u8 {
+    use serde::de::Error;
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    use super::ValueType;
+
+    pub fn serialize<S>(value_type: &ValueType, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.serialize_u8(value_type.as_u8())
+    }
+
+    pub fn deserialize<'de, D>(deserializer: D) -> Result<ValueType, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let value = u8::deserialize(deserializer)?;
+        ValueType::from_u8(value)
+            .ok_or_else(|| D::Error::custom(alloc::format!("unknown value type {}", value)))
+    }
 }
 
 impl From<String> for ValueType {
@@ -30,7 +166,7 @@ impl From<String> for ValueType {
 }
 
 impl Display for ValueType {
-    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
         let printable = match self {
             ValueType::None => "None",
             ValueType::Number => "Number",
@@ -47,3 +183,189 @@ pub struct RowParam {
     pub value_type: ValueType,
     pub value: String,
 }
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec::Vec;
+
+    use serde::{Deserialize, Serialize};
+
+    use crate::error::{MessageError, TimestampError};
+    use crate::models::{Message, ValueType, TOMBSTONE_COLUMN};
+    use crate::timestamp::Timestamp;
+
+    const TS: &str = "2024-04-12T05:13:20.831+00:00-0000-5ef35ca3375b14c8";
+
+    fn valid_message() -> Message {
+        Message::change(TS, "todos", "1", "content", ValueType::String, "It's ok!")
+    }
+
+    #[test]
+    fn change_test() {
+        let m = Message::change(
+            "2024-04-12T05:13:20.831+00:00-0000-5ef35ca3375b14c8",
+            "todos",
+            "1",
+            "content",
+            ValueType::String,
+            "It's ok!",
+        );
+
+        assert_eq!(
+            m.timestamp,
+            "2024-04-12T05:13:20.831+00:00-0000-5ef35ca3375b14c8"
+        );
+        assert_eq!(m.dataset, "todos");
+        assert_eq!(m.row, "1");
+        assert_eq!(m.column, "content");
+        assert!(matches!(m.value_type, ValueType::String));
+        assert_eq!(m.value, "It's ok!");
+    }
+
+    #[test]
+    fn tombstone_test() {
+        let m = Message::tombstone(
+            "2024-04-12T05:13:20.831+00:00-0000-5ef35ca3375b14c8",
+            "todos",
+            "1",
+        );
+
+        assert_eq!(m.dataset, "todos");
+        assert_eq!(m.row, "1");
+        assert_eq!(m.column, TOMBSTONE_COLUMN);
+        assert!(matches!(m.value_type, ValueType::Number));
+        assert_eq!(m.value, "1");
+    }
+
+    #[test]
+    fn validate_test() {
+        assert_eq!(valid_message().validate(), Ok(()));
+        assert_eq!(Message::tombstone(TS, "todos", "1").validate(), Ok(()));
+        assert_eq!(
+            Message::change(TS, "todos", "1", "deleted", ValueType::None, "").validate(),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn validate_invalid_timestamp_test() {
+        let mut m = valid_message();
+        m.timestamp = String::new();
+        assert!(matches!(
+            m.validate(),
+            Err(MessageError::Timestamp(TimestampError::Parse(_)))
+        ));
+
+        m.timestamp = "not a timestamp".to_string();
+        assert!(matches!(
+            m.validate(),
+            Err(MessageError::Timestamp(TimestampError::Parse(_)))
+        ));
+    }
+
+    #[test]
+    fn validate_empty_field_test() {
+        let mut m = valid_message();
+        m.dataset = String::new();
+        assert_eq!(m.validate(), Err(MessageError::EmptyField("dataset")));
+
+        let mut m = valid_message();
+        m.row = String::new();
+        assert_eq!(m.validate(), Err(MessageError::EmptyField("row")));
+
+        let mut m = valid_message();
+        m.column = String::new();
+        assert_eq!(m.validate(), Err(MessageError::EmptyField("column")));
+    }
+
+    #[test]
+    fn validate_invalid_value_test() {
+        let m = Message::change(TS, "todos", "1", "done", ValueType::Number, "yes");
+        assert_eq!(
+            m.validate(),
+            Err(MessageError::InvalidValue {
+                value_type: "Number".to_string(),
+                value: "yes".to_string(),
+            })
+        );
+
+        let m = Message::change(TS, "todos", "1", "done", ValueType::None, "1");
+        assert_eq!(
+            m.validate(),
+            Err(MessageError::InvalidValue {
+                value_type: "None".to_string(),
+                value: "1".to_string(),
+            })
+        );
+    }
+
+    #[test]
+    fn value_type_u8_test() {
+        #[derive(Debug, PartialEq, Serialize, Deserialize)]
+        struct Row {
+            #[serde(with = "crate::models::value_type_as_u8")]
+            value_type: ValueType,
+        }
+
+        for value_type in [ValueType::None, ValueType::Number, ValueType::String] {
+            assert_eq!(ValueType::from_u8(value_type.as_u8()), Some(value_type));
+
+            let row = Row { value_type };
+            let json = serde_json::to_string(&row).unwrap();
+            assert_eq!(json, format!("{{\"value_type\":{}}}", value_type.as_u8()));
+            assert_eq!(serde_json::from_str::<Row>(&json).unwrap(), row);
+
+            // The string forms are unchanged
+            assert_eq!(ValueType::from(value_type.to_string()), value_type);
+        }
+        assert_eq!(ValueType::from_u8(3), None);
+        assert!(serde_json::from_str::<Row>(r#"{"value_type":3}"#).is_err());
+        assert_eq!(
+            serde_json::to_string(&ValueType::Number).unwrap(),
+            r#""Number""#
+        );
+    }
+
+    #[test]
+    fn cmp_by_timestamp_test() {
+        let timestamps = [
+            Timestamp::new(1712898800000, 0, "client0000000002".to_string()),
+            Timestamp::new(1712898800000, 1, "client0000000001".to_string()),
+            Timestamp::new(1712898800000, 1, "client0000000002".to_string()),
+            Timestamp::new(1712898800001, 0, "client0000000001".to_string()),
+            Timestamp::new(1712898900000, 0, "client0000000001".to_string()),
+        ];
+        let message = |t: &Timestamp| {
+            Message::change(
+                t.to_string(),
+                "todos",
+                "1",
+                "content",
+                ValueType::String,
+                "x",
+            )
+        };
+
+        let mut messages: Vec<Message> = [3, 0, 4, 2, 1]
+            .iter()
+            .map(|i| message(&timestamps[*i]))
+            .collect();
+        messages.push(Message::change(
+            "bad",
+            "todos",
+            "1",
+            "content",
+            ValueType::String,
+            "x",
+        ));
+        messages.sort_by(Message::cmp_by_timestamp);
+
+        assert_eq!(messages[0].timestamp, "bad");
+        let sorted: Vec<Timestamp> = messages[1..]
+            .iter()
+            .map(|m| Timestamp::parse(&m.timestamp).unwrap())
+            .collect();
+        assert_eq!(sorted, timestamps);
+        assert!(timestamps.is_sorted());
+    }
+}
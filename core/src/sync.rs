@@ -0,0 +1,235 @@
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+
+use serde::{Deserialize, Serialize};
+
+use crate::error::{BaseMismatch, DiffError};
+use crate::merkle::{MerkleTrie, DEFAULT_BASE};
+use crate::models::Message;
+
+/// The body a client posts to sync with the server
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SyncRequest<const BASE: usize = DEFAULT_BASE> {
+    pub group_id: String,
+    pub client_id: String,
+    pub messages: Vec<Message>,
+    pub merkle: MerkleTrie<BASE>,
+    /// The base the client's trie was built with, checked by the server
+    /// as a trie of another base deserializes without error
+    #[serde(default = "default_base")]
+    pub base: usize,
+}
+
+impl<const BASE: usize> SyncRequest<BASE> {
+    /// Fails when the client's trie has another base than `BASE`
+    pub fn check_base(&self) -> Result<(), BaseMismatch> {
+        check_base::<BASE>(self.base)
+    }
+}
+
+/// The server answer to a [`SyncRequest`]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SyncResponse<const BASE: usize = DEFAULT_BASE> {
+    pub messages: Vec<Message>,
+    pub merkle: MerkleTrie<BASE>,
+    /// The base the server's trie was built with
+    #[serde(default = "default_base")]
+    pub base: usize,
+}
+
+impl<const BASE: usize> SyncResponse<BASE> {
+    /// Fails when the server's trie has another base than `BASE`
+    pub fn check_base(&self) -> Result<(), BaseMismatch> {
+        check_base::<BASE>(self.base)
+    }
+}
+
+fn check_base<const BASE: usize>(remote: usize) -> Result<(), BaseMismatch> {
+    if remote == BASE {
+        Ok(())
+    } else {
+        Err(BaseMismatch {
+            local: BASE,
+            remote,
+        })
+    }
+}
+
+/// The first phase of a sync: a client only sends the root hash of its
+/// merkle trie, so an idle client doesn't transfer its whole trie.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HandshakeRequest {
+    pub group_id: String,
+    pub client_id: String,
+    pub root_hash: u64,
+    /// The base of the client's merkle trie, a root hash is only comparable
+    /// between tries of the same base
+    #[serde(default = "default_base")]
+    pub base: usize,
+}
+
+/// The server answer to a [`HandshakeRequest`], a full [`SyncRequest`] is
+/// only needed when the client isn't in sync.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HandshakeResponse {
+    pub in_sync: bool,
+    /// The base of the server's merkle trie, never in sync with a client of
+    /// another base
+    #[serde(default = "default_base")]
+    pub base: usize,
+}
+
+/// Peers from before the base was exchanged all used the default one
+fn default_base() -> usize {
+    DEFAULT_BASE
+}
+
+/// Find the messages a peer is missing, given the local trie and the trie
+/// the peer sent.
+///
+/// The diff of the tries gives the point in time at which the two
+/// collections of messages "forked", in other words, at this point in time
+/// something changed (e.g., one collection inserted a message that the
+/// other lacks) which resulted in differing hashes. Every local message
+/// after that time is gathered through `since_lookup`.
+pub fn reconcile<const BASE: usize>(
+    local: &MerkleTrie<BASE>,
+    remote: &MerkleTrie<BASE>,
+    since_lookup: impl FnOnce(i64) -> Vec<Message>,
+) -> Result<Vec<Message>, DiffError> {
+    Ok(match local.diff(remote)? {
+        Some(diff_time) => since_lookup(diff_time),
+        None => vec![],
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::error::BaseMismatch;
+    use crate::merkle::{MerkleTrie, DEFAULT_BASE};
+    use crate::models::{Message, ValueType};
+    use crate::sync::{reconcile, SyncRequest, SyncResponse};
+    use crate::timestamp::Timestamp;
+
+    #[test]
+    fn serialize_deserialize_test() {
+        let t = Timestamp::new(1712898800831, 0, "5ef35ca3375b14c8".to_string());
+        let mut merkle: MerkleTrie<10> = MerkleTrie::new();
+        merkle.insert(&t);
+        let message = Message {
+            timestamp: t.to_string(),
+            dataset: "todos".to_string(),
+            row: "ae37814d-4201-432b-a9a2-f277224cd730".to_string(),
+            column: "content".to_string(),
+            value_type: ValueType::String,
+            value: "It's ok!".to_string(),
+        };
+
+        let request = SyncRequest {
+            group_id: "todo-app".to_string(),
+            client_id: "5ef35ca3375b14c8".to_string(),
+            messages: vec![message.clone()],
+            merkle: merkle.clone(),
+            base: 10,
+        };
+        let serialized = serde_json::to_string(&request).unwrap();
+        let deserialized: SyncRequest<10> = serde_json::from_str(&serialized).unwrap();
+        assert_eq!(deserialized.group_id, "todo-app");
+        assert_eq!(deserialized.client_id, "5ef35ca3375b14c8");
+        assert_eq!(deserialized.messages.len(), 1);
+        assert_eq!(deserialized.messages[0].timestamp, message.timestamp);
+        assert_eq!(deserialized.merkle.root_hash(), merkle.root_hash());
+
+        let response = SyncResponse {
+            messages: vec![message],
+            merkle,
+            base: 10,
+        };
+        let serialized = serde_json::to_string(&response).unwrap();
+        let deserialized: SyncResponse<10> = serde_json::from_str(&serialized).unwrap();
+        assert_eq!(deserialized.messages[0].value, "It's ok!");
+        assert_eq!(deserialized.merkle.root_hash(), response.merkle.root_hash());
+        assert_eq!(deserialized.merkle.length(), 1);
+    }
+
+    #[test]
+    fn base_mismatch_test() {
+        let mut merkle: MerkleTrie<10> = MerkleTrie::new();
+        merkle.insert(&Timestamp::new(
+            1712898800831,
+            0,
+            "5ef35ca3375b14c8".to_string(),
+        ));
+        let request = SyncRequest {
+            group_id: "todo-app".to_string(),
+            client_id: "5ef35ca3375b14c8".to_string(),
+            messages: vec![],
+            merkle,
+            base: 10,
+        };
+        let serialized = serde_json::to_string(&request).unwrap();
+        assert_eq!(request.check_base(), Ok(()));
+
+        // The trie deserializes fine, only the base tells it apart
+        let deserialized: SyncRequest<3> = serde_json::from_str(&serialized).unwrap();
+        assert_eq!(
+            deserialized.check_base(),
+            Err(BaseMismatch {
+                local: 3,
+                remote: 10
+            })
+        );
+
+        // Peers not sending a base use the default one
+        let response: SyncResponse = serde_json::from_str(
+            r#"{"messages":[],"merkle":{"root":{"hash":0,"stored":false,"children":null},"length":0}}"#,
+        )
+        .unwrap();
+        assert_eq!(response.base, DEFAULT_BASE);
+        assert_eq!(response.check_base(), Ok(()));
+    }
+
+    #[test]
+    fn reconcile_test() {
+        let message = |t: &Timestamp, value: &str| Message {
+            timestamp: t.to_string(),
+            dataset: "todos".to_string(),
+            row: "1".to_string(),
+            column: "content".to_string(),
+            value_type: ValueType::String,
+            value: value.to_string(),
+        };
+
+        // The in-memory message store of the server
+        let t1 = Timestamp::new(1712898800831, 0, "client".to_string());
+        let t2 = Timestamp::new(1712898801000, 0, "other".to_string());
+        let t3 = Timestamp::new(1712898802000, 0, "other".to_string());
+        let store = [message(&t1, "a"), message(&t2, "b"), message(&t3, "c")];
+        let mut local: MerkleTrie<3> = MerkleTrie::new();
+        for t in [&t1, &t2, &t3] {
+            local.insert(t);
+        }
+        let since_lookup = |diff_time: i64| {
+            let since = Timestamp::new(diff_time, 0, "".to_string()).to_string();
+            store
+                .iter()
+                .filter(|m| m.timestamp >= since)
+                .cloned()
+                .collect::<Vec<_>>()
+        };
+
+        // The client only knows about the first message
+        let mut remote: MerkleTrie<3> = MerkleTrie::new();
+        remote.insert(&t1);
+        let missing = reconcile(&local, &remote, since_lookup).unwrap();
+        assert_eq!(
+            missing.iter().map(|m| m.value.as_str()).collect::<Vec<_>>(),
+            vec!["b", "c"]
+        );
+
+        // Nothing is missing once in sync
+        let missing = reconcile(&local, &local.clone(), since_lookup).unwrap();
+        assert!(missing.is_empty());
+    }
+}
@@ -1,55 +1,241 @@
-use std::cmp::max;
-use std::fmt::Display;
+use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use core::cmp::{max, Ordering};
+use core::fmt::Display;
+use core::hash::{Hash, Hasher};
+#[cfg(all(
+    feature = "std",
+    not(all(target_arch = "wasm32", target_os = "unknown"))
+))]
 use std::time::{SystemTime, UNIX_EPOCH};
 
-use anyhow::{bail, Result};
 use chrono::DateTime;
-use murmurhash32::murmurhash3;
+use serde::{Deserialize, Serialize};
+#[cfg(feature = "std")]
 use uuid::Uuid;
 
+use crate::error::TimestampError;
+use crate::hash::murmurhash3;
+
 /// Maximum physical clock drift allowed, in ms. In other words, if we
 /// receive a message from another node and that node's time differs from
 /// ours by more than this many milliseconds, throw an error.
 const MAX_DRIFT: i64 = 60000;
 
-const MAX_COUNTER: usize = 65535;
+/// A physical clock further behind the logical time than this, in ms, is
+/// logged: every send bumps the counter until the clock catches up again,
+/// which may exhaust it.
+pub const BACKWARD_JUMP_WARNING: i64 = 1000;
+
+/// Number of hex chars the counter is formatted with by default, which
+/// allows counters up to 65535.
+pub const DEFAULT_COUNTER_WIDTH: usize = 4;
+
+/// Number of chars of the node part in the string format. Node ids are
+/// normalized to it, see [`Timestamp::normalize_node`], so the lowest node
+/// string is the one of [`Timestamp::since`].
+pub const NODE_WIDTH: usize = 16;
+
+/// Where the wall time is read from, in millis since the epoch. Without the
+/// `std` feature there is no system clock, so a node brings its own, e.g. a
+/// real-time clock.
+pub trait TimeSource {
+    fn now_millis(&self) -> i64;
+}
+
+impl<F: Fn() -> i64> TimeSource for F {
+    fn now_millis(&self) -> i64 {
+        self()
+    }
+}
+
+/// The system clock
+#[cfg(feature = "std")]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemTimeSource;
+
+#[cfg(feature = "std")]
+impl TimeSource for SystemTimeSource {
+    /// Negative if the system clock is set before the epoch
+    #[cfg(not(all(target_arch = "wasm32", target_os = "unknown")))]
+    fn now_millis(&self) -> i64 {
+        match SystemTime::now().duration_since(UNIX_EPOCH) {
+            Ok(d) => d.as_millis() as i64,
+            Err(e) => -(e.duration().as_millis() as i64),
+        }
+    }
+
+    /// The browser clock, as `SystemTime` panics there
+    #[cfg(all(target_arch = "wasm32", target_os = "unknown"))]
+    fn now_millis(&self) -> i64 {
+        js_sys::Date::now() as i64
+    }
+}
 
-#[derive(Debug, Clone)]
+/// What [`Timestamp::recv`] does with a timestamp carrying our own node id
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum DuplicateNodePolicy {
+    /// Fail with [`TimestampError::DuplicateNode`], as two physical nodes
+    /// sharing an id break the uniqueness of the timestamps
+    #[default]
+    Error,
+    /// Skip the merge, e.g. for a node replaying its own messages after a
+    /// restart. Our own timestamps never run ahead of our timer.
+    Ignore,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Timestamp {
     millis: i64,
     counter: usize,
+    #[serde(deserialize_with = "deserialize_node")]
     node: String,
+    /// Number of hex chars of the counter part in the string format. All
+    /// nodes syncing together must agree on it, otherwise the timestamp
+    /// strings don't sort correctly.
+    #[serde(default = "default_counter_width")]
+    counter_width: usize,
+    #[serde(default)]
+    duplicate_node: DuplicateNodePolicy,
+}
+
+fn default_counter_width() -> usize {
+    DEFAULT_COUNTER_WIDTH
+}
+
+fn deserialize_node<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<String, D::Error> {
+    let node = String::deserialize(deserializer)?;
+    Ok(Timestamp::normalize_node(&node))
 }
 
 impl Display for Timestamp {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
         let date = Self::millis_to_datetime(self.millis);
-        let counter = format!("{:04X}", self.counter);
-        let node = format!("{:016}", self.node);
+        let counter = format!("{:0width$X}", self.counter, width = self.counter_width);
+        write!(f, "{}-{}-{}", date, counter, self.node)
+    }
+}
+
+/// Timestamps are equal if they denote the same event, the counter width
+/// only matters for the string format.
+impl PartialEq for Timestamp {
+    fn eq(&self, other: &Self) -> bool {
+        self.millis == other.millis && self.counter == other.counter && self.node == other.node
+    }
+}
+
+impl Eq for Timestamp {}
+
+/// Timestamps order by millis, then counter, then node, the same as their
+/// strings do when the nodes agree on the counter width.
+impl Ord for Timestamp {
+    fn cmp(&self, other: &Self) -> Ordering {
+        (self.millis, self.counter, &self.node).cmp(&(other.millis, other.counter, &other.node))
+    }
+}
 
-        write!(f, "{}-{}-{}", date, counter, node)
+impl PartialOrd for Timestamp {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Hash for Timestamp {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.millis.hash(state);
+        self.counter.hash(state);
+        self.node.hash(state);
     }
 }
 
 impl Timestamp {
+    /// A timestamp of `node`, normalized with [`Timestamp::normalize_node`]
     pub fn new(millis: i64, counter: usize, node: String) -> Self {
         Self {
             millis,
             counter,
-            node,
+            node: Self::normalize_node(&node),
+            counter_width: DEFAULT_COUNTER_WIDTH,
+            duplicate_node: DuplicateNodePolicy::default(),
         }
     }
 
+    /// The initial timestamp of a new node, identified by a freshly
+    /// generated short uuid
+    #[cfg(feature = "std")]
+    pub fn local() -> Self {
+        Self::new(0, 0, Self::generate_short_uuid())
+    }
+
+    /// A node id as it appears in the string format, [`NODE_WIDTH`] chars:
+    /// a shorter one is left-padded with `0`, a longer one keeps its last
+    /// `NODE_WIDTH` chars, which must still tell the nodes apart.
+    pub fn normalize_node(node: &str) -> String {
+        let len = node.chars().count();
+        if len >= NODE_WIDTH {
+            node.chars().skip(len - NODE_WIDTH).collect()
+        } else {
+            format!("{:0>width$}", node, width = NODE_WIDTH)
+        }
+    }
+
+    /// A timestamp at `millis` with a zero counter and an empty node, e.g.
+    /// as the lower bound of a "since" query
+    pub fn from_millis(millis: i64) -> Self {
+        Self::new(millis, 0, String::new())
+    }
+
+    /// The same timestamp moved by `ms` millis
+    pub fn add_millis(&self, ms: i64) -> Timestamp {
+        Timestamp {
+            millis: self.millis.saturating_add(ms),
+            ..self.clone()
+        }
+    }
+
+    /// The millis elapsed from `other` to this timestamp, negative if
+    /// `other` is later
+    pub fn duration_since(&self, other: &Timestamp) -> i64 {
+        self.millis.saturating_sub(other.millis)
+    }
+
+    /// Use `width` hex chars for the counter, raising the counter limit to
+    /// `16^width - 1`.
+    pub fn with_counter_width(mut self, width: usize) -> Self {
+        self.counter_width = width;
+        self
+    }
+
+    /// Choose what [`Timestamp::recv`] does with a timestamp of our own node
+    pub fn with_duplicate_node_policy(mut self, policy: DuplicateNodePolicy) -> Self {
+        self.duplicate_node = policy;
+        self
+    }
+
+    pub fn duplicate_node_policy(&self) -> DuplicateNodePolicy {
+        self.duplicate_node
+    }
+
     pub fn hash(&self) -> u64 {
         murmurhash3(self.to_string().as_bytes()) as u64
     }
 
     /// Timestamp send. Generates a unique, monotonic timestamp suitable
     /// for transmission to another system in string format
-    pub fn send(&mut self) -> Result<Timestamp> {
+    #[cfg(feature = "std")]
+    pub fn send(&mut self) -> Result<Timestamp, TimestampError> {
         // Retrieve the local wall time
-        let phys = SystemTime::now().duration_since(UNIX_EPOCH)?.as_millis() as i64;
+        self.send_with(&SystemTimeSource)
+    }
+
+    /// Like [`Timestamp::send`], reading the wall time from `source`
+    pub fn send_with(&mut self, source: &impl TimeSource) -> Result<Timestamp, TimestampError> {
+        self.send_at(source.now_millis())
+    }
 
+    /// Like [`Timestamp::send`], with `phys` as the local wall time
+    pub fn send_at(&mut self, phys: i64) -> Result<Timestamp, TimestampError> {
         // Unpack the clock.timestamp logical time and counter
         let l_old = self.millis;
         let c_old = self.counter;
@@ -63,25 +249,37 @@ impl Timestamp {
             false => 0,
         };
 
-        // Check the result for drift and counter overflow
+        // Check the result for drift and counter overflow. As the logical time
+        // only runs ahead of phys by the drift accepted in `recv`, this means
+        // the physical clock went backwards, e.g. by an NTP correction.
         if l_new - phys > MAX_DRIFT {
-            bail!("ClockDriftError: {}, {}, {}", l_new, phys, MAX_DRIFT)
+            return Err(TimestampError::ClockWentBackwards {
+                logical: l_old,
+                phys,
+            });
+        }
+        if l_old - phys > BACKWARD_JUMP_WARNING {
+            log::warn!(
+                "Physical clock is {}ms behind the logical time, counter: {}/{}",
+                l_old - phys,
+                c_new,
+                self.max_counter()
+            );
         }
         // Check counter overflow
-        if c_new > MAX_COUNTER {
-            // We don't support counters greater than 65535 because we need to ensure
-            // that, when converted to a hex string, it doesn't use more than 4 chars
-            // (see Timestamp.toString). For example:
+        if c_new > self.max_counter() {
+            // We don't support counters greater than 16^width - 1 because we need to
+            // ensure that, when converted to a hex string, it doesn't use more than
+            // `counter_width` chars (see Display), e.g. for the default 4 chars:
             //   (65533).toString(16) -> fffd
             //   (65534).toString(16) -> fffe
             //   (65535).toString(16) -> ffff
             //   (65536).toString(16) -> 10000 -- oops, this is 5 chars
-            // It's not that a larger counter couldn't be used--that would just mean
-            // increasing the expected length of the counterpart of the timestamp
-            // and updating the code that parses/generates that string. Some sort of
-            // length needs to be picked, and therefore there is going to be some sort
-            // of limit to how big the counter can be.
-            bail!("OverflowError");
+            // A larger counter can be used by increasing the counter width (see
+            // `with_counter_width`), but some sort of length needs to be picked, and
+            // therefore there is going to be some sort of limit to how big the
+            // counter can be.
+            return Err(TimestampError::CounterOverflow);
         }
 
         // Repack the logical time/counter
@@ -91,26 +289,95 @@ impl Timestamp {
         Ok(self.clone())
     }
 
+    /// Recover from a [`TimestampError::CounterOverflow`] without waiting for
+    /// the physical time to advance: jump the logical time past `millis` and
+    /// the current logical time, clearing the counter. Pass
+    /// [`Timestamp::millis`] to move on to the next millisecond.
+    ///
+    /// Fails with [`TimestampError::ClockDrift`], changing nothing, if that
+    /// is too far ahead of the wall time.
+    #[cfg(feature = "std")]
+    pub fn advance_past(&mut self, millis: i64) -> Result<(), TimestampError> {
+        self.advance_past_at(millis, SystemTimeSource.now_millis())
+    }
+
+    /// Like [`Timestamp::advance_past`], with `phys` as the local wall time
+    pub fn advance_past_at(&mut self, millis: i64, phys: i64) -> Result<(), TimestampError> {
+        // The logical time never goes backward
+        let l_new = max(self.millis, millis).saturating_add(1);
+        if l_new - phys > MAX_DRIFT {
+            return Err(TimestampError::ClockDrift {
+                got: l_new,
+                phys,
+                max: MAX_DRIFT,
+            });
+        }
+
+        self.millis = l_new;
+        self.counter = 0;
+
+        Ok(())
+    }
+
     /// Timestamp receive. Parses and merges a timestamp from a remote
     /// system with the local time global uniqueness and monotonicity are
     /// preserved
-    pub fn recv(&mut self, other_timestamp: &Timestamp) -> Result<()> {
-        let phys = SystemTime::now().duration_since(UNIX_EPOCH)?.as_millis() as i64;
+    #[cfg(feature = "std")]
+    pub fn recv(&mut self, other_timestamp: &Timestamp) -> Result<(), TimestampError> {
+        self.recv_with(other_timestamp, &SystemTimeSource)
+    }
+
+    /// Like [`Timestamp::recv`], reading the wall time from `source`
+    pub fn recv_with(
+        &mut self,
+        other_timestamp: &Timestamp,
+        source: &impl TimeSource,
+    ) -> Result<(), TimestampError> {
+        self.recv_at(other_timestamp, source.now_millis())
+    }
+
+    /// Like [`Timestamp::recv_with`], returning the drift of the remote
+    /// clock, i.e. the remote time minus the local wall time: positive when
+    /// the remote clock is ahead. Report it as a metric to spot a peer with
+    /// a bad clock before it exceeds the maximum drift and gets rejected.
+    pub fn recv_with_drift(
+        &mut self,
+        other_timestamp: &Timestamp,
+        source: &impl TimeSource,
+    ) -> Result<i64, TimestampError> {
+        let phys = source.now_millis();
+        self.recv_at(other_timestamp, phys)?;
+
+        Ok(other_timestamp.millis - phys)
+    }
 
+    /// Like [`Timestamp::recv`], with `phys` as the local wall time
+    pub fn recv_at(
+        &mut self,
+        other_timestamp: &Timestamp,
+        phys: i64,
+    ) -> Result<(), TimestampError> {
         // Unpack the message wall time/counter
         let l_msg = other_timestamp.millis;
         let c_msg = other_timestamp.counter;
 
         // Assert the node id and remote clock drift
         if other_timestamp.node == self.node {
-            // Whoops, looks like the message came from the same node ID as ours!
-            bail!("DuplicateNodeError: {}", self.node);
+            return match self.duplicate_node {
+                // Whoops, looks like the message came from the same node ID as ours!
+                DuplicateNodePolicy::Error => Err(TimestampError::DuplicateNode(self.node.clone())),
+                DuplicateNodePolicy::Ignore => Ok(()),
+            };
         }
 
         if l_msg - phys > MAX_DRIFT {
             // Whoops, the other node's physical time differs from ours by more than
             // the configured limit (e.g., 1 minute).
-            bail!("ClockDriftError");
+            return Err(TimestampError::ClockDrift {
+                got: l_msg,
+                phys,
+                max: MAX_DRIFT,
+            });
         }
 
         // Unpack the clock.timestamp logical time and counter
@@ -136,10 +403,14 @@ impl Timestamp {
 
         // Check the result for drift and counter overflow
         if l_new - phys > MAX_DRIFT {
-            bail!("ClockDriftError");
+            return Err(TimestampError::ClockDrift {
+                got: l_new,
+                phys,
+                max: MAX_DRIFT,
+            });
         }
-        if c_new > MAX_COUNTER {
-            bail!("OverflowError");
+        if c_new > self.max_counter() {
+            return Err(TimestampError::CounterOverflow);
         }
 
         // Repack the logical time/counter
@@ -150,28 +421,50 @@ impl Timestamp {
     }
 
     /// Converts a fixed-length string timestamp to the structured value
-    pub fn parse(timestamp: &str) -> Result<Timestamp> {
+    pub fn parse(timestamp: &str) -> Result<Timestamp, TimestampError> {
         let parts = timestamp.split('-').collect::<Vec<_>>();
 
         if parts.len() == 5 {
             if let Ok(millis) = chrono::DateTime::parse_from_rfc3339(&parts[0..3].join("-")) {
+                let millis = millis.timestamp_millis();
+                // Would wrap around into a bogus merkle key
+                if millis < 0 {
+                    return Err(TimestampError::BeforeEpoch(millis));
+                }
+                // The node is fixed-width like the rest of the string
+                if parts[4].chars().count() != NODE_WIDTH {
+                    return Err(TimestampError::Parse(timestamp.to_string()));
+                }
                 if let Ok(counter) = usize::from_str_radix(parts[3], 16) {
-                    return Ok(Timestamp {
-                        millis: millis.timestamp_millis(),
-                        counter,
-                        node: parts[4].to_string(),
-                    });
+                    return Ok(Timestamp::new(millis, counter, parts[4].to_string())
+                        .with_counter_width(parts[3].len()));
                 }
             }
         };
 
-        bail!("Parse timestamp failed: {}", timestamp);
+        Err(TimestampError::Parse(timestamp.to_string()))
     }
 
     pub fn since(iso_string: &str) -> String {
         format!("{}-0000-0000000000000000", iso_string)
     }
 
+    /// The lowest timestamp string at `millis`, whatever the node: every
+    /// timestamp from `millis` on compares greater or equal to it
+    pub fn since_millis(millis: i64) -> String {
+        Self::since(&Self::millis_to_datetime(millis))
+    }
+
+    /// The exclusive upper bound, in millis, of the buckets sharing the
+    /// parent node of `millis` in a trie of `base` and `bucket_ms`. Along
+    /// with [`Timestamp::since_millis`] it bounds the range of a diff time,
+    /// e.g. 120 gives 130 in base 10.
+    pub fn max_for_bucket(millis: i64, base: usize, bucket_ms: u64) -> i64 {
+        let width = (base as i64).saturating_mul(bucket_ms as i64);
+        (millis.div_euclid(width) + 1).saturating_mul(width)
+    }
+
+    #[cfg(feature = "std")]
     pub fn generate_short_uuid() -> String {
         let uuid = Uuid::new_v4().simple().to_string();
         uuid.replace('-', "")
@@ -190,6 +483,16 @@ impl Timestamp {
     pub fn node(&self) -> &str {
         &self.node
     }
+    pub fn counter_width(&self) -> usize {
+        self.counter_width
+    }
+
+    /// The largest counter that fits into the counter width
+    pub fn max_counter(&self) -> usize {
+        16usize
+            .checked_pow(self.counter_width as u32)
+            .map_or(usize::MAX, |limit| limit - 1)
+    }
 
     fn millis_to_datetime(millis: i64) -> String {
         let datetime = DateTime::from_timestamp_millis(millis).unwrap_or_default();
@@ -199,9 +502,11 @@ impl Timestamp {
 
 #[cfg(test)]
 mod tests {
+    use std::collections::HashSet;
     use std::time::{SystemTime, UNIX_EPOCH};
 
-    use crate::timestamp::Timestamp;
+    use crate::error::TimestampError;
+    use crate::timestamp::{DuplicateNodePolicy, TimeSource, Timestamp, MAX_DRIFT, NODE_WIDTH};
 
     #[test]
     fn new_test() {
@@ -265,6 +570,39 @@ mod tests {
         assert_eq!(t.counter, 1);
     }
 
+    #[test]
+    fn node_width_test() {
+        let full = Timestamp::new(1712898800831, 0, "5ef35ca3375b14c8".to_string());
+        let short = Timestamp::new(1712898800831, 0, "local".to_string());
+
+        let s = short.to_string();
+        assert_eq!(s, "2024-04-12T05:13:20.831+00:00-0000-00000000000local");
+        assert_eq!(s.len(), full.to_string().len());
+        assert_eq!(s.rsplit('-').next().unwrap().len(), NODE_WIDTH);
+
+        // The node is normalized up front, so the string round-trips
+        assert_eq!(short.node(), "00000000000local");
+        assert_eq!(Timestamp::parse(&s).unwrap(), short);
+        assert_eq!(Timestamp::parse(&s).unwrap().to_string(), s);
+        assert!(s.as_str() >= Timestamp::since_millis(1712898800831).as_str());
+        let json = serde_json::to_string(&short)
+            .unwrap()
+            .replace("00000000000local", "local");
+        assert_eq!(serde_json::from_str::<Timestamp>(&json).unwrap(), short);
+
+        let empty = Timestamp::new(1712898800831, 0, String::new());
+        assert!(empty.to_string().ends_with("-0000000000000000"));
+
+        // A longer id keeps its last NODE_WIDTH chars
+        let long = Timestamp::new(1712898800831, 0, "node0000000000001".to_string());
+        assert_eq!(long.node(), "ode0000000000001");
+        assert_eq!(Timestamp::parse(&long.to_string()).unwrap(), long);
+
+        // The string format only has fixed-width nodes
+        assert!(Timestamp::parse("2024-04-12T05:13:20.831+00:00-0000-local").is_err());
+        assert!(Timestamp::parse("2024-04-12T05:13:20.831+00:00-0000-node0000000000001").is_err());
+    }
+
     #[test]
     fn send_test() {
         // Old timestamp
@@ -290,7 +628,7 @@ mod tests {
         } else {
             assert_eq!(local_t.counter, 0);
         }
-        assert_eq!(local_t.node, "local")
+        assert_eq!(local_t.node, "00000000000local")
     }
 
     #[test]
@@ -349,4 +687,299 @@ mod tests {
             assert_eq!(local_t.counter, 0);
         }
     }
+
+    #[test]
+    fn send_overflow_test() {
+        let phys = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_millis() as i64;
+        // Ahead of the physical time, so the counter has to be incremented
+        let mut local_t = Timestamp::new(phys + 1000, 65535, "local".to_string());
+
+        assert_eq!(local_t.send().unwrap_err(), TimestampError::CounterOverflow);
+    }
+
+    #[test]
+    fn recv_duplicate_node_test() {
+        let mut local_t = Timestamp::new(0, 0, "local".to_string());
+        let remote_t = Timestamp::new(0, 0, "local".to_string());
+
+        assert_eq!(
+            local_t.recv(&remote_t).unwrap_err(),
+            TimestampError::DuplicateNode("00000000000local".to_string())
+        );
+    }
+
+    #[test]
+    fn recv_duplicate_node_ignore_test() {
+        let mut local_t = Timestamp::new(1712898800000, 3, "local".to_string())
+            .with_duplicate_node_policy(DuplicateNodePolicy::Ignore);
+        assert_eq!(local_t.duplicate_node_policy(), DuplicateNodePolicy::Ignore);
+        let own = Timestamp::new(1712898800000, 7, "local".to_string());
+
+        local_t.recv_at(&own, 1712898800000).unwrap();
+        assert_eq!(
+            local_t,
+            Timestamp::new(1712898800000, 3, "local".to_string())
+        );
+
+        // Other nodes are still merged
+        let remote = Timestamp::new(1712898800000, 7, "remote".to_string());
+        local_t.recv_at(&remote, 1712898800000).unwrap();
+        assert_eq!(local_t.counter(), 8);
+    }
+
+    #[test]
+    fn recv_clock_drift_test() {
+        let phys = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_millis() as i64;
+        let mut local_t = Timestamp::new(phys, 0, "local".to_string());
+        let remote_t = Timestamp::new(phys + 10 * MAX_DRIFT, 0, "remote".to_string());
+
+        match local_t.recv(&remote_t) {
+            Err(TimestampError::ClockDrift { got, max, .. }) => {
+                assert_eq!(got, phys + 10 * MAX_DRIFT);
+                assert_eq!(max, MAX_DRIFT);
+            }
+            other => panic!("Unexpected result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn parse_error_test() {
+        assert_eq!(
+            Timestamp::parse("not-a-timestamp").unwrap_err(),
+            TimestampError::Parse("not-a-timestamp".to_string())
+        );
+        assert_eq!(
+            Timestamp::parse("1969-12-31T23:59:59.999+00:00-0000-5ef35ca3375b14c8").unwrap_err(),
+            TimestampError::BeforeEpoch(-1)
+        );
+    }
+
+    #[test]
+    fn max_for_bucket_test() {
+        assert_eq!(Timestamp::max_for_bucket(120, 10, 1), 130);
+        assert_eq!(Timestamp::max_for_bucket(129, 10, 1), 130);
+        // 2938 is 11000211 in base 3
+        assert_eq!(Timestamp::max_for_bucket(2938, 3, 1), 2940);
+        assert_eq!(Timestamp::max_for_bucket(120000, 10, 1000), 130000);
+        assert_eq!(Timestamp::max_for_bucket(i64::MAX, 10, 1), i64::MAX);
+    }
+
+    #[test]
+    fn counter_width_test() {
+        let t = Timestamp::new(1712898800831, 100000, "5ef35ca3375b14c8".to_string())
+            .with_counter_width(6);
+        assert_eq!(t.max_counter(), 16777215);
+
+        let serialized = t.to_string();
+        assert_eq!(
+            serialized,
+            "2024-04-12T05:13:20.831+00:00-0186A0-5ef35ca3375b14c8"
+        );
+
+        let parsed = Timestamp::parse(&serialized).unwrap();
+        assert_eq!(parsed.counter, 100000);
+        assert_eq!(parsed.counter_width(), 6);
+        assert_eq!(parsed.to_string(), serialized);
+    }
+
+    #[test]
+    fn send_clock_backwards_test() {
+        let mut local_t = Timestamp::new(1712898800831, 0, "local".to_string());
+
+        // A small jump back only bumps the counter
+        let t = local_t.send_at(1712898800831 - 5000).unwrap();
+        assert_eq!(t.millis, 1712898800831);
+        assert_eq!(t.counter, 1);
+
+        // A jump back beyond the allowed drift is reported as such
+        let phys = 1712898800831 - 2 * MAX_DRIFT;
+        assert_eq!(
+            local_t.send_at(phys).unwrap_err(),
+            TimestampError::ClockWentBackwards {
+                logical: 1712898800831,
+                phys,
+            }
+        );
+        // The failed send leaves the timestamp untouched
+        assert_eq!(local_t.counter, 1);
+
+        // Once the clock caught up, the counter resets
+        let t = local_t.send_at(1712898800832).unwrap();
+        assert_eq!(t.millis, 1712898800832);
+        assert_eq!(t.counter, 0);
+    }
+
+    #[test]
+    fn from_millis_test() {
+        let t = Timestamp::from_millis(1712898800831);
+        assert_eq!(t.millis(), 1712898800831);
+        assert_eq!(t.counter(), 0);
+        assert_eq!(t.node(), "0000000000000000");
+        assert_eq!(
+            t.to_string(),
+            Timestamp::new(1712898800831, 0, "".to_string()).to_string()
+        );
+    }
+
+    #[test]
+    fn add_millis_test() {
+        let t = Timestamp::new(1712898800831, 3, "local".to_string());
+
+        let later = t.add_millis(1000);
+        assert_eq!(later.millis(), 1712898801831);
+        assert_eq!(later.counter(), 3);
+        assert_eq!(later.node(), "00000000000local");
+
+        assert_eq!(t.add_millis(-831).millis(), 1712898800000);
+        assert_eq!(t.add_millis(i64::MAX).millis(), i64::MAX);
+    }
+
+    #[test]
+    fn duration_since_test() {
+        let t1 = Timestamp::new(1712898800831, 0, "local".to_string());
+        let t2 = Timestamp::new(1712898801000, 5, "remote".to_string());
+
+        assert_eq!(t2.duration_since(&t1), 169);
+        assert_eq!(t1.duration_since(&t2), -169);
+        assert_eq!(t1.duration_since(&t1), 0);
+    }
+
+    #[test]
+    fn local_test() {
+        let t1 = Timestamp::local();
+        let t2 = Timestamp::local();
+
+        assert_eq!(t1.millis(), 0);
+        assert_eq!(t1.counter(), 0);
+        assert_eq!(t1.node().len(), 16);
+        assert_eq!(t2.node().len(), 16);
+        assert_ne!(t1.node(), t2.node());
+    }
+
+    #[test]
+    fn hash_set_test() {
+        let serialized = [
+            "2024-04-12T05:13:20.831+00:00-0000-5ef35ca3375b14c8",
+            "2024-04-12T05:13:20.831+00:00-0001-5ef35ca3375b14c8",
+            "2024-04-12T05:13:20.831+00:00-0000-0000000000000001",
+        ];
+        let set: HashSet<Timestamp> = serialized
+            .iter()
+            .map(|s| Timestamp::parse(s).unwrap())
+            .collect();
+        assert_eq!(set.len(), 3);
+
+        for s in serialized {
+            assert!(set.contains(&Timestamp::parse(s).unwrap()));
+        }
+        assert!(set.contains(&Timestamp::new(
+            1712898800831,
+            1,
+            "5ef35ca3375b14c8".to_string()
+        )));
+        // The counter width doesn't make a different event
+        assert!(set.contains(
+            &Timestamp::new(1712898800831, 0, "5ef35ca3375b14c8".to_string()).with_counter_width(6)
+        ));
+        assert!(!set.contains(&Timestamp::new(
+            1712898800832,
+            0,
+            "5ef35ca3375b14c8".to_string()
+        )));
+    }
+
+    #[test]
+    fn since_millis_test() {
+        let since = Timestamp::since_millis(1712898800831);
+        assert_eq!(since, "2024-04-12T05:13:20.831+00:00-0000-0000000000000000");
+
+        for node in ["0000000000000001", "SERVER", "a"] {
+            let at = Timestamp::new(1712898800831, 0, node.to_string()).to_string();
+            let before = Timestamp::new(1712898800830, 5, node.to_string()).to_string();
+            assert!(at.as_str() >= since.as_str());
+            assert!(before.as_str() < since.as_str());
+        }
+    }
+
+    #[test]
+    fn time_source_test() {
+        // E.g. the real-time clock of an embedded node
+        struct FixedClock(i64);
+        impl TimeSource for FixedClock {
+            fn now_millis(&self) -> i64 {
+                self.0
+            }
+        }
+
+        let mut t = Timestamp::new(0, 0, "local".to_string());
+        let sent = t.send_with(&FixedClock(1712898800831)).unwrap();
+        assert_eq!((sent.millis(), sent.counter()), (1712898800831, 0));
+        let sent = t.send_with(&FixedClock(1712898800831)).unwrap();
+        assert_eq!((sent.millis(), sent.counter()), (1712898800831, 1));
+
+        let remote = Timestamp::new(1712898800900, 3, "remote".to_string());
+        t.recv_with(&remote, &|| 1712898800831).unwrap();
+        assert_eq!((t.millis(), t.counter()), (1712898800900, 4));
+    }
+
+    #[test]
+    fn recv_with_drift_test() {
+        let mut t = Timestamp::new(1712898800000, 0, "local".to_string());
+
+        // The remote clock is 5 seconds ahead, then 2 seconds behind
+        let ahead = Timestamp::new(1712898805000, 0, "remote".to_string());
+        let drift = t.recv_with_drift(&ahead, &|| 1712898800000).unwrap();
+        assert_eq!(drift, 5000);
+        assert_eq!(t.millis(), 1712898805000);
+
+        let behind = Timestamp::new(1712898808000, 0, "remote".to_string());
+        let drift = t.recv_with_drift(&behind, &|| 1712898810000).unwrap();
+        assert_eq!(drift, -2000);
+
+        // Too far ahead is an error, not a drift
+        let far = Timestamp::new(1712898810000 + MAX_DRIFT + 1, 0, "remote".to_string());
+        assert!(matches!(
+            t.recv_with_drift(&far, &|| 1712898810000),
+            Err(TimestampError::ClockDrift { .. })
+        ));
+    }
+
+    #[test]
+    fn advance_past_test() {
+        let phys = 1712898800000;
+        let mut t = Timestamp::new(phys, 0, "local".to_string()).with_counter_width(1);
+        for _ in 0..t.max_counter() {
+            t.send_at(phys).unwrap();
+        }
+        assert_eq!(
+            t.send_at(phys).unwrap_err(),
+            TimestampError::CounterOverflow
+        );
+
+        t.advance_past_at(t.millis(), phys).unwrap();
+        assert_eq!((t.millis(), t.counter()), (phys + 1, 0));
+        let sent = t.send_at(phys).unwrap();
+        assert_eq!((sent.millis(), sent.counter()), (phys + 1, 1));
+
+        // Never backwards
+        t.advance_past_at(phys - 1000, phys).unwrap();
+        assert_eq!((t.millis(), t.counter()), (phys + 2, 0));
+
+        // Too far ahead of the wall time
+        let before = t.clone();
+        assert!(matches!(
+            t.advance_past_at(phys + MAX_DRIFT, phys),
+            Err(TimestampError::ClockDrift { .. })
+        ));
+        assert_eq!(
+            (t.millis(), t.counter()),
+            (before.millis(), before.counter())
+        );
+    }
 }
@@ -1,4 +1,6 @@
-use std::sync::{Mutex, OnceLock};
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
 
 use anyhow::bail;
 use rusqlite::{params, Connection};
@@ -7,172 +9,360 @@ use merkle_trie_clock::merkle::MerkleTrie;
 use merkle_trie_clock::models::Message;
 use merkle_trie_clock::timestamp::Timestamp;
 
-pub const MERKLE_BASE: usize = 3;
+use crate::store::{
+    validate_batch, BatchTooLarge, MessagePage, MessageStore, MAX_BATCH_MESSAGES, MERKLE_BASE,
+};
 
-const DB_FILE: &str = "db.sqlite";
+pub const DB_FILE: &str = "db.sqlite";
 
-static DB: OnceLock<Mutex<Connection>> = OnceLock::new();
+/// The merkle trie of a group is written back once this many messages were
+/// added since it was last persisted. Messages added in between are replayed
+/// onto the persisted trie when loading it.
+pub const MERKLE_PERSIST_INTERVAL: usize = 100;
 
-pub struct Db;
+/// Keeps the messages in a SQLite database.
+pub struct SqliteStore {
+    conn: Mutex<Connection>,
+    persist_interval: usize,
+    max_batch: usize,
+    merkle_bytes_written: AtomicUsize,
+}
 
-impl Db {
-    fn global() -> &'static Mutex<Connection> {
-        DB.get_or_init(|| {
-            let c = Connection::open(DB_FILE).unwrap();
+/// A merkle trie as loaded from the database
+struct LoadedMerkle {
+    trie: MerkleTrie<MERKLE_BASE>,
+    /// Messages covered by the trie but not by its persisted version
+    pending: usize,
+}
 
-            c.execute(
-                "CREATE TABLE IF NOT EXISTS messages (
-                        timestamp  TEXT,
-                        group_id   TEXT,
-                        dataset    TEXT,
-                        row        TEXT,
-                        column     TEXT,
-                        value_type TEXT,
-                        value      TEXT,
-                        PRIMARY KEY (timestamp, group_id)
-                    )",
-                [],
-            )
-            .unwrap();
+impl SqliteStore {
+    pub fn open<P: AsRef<Path>>(path: P) -> anyhow::Result<Self> {
+        Self::init(Connection::open(path)?)
+    }
+
+    /// A store in a fresh in-memory database, e.g. for tests
+    pub fn open_in_memory() -> anyhow::Result<Self> {
+        Self::init(Connection::open_in_memory()?)
+    }
+
+    /// Persist the merkle tries every `interval` added messages instead of
+    /// [`MERKLE_PERSIST_INTERVAL`], 1 writes them on every change.
+    pub fn with_persist_interval(mut self, interval: usize) -> Self {
+        self.persist_interval = interval.max(1);
+        self
+    }
+
+    /// Reject batches of more than `max` messages instead of
+    /// [`MAX_BATCH_MESSAGES`], as a batch holds the database for as long as it
+    /// takes to add it.
+    pub fn with_max_batch(mut self, max: usize) -> Self {
+        self.max_batch = max;
+        self
+    }
+
+    /// Total size of the merkle tries written so far, in bytes
+    pub fn merkle_bytes_written(&self) -> usize {
+        self.merkle_bytes_written.load(Ordering::Relaxed)
+    }
 
+    fn init(c: Connection) -> anyhow::Result<Self> {
+        c.execute(
+            "CREATE TABLE IF NOT EXISTS messages (
+                    timestamp  TEXT,
+                    group_id   TEXT,
+                    dataset    TEXT,
+                    row        TEXT,
+                    column     TEXT,
+                    value_type TEXT,
+                    value      TEXT,
+                    node       TEXT,
+                    PRIMARY KEY (timestamp, group_id)
+                )",
+            [],
+        )?;
+
+        c.execute(
+            "CREATE TABLE IF NOT EXISTS messages_merkles (
+                    group_id TEXT PRIMARY KEY,
+                    merkle   TEXT,
+                    merkle_base INT,
+                    last_rowid INT
+                )",
+            [],
+        )?;
+
+        // Databases from before the incremental persistence rewrote the trie
+        // on every change, so it covers all the messages of its group.
+        let has_last_rowid = c
+            .prepare("SELECT last_rowid FROM messages_merkles LIMIT 0")
+            .is_ok();
+        if !has_last_rowid {
+            c.execute("ALTER TABLE messages_merkles ADD COLUMN last_rowid INT", [])?;
             c.execute(
-                "CREATE TABLE IF NOT EXISTS messages_merkles (
-                        group_id TEXT PRIMARY KEY,
-                        merkle   TEXT,
-                        merkle_base INT
-                    )",
+                "UPDATE messages_merkles SET last_rowid = (SELECT IFNULL(MAX(rowid), 0) FROM messages WHERE messages.group_id = messages_merkles.group_id)",
                 [],
-            )
-            .unwrap();
+            )?;
+        }
 
-            Mutex::new(c)
+        // The node of the messages stored before it got its own column
+        let has_node = c.prepare("SELECT node FROM messages LIMIT 0").is_ok();
+        if !has_node {
+            c.execute("ALTER TABLE messages ADD COLUMN node TEXT", [])?;
+            let mut stmt = c.prepare("SELECT rowid, timestamp FROM messages")?;
+            let rows = stmt.query_map([], |row| {
+                Ok((row.get::<usize, i64>(0)?, row.get::<usize, String>(1)?))
+            })?;
+            for row in rows {
+                let (rowid, timestamp) = row?;
+                if let Ok(time) = Timestamp::parse(&timestamp) {
+                    c.execute(
+                        "UPDATE messages SET node = ? WHERE rowid = ?",
+                        params![time.node(), rowid],
+                    )?;
+                }
+            }
+        }
+
+        Ok(Self {
+            conn: Mutex::new(c),
+            persist_interval: MERKLE_PERSIST_INTERVAL,
+            max_batch: MAX_BATCH_MESSAGES,
+            merkle_bytes_written: AtomicUsize::new(0),
         })
     }
-}
 
-pub fn get_merkle(group_id: &str) -> anyhow::Result<MerkleTrie<MERKLE_BASE>> {
-    let conn = Db::global().lock().unwrap();
-    let mut stmt =
-        conn.prepare("SELECT merkle, merkle_base FROM messages_merkles WHERE group_id = ?")?;
-
-    let mut rows = stmt.query_map([group_id], |row| {
-        let merkle: String = row.get(0)?;
-        let merkle_base: usize = row.get(1)?;
-        Ok((merkle, merkle_base))
-    })?;
-
-    match rows.next() {
-        Some(Ok(merkle_item)) => {
-            let merkle_str = merkle_item.0;
-            let merkle_base = merkle_item.1;
-            if merkle_base != MERKLE_BASE {
-                bail!(
-                    "Wrong merkle base, got: {}, expected: {}",
-                    merkle_base,
-                    MERKLE_BASE
-                );
+    /// Load the persisted trie of a group and replay the messages added
+    /// after it was persisted.
+    fn load_merkle(conn: &Connection, group_id: &str) -> anyhow::Result<LoadedMerkle> {
+        let mut stmt = conn.prepare(
+            "SELECT merkle, merkle_base, last_rowid FROM messages_merkles WHERE group_id = ?",
+        )?;
+
+        let mut rows = stmt.query_map([group_id], |row| {
+            let merkle: String = row.get(0)?;
+            let merkle_base: usize = row.get(1)?;
+            let last_rowid: i64 = row.get(2)?;
+            Ok((merkle, merkle_base, last_rowid))
+        })?;
+
+        let (mut trie, last_rowid) = match rows.next() {
+            Some(Ok((merkle_str, merkle_base, last_rowid))) => {
+                if merkle_base != MERKLE_BASE {
+                    bail!(
+                        "Wrong merkle base, got: {}, expected: {}",
+                        merkle_base,
+                        MERKLE_BASE
+                    );
+                }
+                let trie: MerkleTrie<MERKLE_BASE> = serde_json::from_str(&merkle_str)?;
+                (trie, last_rowid)
             }
-            let trie: MerkleTrie<MERKLE_BASE> = serde_json::from_str(&merkle_str)?;
-            Ok(trie)
+            _ => (MerkleTrie::<MERKLE_BASE>::new(), 0),
+        };
+
+        let mut stmt =
+            conn.prepare("SELECT timestamp FROM messages WHERE group_id = ? AND rowid > ?")?;
+        let timestamps = stmt.query_map(params![group_id, last_rowid], |row| {
+            row.get::<usize, String>(0)
+        })?;
+        let mut pending = 0;
+        for timestamp in timestamps {
+            let timestamp = timestamp?;
+            if let Ok(time) = Timestamp::parse(&timestamp) {
+                trie.insert(&time);
+            } else {
+                log::error!("Failed to parse timestamp: {}", timestamp);
+            }
+            pending += 1;
         }
-        _ => Ok(MerkleTrie::<MERKLE_BASE>::new()),
+
+        Ok(LoadedMerkle { trie, pending })
     }
 }
 
-pub fn add_messages(
-    group_id: &str,
-    messages: &[Message],
-) -> anyhow::Result<MerkleTrie<MERKLE_BASE>> {
-    let mut trie = get_merkle(group_id)?;
+impl MessageStore for SqliteStore {
+    fn get_merkle(&self, group_id: &str) -> anyhow::Result<MerkleTrie<MERKLE_BASE>> {
+        let conn = self.conn.lock().unwrap();
+        Ok(Self::load_merkle(&conn, group_id)?.trie)
+    }
 
-    let mut conn = Db::global().lock().unwrap();
-    let tx = conn.transaction()?;
-    let mut changed = false;
+    fn add(&self, group_id: &str, messages: &[Message]) -> anyhow::Result<MerkleTrie<MERKLE_BASE>> {
+        if messages.len() > self.max_batch {
+            return Err(BatchTooLarge {
+                size: messages.len(),
+                limit: self.max_batch,
+            }
+            .into());
+        }
+        validate_batch(messages)?;
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+
+        for message in messages {
+            let time = Timestamp::parse(&message.timestamp)?;
+            tx.execute(
+                "INSERT OR IGNORE INTO messages (timestamp, group_id, dataset, row, column, value_type, value, node) VALUES (?, ?, ?, ?, ?, ?, ?, ?) ON CONFLICT DO NOTHING",
+                params![
+                    message.timestamp,
+                    group_id,
+                    message.dataset,
+                    message.row,
+                    message.column,
+                    message.value_type.to_string(),
+                    message.value,
+                    time.node(),
+                ],
+            )?;
+        }
 
-    for message in messages {
-        let res = tx.execute(
-            "INSERT OR IGNORE INTO messages (timestamp, group_id, dataset, row, column, value_type, value) VALUES (?, ?, ?, ?, ?, ?, ?) ON CONFLICT DO NOTHING",
-            params![
-                message.timestamp,
-                group_id,
-                message.dataset,
-                message.row,
-                message.column,
-                message.value_type.to_string(),
-                message.value,
-            ],
-        )?;
+        // The trie is derived from the message rows rather than from which
+        // inserts took effect: rows left behind without a trie update, e.g. by
+        // a crash, are picked up again here and on re-delivery.
+        let LoadedMerkle { trie, pending } = Self::load_merkle(&tx, group_id)?;
+
+        if pending >= self.persist_interval {
+            let merkle = serde_json::to_string(&trie)?;
+            tx.execute(
+                "INSERT OR REPLACE INTO messages_merkles (group_id, merkle, merkle_base, last_rowid) VALUES (?, ?, ?, (SELECT IFNULL(MAX(rowid), 0) FROM messages WHERE group_id = ?))",
+                params![group_id, merkle, MERKLE_BASE, group_id],
+            )?;
+            self.merkle_bytes_written
+                .fetch_add(merkle.len(), Ordering::Relaxed);
+        }
 
-        if res == 1 {
-            // Update the merkle trie
-            if let Ok(time) = Timestamp::parse(&message.timestamp) {
-                trie.insert(&time);
-                changed = true;
-            } else {
-                log::error!("Failed to parse timestamp: {}", message.timestamp);
+        tx.commit()?;
+
+        Ok(trie)
+    }
+
+    fn rebuild_merkle(&self, group_id: &str) -> anyhow::Result<MerkleTrie<MERKLE_BASE>> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+
+        let timestamps = {
+            let mut stmt = tx.prepare("SELECT timestamp FROM messages WHERE group_id = ?")?;
+            let rows = stmt.query_map([group_id], |row| row.get::<usize, String>(0))?;
+            let mut timestamps = vec![];
+            for timestamp in rows {
+                let timestamp = timestamp?;
+                match Timestamp::parse(&timestamp) {
+                    Ok(time) => timestamps.push(time),
+                    Err(_) => log::error!("Failed to parse timestamp: {}", timestamp),
+                }
             }
+            timestamps
+        };
+        let trie = MerkleTrie::<MERKLE_BASE>::from_timestamps(&timestamps);
+
+        let merkle = serde_json::to_string(&trie)?;
+        tx.execute(
+            "INSERT OR REPLACE INTO messages_merkles (group_id, merkle, merkle_base, last_rowid) VALUES (?, ?, ?, (SELECT IFNULL(MAX(rowid), 0) FROM messages WHERE group_id = ?))",
+            params![group_id, merkle, MERKLE_BASE, group_id],
+        )?;
+        self.merkle_bytes_written
+            .fetch_add(merkle.len(), Ordering::Relaxed);
+        tx.commit()?;
+
+        Ok(trie)
+    }
+
+    fn list_groups(&self) -> anyhow::Result<Vec<String>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(
+            "SELECT group_id FROM messages UNION SELECT group_id FROM messages_merkles ORDER BY group_id",
+        )?;
+        let rows = stmt.query_map([], |row| row.get::<usize, String>(0))?;
+
+        let mut groups = vec![];
+        for group_id in rows {
+            groups.push(group_id?);
         }
+        Ok(groups)
     }
 
-    if changed {
+    fn delete_group(&self, group_id: &str) -> anyhow::Result<()> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+        tx.execute("DELETE FROM messages WHERE group_id = ?", [group_id])?;
         tx.execute(
-            "INSERT OR REPLACE INTO messages_merkles (group_id, merkle, merkle_base) VALUES (?, ?, ?)",
-            params![group_id, serde_json::to_string(&trie)?, MERKLE_BASE],
+            "DELETE FROM messages_merkles WHERE group_id = ?",
+            [group_id],
         )?;
+        tx.commit()?;
+        Ok(())
     }
 
-    tx.commit()?;
+    fn find_late(
+        &self,
+        group_id: &str,
+        client_id: &str,
+        timestamp: &str,
+        limit: usize,
+    ) -> anyhow::Result<MessagePage> {
+        let conn = self.conn.lock().unwrap();
+        // The node column holds the ids as normalized in the timestamps
+        let client_id = Timestamp::normalize_node(client_id);
+
+        // One more row than the limit tells whether there are more messages
+        let mut stmt = conn.prepare("SELECT dataset, row, column, value_type, value, timestamp FROM messages WHERE group_id = ? AND timestamp > ? AND (node IS NULL OR node != ?) ORDER BY timestamp LIMIT ?")?;
+        let new_messages_result = stmt.query_map(
+            params![
+                group_id,
+                timestamp,
+                client_id,
+                limit.saturating_add(1) as i64
+            ],
+            |row| {
+                Ok(Message {
+                    dataset: row.get(0)?,
+                    row: row.get(1)?,
+                    column: row.get(2)?,
+                    value_type: row.get::<usize, String>(3)?.into(),
+                    value: row.get(4)?,
+                    timestamp: row.get(5)?,
+                })
+            },
+        )?;
+
+        let mut new_messages = vec![];
+        for msg in new_messages_result {
+            new_messages.push(msg?);
+        }
 
-    Ok(trie)
-}
+        let has_more = new_messages.len() > limit;
+        new_messages.truncate(limit);
 
-pub fn find_late_messages(
-    group_id: &str,
-    client_id: &str,
-    timestamp: &str,
-) -> anyhow::Result<Vec<Message>> {
-    let conn = Db::global().lock().unwrap();
-
-    let mut stmt = conn.prepare("SELECT dataset, row, column, value_type, value, timestamp FROM messages WHERE group_id = ? AND timestamp > ? AND timestamp NOT LIKE '%' || ? ORDER BY timestamp").unwrap();
-    let new_messages_result = stmt.query_map(params![group_id, timestamp, client_id], |row| {
-        Ok(Message {
-            dataset: row.get(0)?,
-            row: row.get(1)?,
-            column: row.get(2)?,
-            value_type: row.get::<usize, String>(3)?.into(),
-            value: row.get(4)?,
-            timestamp: row.get(5)?,
+        Ok(MessagePage {
+            messages: new_messages,
+            has_more,
         })
-    })?;
-
-    let mut new_messages = vec![];
-    for msg in new_messages_result {
-        println!("Msg: {:?}", msg);
-        let msg = msg.unwrap();
-        new_messages.push(msg);
     }
-
-    Ok(new_messages)
 }
 
 #[cfg(test)]
 mod tests {
     use std::time::{SystemTime, UNIX_EPOCH};
 
+    use rusqlite::params;
+
+    use merkle_trie_clock::merkle::MerkleTrie;
     use merkle_trie_clock::models::{Message, ValueType};
     use merkle_trie_clock::timestamp::Timestamp;
 
-    use crate::db::{add_messages, get_merkle, Db};
+    use crate::db::{SqliteStore, DB_FILE, MERKLE_PERSIST_INTERVAL};
+    use crate::store::{MessageStore, MERKLE_BASE};
 
     #[test]
     fn db_test() {
-        let c = Db::global().lock().unwrap();
+        let store = SqliteStore::open_in_memory().unwrap();
+        let c = store.conn.lock().unwrap();
         assert!(!c.is_busy())
     }
 
     #[test]
     fn get_merkle_test() {
-        let r = get_merkle("undefined").unwrap();
+        let store = SqliteStore::open_in_memory().unwrap();
+        let r = store.get_merkle("undefined").unwrap();
         assert!(r.is_empty());
     }
 
@@ -193,9 +383,280 @@ mod tests {
             value_type: ValueType::String,
             value: "It's ok!".to_string(),
         };
-        let trie = add_messages("todo-app", &[message]).unwrap();
+        let store = SqliteStore::open_in_memory().unwrap();
+        let trie = store.add("todo-app", &[message]).unwrap();
 
         assert!(!trie.is_empty());
         trie.debug();
     }
+
+    #[test]
+    fn find_late_messages_limit_test() {
+        let store = SqliteStore::open_in_memory().unwrap();
+        let group_id = Timestamp::generate_short_uuid();
+        let messages: Vec<Message> = (0..5)
+            .map(|i| {
+                let t = Timestamp::new(1712898800000 + i, 0, "client".to_string());
+                Message::change(
+                    t.to_string(),
+                    "todos",
+                    "1",
+                    "content",
+                    ValueType::String,
+                    "x",
+                )
+            })
+            .collect();
+        store.add(&group_id, &messages).unwrap();
+
+        let since = Timestamp::new(0, 0, "SERVER".to_string()).to_string();
+        let page = store.find_late(&group_id, "other", &since, 2).unwrap();
+        assert_eq!(page.messages.len(), 2);
+        assert!(page.has_more);
+        assert_eq!(page.messages[0].timestamp, messages[0].timestamp);
+
+        // The next page starts after the last returned message
+        let page = store
+            .find_late(&group_id, "other", &page.messages[1].timestamp, 2)
+            .unwrap();
+        assert_eq!(page.messages[0].timestamp, messages[2].timestamp);
+
+        let page = store.find_late(&group_id, "other", &since, 10).unwrap();
+        assert_eq!(page.messages.len(), 5);
+        assert!(!page.has_more);
+    }
+
+    fn numbered_message(i: i64) -> Message {
+        let t = Timestamp::new(1712898800000 + i, 0, "client0000000001".to_string());
+        Message::change(
+            t.to_string(),
+            "todos",
+            "1",
+            "content",
+            ValueType::String,
+            "x",
+        )
+    }
+
+    #[test]
+    fn incremental_merkle_test() {
+        let store = SqliteStore::open_in_memory().unwrap();
+
+        // What rewriting the trie on every change would write, and what
+        // rewriting it on every 100th one does
+        let mut rewrite_bytes = 0;
+        let mut interval_bytes = 0;
+        let mut trie = Default::default();
+        for i in 0..1000 {
+            trie = store.add("group", &[numbered_message(i)]).unwrap();
+            let bytes = serde_json::to_string(&trie).unwrap().len();
+            rewrite_bytes += bytes;
+            if (i + 1) % MERKLE_PERSIST_INTERVAL as i64 == 0 {
+                interval_bytes += bytes;
+            }
+        }
+        assert_eq!(trie.stored_keys().len(), 1000);
+        assert_eq!(store.get_merkle("group").unwrap().diff(&trie), Ok(None));
+
+        // Only every 100th add rewrites the trie
+        assert_eq!(store.merkle_bytes_written(), interval_bytes);
+        assert!(store.merkle_bytes_written() * 50 < rewrite_bytes);
+    }
+
+    #[test]
+    fn incremental_merkle_reload_test() {
+        let path =
+            std::env::temp_dir().join(format!("{}-{}", Timestamp::generate_short_uuid(), DB_FILE));
+        let store = SqliteStore::open(&path).unwrap().with_persist_interval(3);
+
+        let mut trie = Default::default();
+        for i in 0..5 {
+            trie = store.add("group", &[numbered_message(i)]).unwrap();
+        }
+        drop(store);
+
+        // The last 2 messages weren't persisted in the trie, but are replayed
+        let reopened = SqliteStore::open(&path).unwrap();
+        let loaded = reopened.get_merkle("group").unwrap();
+        assert_eq!(loaded.stored_keys().len(), 5);
+        assert_eq!(loaded.diff(&trie), Ok(None));
+
+        drop(reopened);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn crash_before_merkle_persist_test() {
+        let store = SqliteStore::open_in_memory()
+            .unwrap()
+            .with_persist_interval(1);
+        let messages: Vec<Message> = (0..6).map(numbered_message).collect();
+        store.add("group", &messages[..3]).unwrap();
+
+        // Rows that made it into the table while the trie update got lost
+        {
+            let conn = store.conn.lock().unwrap();
+            for message in &messages[3..5] {
+                conn.execute(
+                    "INSERT INTO messages (timestamp, group_id, dataset, row, column, value_type, value) VALUES (?, 'group', ?, ?, ?, ?, ?)",
+                    params![
+                        message.timestamp,
+                        message.dataset,
+                        message.row,
+                        message.column,
+                        message.value_type.to_string(),
+                        message.value,
+                    ],
+                )
+                .unwrap();
+            }
+        }
+        let expected = MerkleTrie::<MERKLE_BASE>::from_timestamps(
+            &messages
+                .iter()
+                .map(|m| Timestamp::parse(&m.timestamp).unwrap())
+                .collect::<Vec<_>>(),
+        );
+        assert_eq!(store.get_merkle("group").unwrap().length(), 5);
+
+        // The client re-delivers them, the inserts are ignored
+        let trie = store.add("group", &messages[3..]).unwrap();
+        assert_eq!(trie.diff(&expected), Ok(None));
+
+        // And the persisted trie covers them too
+        let persisted: MerkleTrie<MERKLE_BASE> = serde_json::from_str(
+            &store
+                .conn
+                .lock()
+                .unwrap()
+                .query_row(
+                    "SELECT merkle FROM messages_merkles WHERE group_id = 'group'",
+                    [],
+                    |row| row.get::<usize, String>(0),
+                )
+                .unwrap(),
+        )
+        .unwrap();
+        assert_eq!(persisted.diff(&expected), Ok(None));
+    }
+
+    #[test]
+    fn rebuild_merkle_test() {
+        let store = SqliteStore::open_in_memory()
+            .unwrap()
+            .with_persist_interval(1);
+        let mut trie = Default::default();
+        for i in 0..10 {
+            trie = store.add("group", &[numbered_message(i)]).unwrap();
+        }
+
+        // A lost trie, then one stored with another base
+        for corrupt in [
+            "DELETE FROM messages_merkles WHERE group_id = 'group'",
+            "UPDATE messages_merkles SET merkle_base = 2 WHERE group_id = 'group'",
+        ] {
+            store.conn.lock().unwrap().execute(corrupt, []).unwrap();
+
+            let rebuilt = store.rebuild_merkle("group").unwrap();
+            assert_eq!(rebuilt.root_hash(), trie.root_hash());
+            assert_eq!(store.get_merkle("group").unwrap().diff(&trie), Ok(None));
+        }
+    }
+
+    #[test]
+    fn delete_group_test() {
+        let store = SqliteStore::open_in_memory()
+            .unwrap()
+            .with_persist_interval(1);
+        store.add("group1", &[numbered_message(0)]).unwrap();
+        store.add("group2", &[numbered_message(1)]).unwrap();
+        assert_eq!(store.list_groups().unwrap(), vec!["group1", "group2"]);
+
+        store.delete_group("group1").unwrap();
+        assert_eq!(store.list_groups().unwrap(), vec!["group2"]);
+
+        let since = Timestamp::new(0, 0, "SERVER".to_string()).to_string();
+        assert!(store
+            .find_late("group1", "other", &since, 10)
+            .unwrap()
+            .messages
+            .is_empty());
+        assert!(store.get_merkle("group1").unwrap().is_empty());
+        let merkles: i64 = store
+            .conn
+            .lock()
+            .unwrap()
+            .query_row(
+                "SELECT COUNT(*) FROM messages_merkles WHERE group_id = 'group1'",
+                [],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(merkles, 0);
+
+        // The other group is untouched
+        assert_eq!(
+            store
+                .find_late("group2", "other", &since, 10)
+                .unwrap()
+                .messages
+                .len(),
+            1
+        );
+        assert!(!store.get_merkle("group2").unwrap().is_empty());
+    }
+
+    #[test]
+    fn find_late_exclude_client_test() {
+        let store = SqliteStore::open_in_memory().unwrap();
+        // A short client id is stored padded in the timestamps
+        let node = "node000000000001";
+        let short = "local";
+        let messages: Vec<Message> = [node, short]
+            .iter()
+            .enumerate()
+            .map(|(i, n)| {
+                let t = Timestamp::new(1712898800000 + i as i64, 0, n.to_string());
+                Message::change(
+                    t.to_string(),
+                    "todos",
+                    "1",
+                    "content",
+                    ValueType::String,
+                    "x",
+                )
+            })
+            .collect();
+        store.add("group", &messages).unwrap();
+
+        let since = Timestamp::since_millis(0);
+        let page = store.find_late("group", node, &since, 10).unwrap();
+        assert_eq!(page.messages.len(), 1);
+        assert_eq!(page.messages[0].timestamp, messages[1].timestamp);
+
+        let page = store.find_late("group", short, &since, 10).unwrap();
+        assert_eq!(page.messages.len(), 1);
+        assert_eq!(page.messages[0].timestamp, messages[0].timestamp);
+    }
+
+    #[test]
+    fn node_column_migration_test() {
+        let c = rusqlite::Connection::open_in_memory().unwrap();
+        c.execute_batch(
+            "CREATE TABLE messages (timestamp TEXT, group_id TEXT, dataset TEXT, row TEXT, column TEXT, value_type TEXT, value TEXT, PRIMARY KEY (timestamp, group_id));
+             INSERT INTO messages VALUES ('2024-04-12T05:13:20.831+00:00-0000-node000000000001', 'group', 'todos', '1', 'content', 'String', 'x');",
+        )
+        .unwrap();
+        let store = SqliteStore::init(c).unwrap();
+
+        let since = Timestamp::since_millis(0);
+        let page = store
+            .find_late("group", "node000000000001", &since, 10)
+            .unwrap();
+        assert!(page.messages.is_empty());
+        let page = store.find_late("group", "other", &since, 10).unwrap();
+        assert_eq!(page.messages.len(), 1);
+        // The trie is rebuilt from the messages
+        assert_eq!(store.get_merkle("group").unwrap().stored_keys().len(), 1);
+    }
 }
@@ -7,6 +7,7 @@ CREATE TABLE IF NOT EXISTS messages
     column     TEXT,
     value_type TEXT,
     value      TEXT,
+    node       TEXT,
     PRIMARY KEY (timestamp, group_id)
 );
 
@@ -14,5 +15,6 @@ CREATE TABLE IF NOT EXISTS messages_merkles
 (
     group_id TEXT PRIMARY KEY,
     merkle   TEXT,
-    merkle_base INT
+    merkle_base INT,
+    last_rowid INT
 );
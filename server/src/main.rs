@@ -1,18 +1,35 @@
+use std::sync::Arc;
+
 use actix_cors::Cors;
-use actix_web::web::Json;
-use actix_web::{get, middleware, post, App, HttpRequest, HttpResponse, HttpServer, Result};
+use actix_web::http::header::{ACCEPT, CONTENT_TYPE, ETAG, IF_NONE_MATCH};
+use actix_web::web::{Bytes, Data, Json, PayloadConfig};
+use actix_web::{
+    get, middleware, post, App, HttpRequest, HttpResponse, HttpResponseBuilder, HttpServer, Result,
+};
 use log::LevelFilter;
-use serde::{Deserialize, Serialize};
+use serde::Serialize;
 
-use merkle_trie_clock::merkle::MerkleTrie;
-use merkle_trie_clock::models::Message;
+use merkle_trie_clock::codec::{Codec, Format};
+use merkle_trie_clock::error::{BaseMismatch, DiffError};
+use merkle_trie_clock::sync::{
+    reconcile, HandshakeRequest, HandshakeResponse, SyncRequest, SyncResponse,
+};
 use merkle_trie_clock::timestamp::Timestamp;
 
-use crate::db::{add_messages, find_late_messages, MERKLE_BASE};
+use crate::db::{SqliteStore, DB_FILE};
+use crate::store::{BatchTooLarge, InvalidMessage, MessageStore, MERKLE_BASE};
 
 pub mod db;
+pub mod replicate;
+pub mod store;
+
+/// The most messages a single sync response carries. A client further
+/// behind gets the oldest ones and syncs again for the rest, as its merkle
+/// still differs from the server's.
+const MAX_LATE_MESSAGES: usize = 1000;
 
-const NODE_NAME: &str = "SERVER";
+/// The largest sync body accepted, the same as the JSON extractor's default
+const MAX_SYNC_BODY: usize = 2 * 1024 * 1024;
 
 #[get("/ping")]
 async fn ping(req: HttpRequest) -> Result<HttpResponse> {
@@ -21,51 +38,206 @@ async fn ping(req: HttpRequest) -> Result<HttpResponse> {
     Ok(HttpResponse::Ok().body("Ok".to_string()))
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-struct SyncRequest {
-    group_id: String,
-    client_id: String,
-    messages: Vec<Message>,
-    merkle: MerkleTrie<MERKLE_BASE>,
+#[post("/sync")]
+async fn sync(
+    store: Data<dyn MessageStore>,
+    http_req: HttpRequest,
+    body: Bytes,
+) -> Result<HttpResponse> {
+    let header = |name| http_req.headers().get(name).and_then(|v| v.to_str().ok());
+    let Some((format, res_format)) = negotiate(header(CONTENT_TYPE), header(ACCEPT)) else {
+        return Ok(HttpResponse::UnsupportedMediaType().finish());
+    };
+    let req: SyncRequest<MERKLE_BASE> = match format.decode(&body) {
+        Ok(req) => req,
+        Err(e) => return Ok(HttpResponse::BadRequest().body(e.to_string())),
+    };
+
+    if let Some(known) = header(IF_NONE_MATCH).and_then(parse_etag) {
+        match not_modified(store.get_ref(), &req, known) {
+            Ok(true) => {
+                return Ok(HttpResponse::NotModified()
+                    .insert_header((ETAG, etag(known)))
+                    .finish())
+            }
+            Ok(false) => {}
+            Err(e) => {
+                log::error!("Sync failed: {:?}", e);
+                return Ok(HttpResponse::InternalServerError().body(e.to_string()));
+            }
+        }
+    }
+
+    match handle_sync(store.get_ref(), req) {
+        Ok(res) => {
+            let mut builder = HttpResponse::Ok();
+            builder.insert_header((ETAG, etag(res.merkle.root_hash())));
+            Ok(encoded(builder, res_format, &res))
+        }
+        Err(e) if e.is::<BaseMismatch>() => Ok(encoded(
+            HttpResponse::Conflict(),
+            res_format,
+            &e.downcast_ref::<BaseMismatch>(),
+        )),
+        Err(e) if e.is::<DiffError>() || e.is::<InvalidMessage>() => {
+            Ok(HttpResponse::BadRequest().body(e.to_string()))
+        }
+        Err(e) if e.is::<BatchTooLarge>() => {
+            Ok(HttpResponse::PayloadTooLarge().body(e.to_string()))
+        }
+        Err(e) => {
+            log::error!("Sync failed: {:?}", e);
+            Ok(HttpResponse::InternalServerError().body(e.to_string()))
+        }
+    }
+}
+
+/// The root hash of a group as an entity tag, so a polling client can send
+/// it back in `If-None-Match`
+fn etag(root_hash: u64) -> String {
+    format!("\"{}\"", root_hash)
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-struct SyncResponse {
-    messages: Vec<Message>,
-    merkle: MerkleTrie<MERKLE_BASE>,
+/// The root hash of an `If-None-Match` entity tag, weak or not
+fn parse_etag(value: &str) -> Option<u64> {
+    let value = value.trim();
+    let value = value.strip_prefix("W/").unwrap_or(value);
+    value.strip_prefix('"')?.strip_suffix('"')?.parse().ok()
 }
 
-#[post("/sync")]
-async fn sync(req: Json<SyncRequest>) -> Result<HttpResponse> {
+/// Whether a sync can be answered with `304 Not Modified`: the client has
+/// nothing to upload, and both its trie and the one of the group still have
+/// the root hash it got last time, so there is nothing new for it either.
+/// A client that got a page of a longer answer doesn't have all of the
+/// messages yet, its own trie differs.
+fn not_modified<const BASE: usize>(
+    store: &dyn MessageStore<BASE>,
+    req: &SyncRequest<BASE>,
+    known_root_hash: u64,
+) -> anyhow::Result<bool> {
+    if !req.messages.is_empty() || req.base != BASE || req.merkle.root_hash() != known_root_hash {
+        return Ok(false);
+    }
+
+    Ok(store.get_merkle(&req.group_id)?.root_hash() == known_root_hash)
+}
+
+/// The format of a sync request by its content type, JSON without one, and
+/// the format of the answer: the first supported one the client accepts,
+/// else the one it sent. `None` for an unsupported content type.
+fn negotiate(content_type: Option<&str>, accept: Option<&str>) -> Option<(Format, Format)> {
+    let format = content_type.map_or(Some(Format::Json), Format::from_content_type)?;
+    let res_format = accept.and_then(Format::from_accept).unwrap_or(format);
+
+    Some((format, res_format))
+}
+
+fn encoded<T: Serialize>(mut res: HttpResponseBuilder, format: Format, body: &T) -> HttpResponse {
+    match format.encode(body) {
+        Ok(body) => res.content_type(format.content_type()).body(body),
+        Err(e) => {
+            log::error!("Encoding the sync response failed: {:?}", e);
+            HttpResponse::InternalServerError().body(e.to_string())
+        }
+    }
+}
+
+#[post("/handshake")]
+async fn handshake(
+    store: Data<dyn MessageStore>,
+    req: Json<HandshakeRequest>,
+) -> Result<HttpResponse> {
+    match handle_handshake(store.get_ref(), &req) {
+        Ok(res) => Ok(HttpResponse::Ok().json(res)),
+        Err(e) => {
+            log::error!("Handshake failed: {:?}", e);
+            Ok(HttpResponse::InternalServerError().body(e.to_string()))
+        }
+    }
+}
+
+/// Tell the client whether its root hash matches the one of the group, so
+/// an idle client can skip the full sync. A client of another merkle base
+/// is never in sync, it finds out about the mismatch from the answer.
+fn handle_handshake<const BASE: usize>(
+    store: &dyn MessageStore<BASE>,
+    req: &HandshakeRequest,
+) -> anyhow::Result<HandshakeResponse> {
+    if req.base != BASE {
+        log::warn!(
+            "Client {} uses merkle base {}, expected: {}",
+            req.client_id,
+            req.base,
+            BASE
+        );
+        return Ok(HandshakeResponse {
+            in_sync: false,
+            base: BASE,
+        });
+    }
+    let trie = store.get_merkle(&req.group_id)?;
+
+    Ok(HandshakeResponse {
+        in_sync: trie.root_hash() == req.root_hash,
+        base: BASE,
+    })
+}
+
+/// Store the messages of the client and answer with the messages it misses
+/// and the merkle trie of the group.
+fn handle_sync<const BASE: usize>(
+    store: &dyn MessageStore<BASE>,
+    req: SyncRequest<BASE>,
+) -> anyhow::Result<SyncResponse<BASE>> {
+    // A trie of another base deserializes fine but can't be diffed
+    req.check_base()?;
     let SyncRequest {
         group_id,
         client_id,
         messages,
         merkle: client_merkle,
-    } = req.into_inner();
+        ..
+    } = req;
 
-    println!(
-        "Got sync request, messages: {:?}, merkle: {:?}",
-        messages, client_merkle
-    );
-
-    let trie = add_messages(&group_id, &messages).unwrap();
+    let trie = store.add(&group_id, &messages)?;
 
-    let mut new_messages = vec![];
-
-    // Get the point in time (in minutes?) at which the two collections of
-    // messages "forked." In other words, at this point in time, something
-    // changed (e.g., one collection inserted a message that the other lacks)
-    // which resulted in differing hashes.
-    if let Some(diff_time) = trie.diff(&client_merkle) {
-        let timestamp = Timestamp::new(diff_time, 0, NODE_NAME.to_string()).to_string();
-        new_messages = find_late_messages(&group_id, &client_id, &timestamp).unwrap();
-    };
+    let mut lookup_err = None;
+    let new_messages = reconcile(&trie, &client_merkle, |diff_time| {
+        // The lowest string at the diff time, so the messages at the diff time
+        // are sent whatever their node id
+        let timestamp = Timestamp::since_millis(diff_time);
+        match store.find_late(&group_id, &client_id, &timestamp, MAX_LATE_MESSAGES) {
+            Ok(page) => {
+                if page.has_more {
+                    log::debug!(
+                        "More than {} late messages, sending the first page",
+                        MAX_LATE_MESSAGES
+                    );
+                }
+                page.messages
+            }
+            Err(e) => {
+                lookup_err = Some(e);
+                vec![]
+            }
+        }
+    })?;
+    if let Some(e) = lookup_err {
+        return Err(e);
+    }
+    log::info!(
+        "Synced group {} with client {}: {} messages received, {} sent",
+        group_id,
+        client_id,
+        messages.len(),
+        new_messages.len()
+    );
 
-    Ok(HttpResponse::Ok().json(SyncResponse {
+    Ok(SyncResponse {
         messages: new_messages,
         merkle: trie,
-    }))
+        base: BASE,
+    })
 }
 
 #[actix_web::main]
@@ -76,16 +248,490 @@ async fn main() -> std::io::Result<()> {
         .init();
     log::info!("starting HTTP server at http://localhost:8006");
 
-    HttpServer::new(|| {
+    let store: Arc<dyn MessageStore> =
+        Arc::new(SqliteStore::open(DB_FILE).map_err(std::io::Error::other)?);
+    let store = Data::from(store);
+
+    HttpServer::new(move || {
         let cors = Cors::permissive();
         App::new()
+            .app_data(store.clone())
+            .app_data(PayloadConfig::new(MAX_SYNC_BODY))
             // enable logger
             .wrap(middleware::Logger::default())
+            // gzip responses for clients accepting it, gzipped request
+            // bodies are decompressed by the body extractors
+            .wrap(middleware::Compress::default())
             .wrap(cors)
             .service(ping)
             .service(sync)
+            .service(handshake)
     })
     .bind(("127.0.0.1", 8006))?
     .run()
     .await
 }
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use actix_web::http::header::{ACCEPT, CONTENT_TYPE, ETAG, IF_NONE_MATCH};
+    use actix_web::http::StatusCode;
+    use actix_web::test::{call_service, init_service, read_body, TestRequest};
+    use actix_web::web::Data;
+    use actix_web::App;
+
+    use merkle_trie_clock::codec::{Codec, Format};
+    use merkle_trie_clock::error::BaseMismatch;
+    use merkle_trie_clock::merkle::MerkleTrie;
+    use merkle_trie_clock::models::{Message, ValueType};
+    use merkle_trie_clock::sync::{HandshakeRequest, SyncRequest, SyncResponse};
+    use merkle_trie_clock::timestamp::Timestamp;
+
+    use crate::db::SqliteStore;
+    use crate::store::{MemStore, MessageStore, MERKLE_BASE};
+    use crate::{handle_handshake, handle_sync, negotiate, sync, MAX_LATE_MESSAGES};
+
+    fn message(millis: i64, node: &str) -> (Timestamp, Message) {
+        let t = Timestamp::new(millis, 0, node.to_string());
+        let m = Message::change(
+            t.to_string(),
+            "todos",
+            "1",
+            "content",
+            ValueType::String,
+            node,
+        );
+        (t, m)
+    }
+
+    #[test]
+    fn handle_sync_test() {
+        let store = MemStore::new();
+
+        // client1 uploads a message
+        let (t1, m1) = message(1712898800000, "client0000000001");
+        let mut merkle1 = MerkleTrie::new();
+        merkle1.insert(&t1);
+        let res = handle_sync(
+            &store,
+            SyncRequest {
+                group_id: "group".to_string(),
+                client_id: "client0000000001".to_string(),
+                messages: vec![m1.clone()],
+                merkle: merkle1.clone(),
+                base: MERKLE_BASE,
+            },
+        )
+        .unwrap();
+        assert!(res.messages.is_empty());
+        assert_eq!(res.merkle.diff(&merkle1), Ok(None));
+
+        // client2 uploads its own message and gets the one of client1
+        let (t2, m2) = message(1712898801000, "client0000000002");
+        let mut merkle2 = MerkleTrie::new();
+        merkle2.insert(&t2);
+        let res = handle_sync(
+            &store,
+            SyncRequest {
+                group_id: "group".to_string(),
+                client_id: "client0000000002".to_string(),
+                messages: vec![m2],
+                merkle: merkle2.clone(),
+                base: MERKLE_BASE,
+            },
+        )
+        .unwrap();
+        assert_eq!(res.messages.len(), 1);
+        assert_eq!(res.messages[0].timestamp, m1.timestamp);
+
+        // After applying it, client2 is in sync
+        merkle2.insert(&t1);
+        assert_eq!(res.merkle.diff(&merkle2), Ok(None));
+    }
+
+    #[test]
+    fn negotiate_test() {
+        let json = Some((Format::Json, Format::Json));
+        assert_eq!(negotiate(None, None), json);
+        assert_eq!(negotiate(Some("application/json"), Some("*/*")), json);
+        assert_eq!(negotiate(None, Some("text/html, application/json")), json);
+        assert_eq!(negotiate(Some("text/plain"), None), None);
+    }
+
+    #[actix_web::test]
+    async fn sync_endpoint_test() {
+        let store: Arc<dyn MessageStore> = Arc::new(MemStore::new());
+        let app = init_service(App::new().app_data(Data::from(store)).service(sync)).await;
+
+        let formats = [
+            Format::Json,
+            #[cfg(feature = "messagepack")]
+            Format::MessagePack,
+            #[cfg(feature = "cbor")]
+            Format::Cbor,
+        ];
+        for (i, format) in formats.into_iter().enumerate() {
+            let (t, m) = message(1712898800000 + i as i64, "client0000000001");
+            let merkle = MerkleTrie::from_timestamps(&[t]);
+            let req = SyncRequest {
+                group_id: format.content_type().to_string(),
+                client_id: "client0000000001".to_string(),
+                messages: vec![m],
+                merkle: merkle.clone(),
+                base: MERKLE_BASE,
+            };
+            let res = call_service(
+                &app,
+                TestRequest::post()
+                    .uri("/sync")
+                    .insert_header((CONTENT_TYPE, format.content_type()))
+                    .insert_header((ACCEPT, format.content_type()))
+                    .set_payload(format.encode(&req).unwrap())
+                    .to_request(),
+            )
+            .await;
+
+            assert_eq!(res.status(), StatusCode::OK);
+            assert_eq!(
+                res.headers().get(CONTENT_TYPE).unwrap(),
+                format.content_type()
+            );
+            let res: SyncResponse = format.decode(&read_body(res).await).unwrap();
+            assert!(res.messages.is_empty());
+            assert_eq!(res.merkle.diff(&merkle), Ok(None));
+        }
+
+        let res = call_service(
+            &app,
+            TestRequest::post()
+                .uri("/sync")
+                .insert_header((CONTENT_TYPE, "text/plain"))
+                .set_payload("hello")
+                .to_request(),
+        )
+        .await;
+        assert_eq!(res.status(), StatusCode::UNSUPPORTED_MEDIA_TYPE);
+    }
+
+    #[actix_web::test]
+    async fn sync_not_modified_test() {
+        let store: Arc<dyn MessageStore> = Arc::new(MemStore::new());
+        let app = init_service(App::new().app_data(Data::from(store)).service(sync)).await;
+        let request =
+            |messages: Vec<Message>, merkle: &MerkleTrie<MERKLE_BASE>, etag: Option<&str>| {
+                let req = SyncRequest::<MERKLE_BASE> {
+                    group_id: "group".to_string(),
+                    client_id: "client0000000001".to_string(),
+                    messages,
+                    merkle: merkle.clone(),
+                    base: MERKLE_BASE,
+                };
+                let mut req = TestRequest::post()
+                    .uri("/sync")
+                    .set_payload(serde_json::to_vec(&req).unwrap());
+                if let Some(etag) = etag {
+                    req = req.insert_header((IF_NONE_MATCH, etag));
+                }
+                req.to_request()
+            };
+
+        let (t, m) = message(1712898800000, "client0000000001");
+        let merkle = MerkleTrie::from_timestamps(&[t]);
+        let res = call_service(&app, request(vec![m], &merkle, None)).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let etag = res
+            .headers()
+            .get(ETAG)
+            .unwrap()
+            .to_str()
+            .unwrap()
+            .to_string();
+
+        // Nothing changed since: neither messages nor a trie are sent
+        let res = call_service(&app, request(vec![], &merkle, Some(&etag))).await;
+        assert_eq!(res.status(), StatusCode::NOT_MODIFIED);
+        assert_eq!(res.headers().get(ETAG).unwrap(), etag.as_str());
+        assert!(read_body(res).await.is_empty());
+        let weak = format!("W/{}", etag);
+        let res = call_service(&app, request(vec![], &merkle, Some(&weak))).await;
+        assert_eq!(res.status(), StatusCode::NOT_MODIFIED);
+
+        // A stale root hash, a client trie that differs, or messages to
+        // upload, get a full sync
+        let res = call_service(&app, request(vec![], &merkle, Some("\"1\""))).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let res = call_service(&app, request(vec![], &MerkleTrie::new(), Some(&etag))).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let (_, m) = message(1712898801000, "client0000000001");
+        let res = call_service(&app, request(vec![m], &merkle, Some(&etag))).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_ne!(res.headers().get(ETAG).unwrap(), etag.as_str());
+    }
+
+    #[actix_web::test]
+    async fn sync_not_modified_paged_test() {
+        let store: Arc<dyn MessageStore> = Arc::new(MemStore::new());
+        let (timestamps, messages): (Vec<_>, Vec<_>) = (0..MAX_LATE_MESSAGES as i64 + 1)
+            .map(|i| message(1712898800000 + i, "client0000000002"))
+            .unzip();
+        for batch in messages.chunks(MAX_LATE_MESSAGES / 2) {
+            store.add("group", batch).unwrap();
+        }
+        let app = init_service(App::new().app_data(Data::from(store)).service(sync)).await;
+        let request = |merkle: &MerkleTrie<MERKLE_BASE>, etag: Option<&str>| {
+            let req = SyncRequest::<MERKLE_BASE> {
+                group_id: "group".to_string(),
+                client_id: "client0000000001".to_string(),
+                messages: vec![],
+                merkle: merkle.clone(),
+                base: MERKLE_BASE,
+            };
+            let mut req = TestRequest::post()
+                .uri("/sync")
+                .set_payload(serde_json::to_vec(&req).unwrap());
+            if let Some(etag) = etag {
+                req = req.insert_header((IF_NONE_MATCH, etag));
+            }
+            req.to_request()
+        };
+
+        let mut merkle = MerkleTrie::new();
+        let mut etag = None;
+        let mut received = 0;
+        loop {
+            let res = call_service(&app, request(&merkle, etag.as_deref())).await;
+            if res.status() == StatusCode::NOT_MODIFIED {
+                break;
+            }
+            assert_eq!(res.status(), StatusCode::OK);
+            etag = Some(
+                res.headers()
+                    .get(ETAG)
+                    .unwrap()
+                    .to_str()
+                    .unwrap()
+                    .to_string(),
+            );
+            let res: SyncResponse = serde_json::from_slice(&read_body(res).await).unwrap();
+            assert!(res.messages.len() <= MAX_LATE_MESSAGES);
+            for m in &res.messages {
+                merkle.insert(&Timestamp::parse(&m.timestamp).unwrap());
+            }
+            received += res.messages.len();
+        }
+
+        // Replaying the tag of the first page still gets the rest of them
+        assert_eq!(received, timestamps.len());
+        assert_eq!(
+            merkle.root_hash(),
+            MerkleTrie::<MERKLE_BASE>::from_timestamps(&timestamps).root_hash()
+        );
+    }
+
+    #[actix_web::test]
+    async fn sync_batch_too_large_test() {
+        let stores: [Arc<dyn MessageStore>; 2] = [
+            Arc::new(MemStore::new().with_max_batch(2)),
+            Arc::new(SqliteStore::open_in_memory().unwrap().with_max_batch(2)),
+        ];
+        for store in stores {
+            batch_too_large(store).await;
+        }
+    }
+
+    async fn batch_too_large(store: Arc<dyn MessageStore>) {
+        let app = init_service(App::new().app_data(Data::from(store.clone())).service(sync)).await;
+        let request = |messages: Vec<Message>| {
+            let req = SyncRequest::<MERKLE_BASE> {
+                group_id: "group".to_string(),
+                client_id: "client0000000001".to_string(),
+                messages,
+                merkle: MerkleTrie::new(),
+                base: MERKLE_BASE,
+            };
+            TestRequest::post()
+                .uri("/sync")
+                .set_payload(serde_json::to_vec(&req).unwrap())
+                .to_request()
+        };
+        let messages: Vec<Message> = (0..3)
+            .map(|i| message(1712898800000 + i, "client0000000001").1)
+            .collect();
+
+        let res = call_service(&app, request(messages.clone())).await;
+        assert_eq!(res.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        let body = read_body(res).await;
+        assert_eq!(
+            std::str::from_utf8(&body).unwrap(),
+            "Too many messages in one batch, got: 3, limit: 2"
+        );
+        assert!(store.get_merkle("group").unwrap().is_empty());
+
+        let res = call_service(&app, request(messages[..2].to_vec())).await;
+        assert_eq!(res.status(), StatusCode::OK);
+    }
+
+    #[test]
+    fn handle_sync_bucket_mismatch_test() {
+        let store = MemStore::new();
+        let err = handle_sync(
+            &store,
+            SyncRequest {
+                group_id: "group".to_string(),
+                client_id: "client".to_string(),
+                messages: vec![],
+                merkle: MerkleTrie::new().with_bucket_ms(1000),
+                base: MERKLE_BASE,
+            },
+        )
+        .unwrap_err();
+        assert!(err.is::<merkle_trie_clock::error::DiffError>());
+    }
+
+    #[test]
+    fn handle_sync_diff_boundary_test() {
+        let store = MemStore::new();
+
+        // Both sides know m0, the server also knows m1 of another node whose id
+        // sorts below any letter.
+        let (t0, m0) = message(1712898799000, "client0000000003");
+        let (t1, m1) = message(1712898800000, "0000000000000001");
+        store.add("group", &[m0, m1.clone()]).unwrap();
+
+        let (t2, m2) = message(1712898801000, "client0000000002");
+        let mut merkle2 = MerkleTrie::from_timestamps([&t0, &t2]);
+        let res = handle_sync(
+            &store,
+            SyncRequest {
+                group_id: "group".to_string(),
+                client_id: "client0000000002".to_string(),
+                messages: vec![m2],
+                merkle: merkle2.clone(),
+                base: MERKLE_BASE,
+            },
+        )
+        .unwrap();
+
+        // m1 is exactly at the diff time: sent; m0 is before it: not sent
+        assert_eq!(merkle2.diff(&res.merkle), Ok(Some(t1.millis())));
+        let sent: Vec<&str> = res.messages.iter().map(|m| m.timestamp.as_str()).collect();
+        assert_eq!(sent, vec![m1.timestamp.as_str()]);
+
+        merkle2.insert(&t1);
+        assert_eq!(res.merkle.diff(&merkle2), Ok(None));
+    }
+
+    #[test]
+    fn handle_handshake_test() {
+        let store = MemStore::new();
+        let handshake = |root_hash| HandshakeRequest {
+            group_id: "group".to_string(),
+            client_id: "client0000000001".to_string(),
+            root_hash,
+            base: MERKLE_BASE,
+        };
+
+        // An empty client is in sync with an unknown group
+        assert!(handle_handshake(&store, &handshake(0)).unwrap().in_sync);
+
+        let (t1, m1) = message(1712898800000, "client0000000002");
+        store.add("group", &[m1]).unwrap();
+        assert!(!handle_handshake(&store, &handshake(0)).unwrap().in_sync);
+        assert!(
+            handle_handshake(&store, &handshake(t1.hash()))
+                .unwrap()
+                .in_sync
+        );
+    }
+
+    #[test]
+    fn handle_sync_base_mismatch_test() {
+        let store = MemStore::new();
+        let (_, m1) = message(1712898800000, "client0000000001");
+        let err = handle_sync(
+            &store,
+            SyncRequest {
+                group_id: "group".to_string(),
+                client_id: "client0000000001".to_string(),
+                messages: vec![m1],
+                merkle: MerkleTrie::new(),
+                base: 10,
+            },
+        )
+        .unwrap_err();
+
+        assert_eq!(
+            err.downcast_ref::<BaseMismatch>(),
+            Some(&BaseMismatch {
+                local: MERKLE_BASE,
+                remote: 10
+            })
+        );
+        // Nothing got stored
+        assert!(store.list_groups().unwrap().is_empty());
+    }
+
+    #[test]
+    fn base_16_round_trip_test() {
+        let store = MemStore::<16>::default();
+        let handshake = |base| HandshakeRequest {
+            group_id: "group".to_string(),
+            client_id: "client0000000002".to_string(),
+            root_hash: 0,
+            base,
+        };
+        let res = handle_handshake(&store, &handshake(16)).unwrap();
+        assert!(res.in_sync);
+        assert_eq!(res.base, 16);
+        // A base 3 client learns the base of the server
+        let res = handle_handshake(&store, &handshake(3)).unwrap();
+        assert!(!res.in_sync);
+        assert_eq!(res.base, 16);
+
+        let (t1, m1) = message(1712898800000, "client0000000001");
+        let mut merkle1 = MerkleTrie::<16>::new();
+        merkle1.insert(&t1);
+        handle_sync(
+            &store,
+            SyncRequest {
+                group_id: "group".to_string(),
+                client_id: "client0000000001".to_string(),
+                messages: vec![m1.clone()],
+                merkle: merkle1,
+                base: 16,
+            },
+        )
+        .unwrap();
+
+        let mut merkle2 = MerkleTrie::<16>::new();
+        let res = handle_sync(
+            &store,
+            SyncRequest {
+                group_id: "group".to_string(),
+                client_id: "client0000000002".to_string(),
+                messages: vec![],
+                merkle: merkle2.clone(),
+                base: 16,
+            },
+        )
+        .unwrap();
+        assert_eq!(res.messages.len(), 1);
+        assert_eq!(res.messages[0].timestamp, m1.timestamp);
+
+        merkle2.insert(&t1);
+        assert_eq!(res.merkle.diff(&merkle2), Ok(None));
+        assert!(
+            handle_handshake(
+                &store,
+                &HandshakeRequest {
+                    root_hash: merkle2.root_hash(),
+                    ..handshake(16)
+                }
+            )
+            .unwrap()
+            .in_sync
+        );
+    }
+}
@@ -0,0 +1,154 @@
+use std::collections::HashSet;
+
+use anyhow::bail;
+
+use merkle_trie_clock::sync::{SyncRequest, SyncResponse};
+use merkle_trie_clock::timestamp::Timestamp;
+
+use crate::store::{MessageStore, MERKLE_BASE};
+
+/// The client id the local server syncs with. No message carries an empty
+/// node id, so the peer doesn't hold any of its messages back.
+pub const REPLICA_CLIENT_ID: &str = "";
+
+/// The most messages uploaded to the peer per round
+const MAX_REPLICATED_MESSAGES: usize = 1000;
+
+/// Gives up when the stores still differ after this many rounds
+const MAX_REPLICATION_ROUNDS: usize = 100;
+
+/// What a replication exchanged with the peer
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ReplicationStats {
+    /// Messages uploaded to the peer
+    pub sent: usize,
+    /// Messages received from the peer
+    pub received: usize,
+    /// Sync requests sent
+    pub rounds: usize,
+}
+
+/// Replicate a group with the server at `peer_url` through its `/sync`
+/// endpoint, so both servers end up with the same messages.
+pub fn replicate(
+    store: &dyn MessageStore,
+    peer_url: &str,
+    group_id: &str,
+) -> anyhow::Result<ReplicationStats> {
+    let client = reqwest::blocking::Client::new();
+    let url = format!("{}/sync", peer_url.trim_end_matches('/'));
+
+    replicate_with(store, group_id, |request| {
+        Ok(client
+            .post(&url)
+            .json(request)
+            .send()?
+            .error_for_status()?
+            .json::<SyncResponse<MERKLE_BASE>>()?)
+    })
+}
+
+/// Replicate a group, sending the sync requests through `sync`. The local
+/// store acts as a client of the peer: it stores the messages the peer
+/// answers with, then uploads its own messages after the time the tries
+/// still differ at, until both tries match.
+pub fn replicate_with(
+    store: &dyn MessageStore,
+    group_id: &str,
+    mut sync: impl FnMut(&SyncRequest<MERKLE_BASE>) -> anyhow::Result<SyncResponse<MERKLE_BASE>>,
+) -> anyhow::Result<ReplicationStats> {
+    let mut stats = ReplicationStats::default();
+    let mut messages = vec![];
+
+    while stats.rounds < MAX_REPLICATION_ROUNDS {
+        stats.rounds += 1;
+        stats.sent += messages.len();
+        let res = sync(&SyncRequest {
+            group_id: group_id.to_string(),
+            client_id: REPLICA_CLIENT_ID.to_string(),
+            messages: std::mem::take(&mut messages),
+            merkle: store.get_merkle(group_id)?,
+            base: MERKLE_BASE,
+        })?;
+        res.check_base()?;
+        stats.received += res.messages.len();
+
+        let trie = store.add(group_id, &res.messages)?;
+        let Some(diff_time) = trie.diff(&res.merkle)? else {
+            return Ok(stats);
+        };
+        // The peer answered with what we miss, it misses our messages from
+        // the diff time on, except the ones it just sent
+        let since = Timestamp::since_millis(diff_time);
+        let received: HashSet<&str> = res.messages.iter().map(|m| m.timestamp.as_str()).collect();
+        messages = store
+            .find_late(group_id, REPLICA_CLIENT_ID, &since, MAX_REPLICATED_MESSAGES)?
+            .messages;
+        messages.retain(|m| !received.contains(m.timestamp.as_str()));
+    }
+
+    bail!(
+        "Group {} still differs from the peer after {} rounds",
+        group_id,
+        MAX_REPLICATION_ROUNDS
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use merkle_trie_clock::models::{Message, ValueType};
+    use merkle_trie_clock::timestamp::Timestamp;
+
+    use crate::handle_sync;
+    use crate::replicate::replicate_with;
+    use crate::store::{MemStore, MessageStore};
+
+    fn message(millis: i64, node: &str) -> Message {
+        let t = Timestamp::new(millis, 0, node.to_string());
+        Message::change(
+            t.to_string(),
+            "todos",
+            "1",
+            "content",
+            ValueType::String,
+            node,
+        )
+    }
+
+    #[test]
+    fn replicate_test() {
+        let primary = MemStore::new();
+        let replica = MemStore::new();
+
+        let shared = message(1712898799000, "client0000000003");
+        let m1 = message(1712898800000, "client0000000001");
+        let m2 = message(1712898801000, "client0000000002");
+        primary.add("group", &[shared.clone(), m1.clone()]).unwrap();
+        replica.add("group", &[shared, m2.clone()]).unwrap();
+
+        let stats = replicate_with(&primary, "group", |request| {
+            handle_sync(&replica, request.clone())
+        })
+        .unwrap();
+        assert_eq!(stats.sent, 1);
+        assert_eq!(stats.received, 1);
+
+        let trie = primary.get_merkle("group").unwrap();
+        assert_eq!(trie.diff(&replica.get_merkle("group").unwrap()), Ok(None));
+        assert_eq!(trie.stored_keys().len(), 3);
+        for store in [&primary, &replica] {
+            let page = store.find_late("group", "", "", 10).unwrap();
+            let timestamps: Vec<&str> =
+                page.messages.iter().map(|m| m.timestamp.as_str()).collect();
+            assert!(timestamps.contains(&m1.timestamp.as_str()));
+            assert!(timestamps.contains(&m2.timestamp.as_str()));
+        }
+
+        // Replicating again only compares the tries
+        let stats = replicate_with(&primary, "group", |request| {
+            handle_sync(&replica, request.clone())
+        })
+        .unwrap();
+        assert_eq!((stats.sent, stats.received, stats.rounds), (0, 0, 1));
+    }
+}
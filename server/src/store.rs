@@ -0,0 +1,373 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Mutex;
+
+use thiserror::Error;
+
+use merkle_trie_clock::error::MessageError;
+use merkle_trie_clock::merkle::{MerkleTrie, DEFAULT_BASE};
+use merkle_trie_clock::models::Message;
+use merkle_trie_clock::timestamp::Timestamp;
+
+pub const MERKLE_BASE: usize = DEFAULT_BASE;
+
+/// The most messages a store adds in one call by default
+pub const MAX_BATCH_MESSAGES: usize = 1000;
+
+/// A batch of messages rejected for holding more than the store adds at once
+#[derive(Debug, Error)]
+#[error("Too many messages in one batch, got: {size}, limit: {limit}")]
+pub struct BatchTooLarge {
+    pub size: usize,
+    pub limit: usize,
+}
+
+/// A batch rejected for holding a message clients can't apply, see
+/// [`Message::validate`]
+#[derive(Debug, Error)]
+#[error("Invalid message {timestamp}: {source}")]
+pub struct InvalidMessage {
+    pub timestamp: String,
+    #[source]
+    pub source: MessageError,
+}
+
+/// Check every message of a batch before a store adds any of them
+pub fn validate_batch(messages: &[Message]) -> Result<(), InvalidMessage> {
+    for message in messages {
+        message.validate().map_err(|source| InvalidMessage {
+            timestamp: message.timestamp.clone(),
+            source,
+        })?;
+    }
+
+    Ok(())
+}
+
+/// A bounded batch of messages, `has_more` tells whether the query had
+/// more rows than the limit.
+#[derive(Debug)]
+pub struct MessagePage {
+    pub messages: Vec<Message>,
+    pub has_more: bool,
+}
+
+/// Where the server keeps the messages and merkle trie of each group.
+pub trait MessageStore<const BASE: usize = MERKLE_BASE>: Send + Sync {
+    /// The merkle trie of a group, empty for an unknown group
+    fn get_merkle(&self, group_id: &str) -> anyhow::Result<MerkleTrie<BASE>>;
+
+    /// Store the messages not known yet and return the updated merkle trie
+    fn add(&self, group_id: &str, messages: &[Message]) -> anyhow::Result<MerkleTrie<BASE>>;
+
+    /// Reconstruct the merkle trie of a group from all its messages and
+    /// store it, e.g. after the stored trie got lost or corrupted
+    fn rebuild_merkle(&self, group_id: &str) -> anyhow::Result<MerkleTrie<BASE>>;
+
+    /// The ids of all groups with messages, sorted
+    fn list_groups(&self) -> anyhow::Result<Vec<String>>;
+
+    /// Erase all messages and the merkle trie of a group
+    fn delete_group(&self, group_id: &str) -> anyhow::Result<()>;
+
+    /// Find the messages of a group after `timestamp` not sent by the client,
+    /// at most `limit` of them, oldest first.
+    fn find_late(
+        &self,
+        group_id: &str,
+        client_id: &str,
+        timestamp: &str,
+        limit: usize,
+    ) -> anyhow::Result<MessagePage>;
+}
+
+struct Group<const BASE: usize> {
+    /// Keyed by timestamp, so iteration is in timestamp order
+    messages: BTreeMap<String, Message>,
+    merkle: MerkleTrie<BASE>,
+}
+
+impl<const BASE: usize> Default for Group<BASE> {
+    fn default() -> Self {
+        Self {
+            messages: BTreeMap::new(),
+            merkle: MerkleTrie::new(),
+        }
+    }
+}
+
+/// Keeps everything in memory, e.g. for tests.
+pub struct MemStore<const BASE: usize = MERKLE_BASE> {
+    groups: Mutex<HashMap<String, Group<BASE>>>,
+    max_batch: usize,
+}
+
+impl<const BASE: usize> Default for MemStore<BASE> {
+    fn default() -> Self {
+        Self {
+            groups: Mutex::new(HashMap::new()),
+            max_batch: MAX_BATCH_MESSAGES,
+        }
+    }
+}
+
+impl MemStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl<const BASE: usize> MemStore<BASE> {
+    /// Reject batches of more than `max` messages as [`BatchTooLarge`],
+    /// [`MAX_BATCH_MESSAGES`] by default, the same as the SQLite store.
+    pub fn with_max_batch(mut self, max: usize) -> Self {
+        self.max_batch = max;
+        self
+    }
+}
+
+impl<const BASE: usize> MessageStore<BASE> for MemStore<BASE> {
+    fn get_merkle(&self, group_id: &str) -> anyhow::Result<MerkleTrie<BASE>> {
+        let groups = self.groups.lock().unwrap();
+        Ok(groups
+            .get(group_id)
+            .map(|group| group.merkle.clone())
+            .unwrap_or_default())
+    }
+
+    fn add(&self, group_id: &str, messages: &[Message]) -> anyhow::Result<MerkleTrie<BASE>> {
+        if messages.len() > self.max_batch {
+            return Err(BatchTooLarge {
+                size: messages.len(),
+                limit: self.max_batch,
+            }
+            .into());
+        }
+        validate_batch(messages)?;
+        let mut groups = self.groups.lock().unwrap();
+        let group = groups.entry(group_id.to_string()).or_default();
+
+        for message in messages {
+            if group.messages.contains_key(&message.timestamp) {
+                continue;
+            }
+            // Update the merkle trie
+            group.merkle.insert(&Timestamp::parse(&message.timestamp)?);
+            group
+                .messages
+                .insert(message.timestamp.clone(), message.clone());
+        }
+
+        // A group only exists once it has messages, as in the SQLite store
+        let merkle = group.merkle.clone();
+        if group.messages.is_empty() {
+            groups.remove(group_id);
+        }
+
+        Ok(merkle)
+    }
+
+    fn rebuild_merkle(&self, group_id: &str) -> anyhow::Result<MerkleTrie<BASE>> {
+        let mut groups = self.groups.lock().unwrap();
+        let Some(group) = groups.get_mut(group_id) else {
+            return Ok(MerkleTrie::new());
+        };
+
+        let timestamps = group
+            .messages
+            .keys()
+            .map(|t| Timestamp::parse(t))
+            .collect::<Result<Vec<_>, _>>()?;
+        group.merkle = MerkleTrie::from_timestamps(&timestamps);
+
+        Ok(group.merkle.clone())
+    }
+
+    fn list_groups(&self) -> anyhow::Result<Vec<String>> {
+        let groups = self.groups.lock().unwrap();
+        let mut ids: Vec<String> = groups.keys().cloned().collect();
+        ids.sort();
+        Ok(ids)
+    }
+
+    fn delete_group(&self, group_id: &str) -> anyhow::Result<()> {
+        self.groups.lock().unwrap().remove(group_id);
+        Ok(())
+    }
+
+    fn find_late(
+        &self,
+        group_id: &str,
+        client_id: &str,
+        timestamp: &str,
+        limit: usize,
+    ) -> anyhow::Result<MessagePage> {
+        let groups = self.groups.lock().unwrap();
+        let Some(group) = groups.get(group_id) else {
+            return Ok(MessagePage {
+                messages: vec![],
+                has_more: false,
+            });
+        };
+
+        let client_id = Timestamp::normalize_node(client_id);
+        let mut late = group
+            .messages
+            .iter()
+            .filter(|(t, _)| {
+                t.as_str() > timestamp
+                    && Timestamp::parse(t).map_or(true, |t| t.node() != client_id)
+            })
+            .map(|(_, message)| message.clone());
+        let messages: Vec<Message> = late.by_ref().take(limit).collect();
+        let has_more = late.next().is_some();
+
+        Ok(MessagePage { messages, has_more })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use merkle_trie_clock::models::{Message, ValueType};
+    use merkle_trie_clock::timestamp::Timestamp;
+
+    use crate::db::SqliteStore;
+    use crate::store::{InvalidMessage, MemStore, MessageStore};
+
+    fn message(millis: i64, node: &str) -> Message {
+        let t = Timestamp::new(millis, 0, node.to_string());
+        Message::change(
+            t.to_string(),
+            "todos",
+            "1",
+            "content",
+            ValueType::String,
+            "x",
+        )
+    }
+
+    #[test]
+    fn mem_store_test() {
+        let store = MemStore::new();
+        assert!(store.get_merkle("group").unwrap().is_empty());
+
+        let messages = [
+            message(1712898800000, "client0000000001"),
+            message(1712898801000, "client0000000002"),
+            message(1712898802000, "client0000000001"),
+        ];
+        let trie = store.add("group", &messages).unwrap();
+        assert_eq!(trie.stored_keys().len(), 3);
+        // Adding again changes nothing
+        let again = store.add("group", &messages).unwrap();
+        assert_eq!(again.diff(&trie), Ok(None));
+        assert_eq!(store.get_merkle("group").unwrap().diff(&trie), Ok(None));
+        assert!(store.get_merkle("other").unwrap().is_empty());
+
+        let since = Timestamp::new(0, 0, "SERVER".to_string()).to_string();
+        let page = store
+            .find_late("group", "client0000000001", &since, 10)
+            .unwrap();
+        assert_eq!(page.messages.len(), 1);
+        assert_eq!(page.messages[0].timestamp, messages[1].timestamp);
+        assert!(!page.has_more);
+
+        let page = store
+            .find_late("group", "client0000000003", &since, 2)
+            .unwrap();
+        assert_eq!(page.messages.len(), 2);
+        assert!(page.has_more);
+
+        // A short client id is compared as it is stored, padded
+        store
+            .add("group", &[message(1712898803000, "local")])
+            .unwrap();
+        let page = store.find_late("group", "local", &since, 10).unwrap();
+        assert_eq!(page.messages.len(), 3);
+        assert!(page
+            .messages
+            .iter()
+            .all(|m| !m.timestamp.ends_with("local")));
+    }
+
+    #[test]
+    fn mem_store_rebuild_merkle_test() {
+        let store = MemStore::new();
+        let messages = [
+            message(1712898800000, "client0000000001"),
+            message(1712898801000, "client0000000002"),
+        ];
+        let trie = store.add("group", &messages).unwrap();
+
+        store
+            .groups
+            .lock()
+            .unwrap()
+            .get_mut("group")
+            .unwrap()
+            .merkle
+            .clear();
+        let rebuilt = store.rebuild_merkle("group").unwrap();
+
+        assert_eq!(rebuilt.root_hash(), trie.root_hash());
+        assert_eq!(store.get_merkle("group").unwrap().diff(&trie), Ok(None));
+        assert!(store.rebuild_merkle("other").unwrap().is_empty());
+    }
+
+    #[test]
+    fn mem_store_groups_test() {
+        let store = MemStore::new();
+        store
+            .add("b", &[message(1712898800000, "client0000000001")])
+            .unwrap();
+        store
+            .add("a", &[message(1712898801000, "client0000000001")])
+            .unwrap();
+        assert_eq!(store.list_groups().unwrap(), vec!["a", "b"]);
+
+        store.delete_group("a").unwrap();
+        assert_eq!(store.list_groups().unwrap(), vec!["b"]);
+        assert!(store.get_merkle("a").unwrap().is_empty());
+    }
+
+    #[test]
+    fn empty_batch_test() {
+        let stores: [Box<dyn MessageStore>; 2] = [
+            Box::new(MemStore::new()),
+            Box::new(SqliteStore::open_in_memory().unwrap()),
+        ];
+        for store in stores {
+            assert!(store.add("empty", &[]).unwrap().is_empty());
+            assert!(store.list_groups().unwrap().is_empty());
+
+            store
+                .add("group", &[message(1712898800000, "client0000000001")])
+                .unwrap();
+            assert!(!store.add("group", &[]).unwrap().is_empty());
+            assert_eq!(store.list_groups().unwrap(), vec!["group"]);
+        }
+    }
+
+    #[test]
+    fn invalid_message_test() {
+        let stores: [Box<dyn MessageStore>; 2] = [
+            Box::new(MemStore::new()),
+            Box::new(SqliteStore::open_in_memory().unwrap()),
+        ];
+        let mut invalid = message(1712898801000, "client0000000001");
+        invalid.column = String::new();
+        let mut unparsable = message(1712898802000, "client0000000001");
+        unparsable.timestamp = "not a timestamp".to_string();
+
+        for store in stores {
+            for bad in [&invalid, &unparsable] {
+                let batch = [message(1712898800000, "client0000000001"), bad.clone()];
+                let err = store.add("group", &batch).unwrap_err();
+                let err = err.downcast_ref::<InvalidMessage>().unwrap();
+                assert_eq!(err.timestamp, bad.timestamp);
+            }
+            // Nothing of the batches was added
+            assert!(store.list_groups().unwrap().is_empty());
+            assert!(store.get_merkle("group").unwrap().is_empty());
+        }
+    }
+}